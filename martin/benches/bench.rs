@@ -58,10 +58,9 @@ impl Source for NullSource {
 }
 
 async fn process_tile(sources: &TileSources) {
-    let src = DynTileSource::new(sources, "null", Some(0), "", None, None, None).unwrap();
-    src.get_http_response(TileCoord { z: 0, x: 0, y: 0 })
-        .await
-        .unwrap();
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+    let src = DynTileSource::new(sources, "null", Some(xyz), "", None, None, None).unwrap();
+    src.get_http_response(xyz).await.unwrap();
 }
 
 fn bench_null_source(c: &mut Criterion) {
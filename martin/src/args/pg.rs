@@ -46,6 +46,9 @@ pub struct PgArgs {
     /// Limit the number of features in a tile from a PG table source.
     #[arg(short, long)]
     pub max_feature_count: Option<usize>,
+    /// Refuse to start if any of the published PG tables has no spatial index, instead of just warning about it.
+    #[arg(long)]
+    pub fail_on_missing_index: bool,
 }
 
 impl PgArgs {
@@ -64,9 +67,24 @@ impl PgArgs {
                 connection_string: Some(s),
                 ssl_certificates: certs.clone(),
                 default_srid,
+                schema_default_srids: None,
                 auto_bounds: self.auto_bounds,
+                default_bounds: None,
+                fail_on_missing_index: self.fail_on_missing_index.then_some(true),
                 max_feature_count: self.max_feature_count,
                 pool_size: self.pool_size,
+                min_idle: None,
+                pool_timeout_ms: None,
+                password_file: None,
+                idle_timeout_ms: None,
+                max_lifetime_ms: None,
+                query_timeout_ms: None,
+                function_retries: None,
+                function_retry_delay_ms: None,
+                client_min_messages: None,
+                discovery_concurrency: None,
+                include_tables: OptOneMany::NoVals,
+                exclude_tables: OptOneMany::NoVals,
                 auto_publish: OptBoolObj::NoValue,
                 tables: None,
                 functions: None,
@@ -89,6 +107,7 @@ impl PgArgs {
             auto_bounds,
             max_feature_count,
             ca_root_file,
+            fail_on_missing_index,
         } = self;
 
         if let Some(value) = default_srid {
@@ -115,6 +134,12 @@ impl PgArgs {
                 c.max_feature_count = max_feature_count;
             });
         }
+        if fail_on_missing_index {
+            info!("Enabling fail_on_missing_index on all Postgres connections because of a CLI parameter");
+            pg_config.iter_mut().for_each(|c| {
+                c.fail_on_missing_index = Some(true);
+            });
+        }
         if let Some(ref value) = ca_root_file {
             info!("Overriding root certificate file to {} on all Postgres connections because of a CLI parameter",
                 value.display());
@@ -9,6 +9,7 @@ use crate::args::srv::SrvArgs;
 use crate::config::Config;
 #[cfg(any(feature = "mbtiles", feature = "pmtiles", feature = "sprites"))]
 use crate::file_config::FileConfigEnum;
+use crate::file_config::FontConfigSrc;
 use crate::MartinError::ConfigAndConnectionsError;
 use crate::{MartinResult, OptOneMany};
 
@@ -113,7 +114,7 @@ impl Args {
         }
 
         if !self.extras.font.is_empty() {
-            config.fonts = OptOneMany::new(self.extras.font);
+            config.fonts = OptOneMany::new(self.extras.font.into_iter().map(FontConfigSrc::Path));
         }
 
         cli_strings.check()
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::future::Future;
@@ -6,6 +6,9 @@ use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 
+use actix_web::http::header::HeaderValue;
+#[cfg(feature = "postgres")]
+use futures::future::try_join;
 use futures::future::try_join_all;
 use log::info;
 use serde::{Deserialize, Serialize};
@@ -13,14 +16,23 @@ use subst::VariableMap;
 
 #[cfg(any(feature = "mbtiles", feature = "pmtiles", feature = "sprites"))]
 use crate::file_config::FileConfigEnum;
+use crate::file_config::FontConfigSrc;
 #[cfg(feature = "fonts")]
-use crate::fonts::FontSources;
+use crate::fonts::{FontSources, FontSourcesOptions};
 use crate::source::{TileInfoSources, TileSources};
 #[cfg(feature = "sprites")]
 use crate::sprites::{SpriteConfig, SpriteSources};
-use crate::srv::{SrvConfig, RESERVED_KEYWORDS};
+#[cfg(feature = "postgres")]
+use crate::srv::CachePartition;
+use crate::srv::{check_response_headers, check_webp_sources, SrvConfig, RESERVED_KEYWORDS};
+use crate::tms::{apply_tile_matrix_sets, TileMatrixSetConfig};
 use crate::utils::{parse_base_path, CacheValue, MainCache, OptMainCache};
-use crate::MartinError::{ConfigLoadError, ConfigParseError, ConfigWriteError, NoSources};
+#[cfg(feature = "postgres")]
+use crate::MartinError::UnpartitionedSessionHeader;
+use crate::MartinError::{
+    ConfigLoadError, ConfigParseError, ConfigWriteError, HealthPathError, NoSources,
+    ServerHeaderError,
+};
 use crate::{IdResolver, MartinResult, OptOneMany};
 
 pub type UnrecognizedValues = HashMap<String, serde_yaml::Value>;
@@ -28,6 +40,11 @@ pub type UnrecognizedValues = HashMap<String, serde_yaml::Value>;
 pub struct ServerState {
     pub cache: OptMainCache,
     pub tiles: TileSources,
+    #[cfg(feature = "postgres")]
+    pub pg_pools: Vec<crate::pg::PgPool>,
+    /// Materialized views to refresh in the background while the server is running
+    #[cfg(feature = "postgres")]
+    pub pg_refreshes: Vec<crate::pg::ViewRefresh>,
     #[cfg(feature = "sprites")]
     pub sprites: SpriteSources,
     #[cfg(feature = "fonts")]
@@ -59,7 +76,19 @@ pub struct Config {
     pub sprites: FileConfigEnum<SpriteConfig>,
 
     #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
-    pub fonts: OptOneMany<PathBuf>,
+    pub fonts: OptOneMany<FontConfigSrc>,
+
+    /// Number of font faces kept open by each server thread to speed up glyph rendering
+    pub face_cache_size: Option<usize>,
+
+    /// Maximum number of codepoints a single font range request may render
+    pub max_font_range: Option<usize>,
+
+    /// Render the composite fonts from the available fonts, skipping the missing ones instead of failing
+    pub skip_missing_fonts: Option<bool>,
+
+    /// Custom tile matrix sets by their ID, for the sources that do not use the Web Mercator tile grid
+    pub tile_matrix_sets: Option<BTreeMap<String, TileMatrixSetConfig>>,
 
     #[serde(flatten)]
     pub unrecognized: UnrecognizedValues,
@@ -74,11 +103,21 @@ impl Config {
         if let Some(path) = &self.srv.base_path {
             self.srv.base_path = Some(parse_base_path(path)?);
         }
+        if let Some(path) = self.srv.health_path.iter().find(|v| !v.starts_with('/')) {
+            return Err(HealthPathError(path.clone()));
+        }
+        if let Some(value) = self.srv.get_server_header() {
+            if HeaderValue::try_from(value).is_err() {
+                return Err(ServerHeaderError(value.to_string()));
+            }
+        }
 
         #[cfg(feature = "postgres")]
         for pg in self.postgres.iter_mut() {
             res.extend(pg.finalize()?);
         }
+        #[cfg(feature = "postgres")]
+        self.check_session_headers()?;
 
         #[cfg(feature = "pmtiles")]
         res.extend(self.pmtiles.finalize("pmtiles.")?);
@@ -116,8 +155,34 @@ impl Config {
         }
     }
 
+    /// The tiles of the sources with header session settings, e.g. of each tenant, may differ by the header values.
+    /// Make sure they are never served from the cache to the requests with other values.
+    #[cfg(feature = "postgres")]
+    fn check_session_headers(&self) -> MartinResult<()> {
+        let partition = match &self.srv.cache_partition {
+            Some(CachePartition::Header(name)) => Some(name.as_str()),
+            _ => None,
+        };
+        for pg in self.postgres.iter() {
+            let tables = pg.tables.iter().flatten();
+            let tables = tables.map(|(id, v)| (id, &v.session_headers));
+            let functions = pg.functions.iter().flatten();
+            let functions = functions.map(|(id, v)| (id, &v.session_headers));
+            for (id, headers) in tables.chain(functions) {
+                for header in headers.iter().flat_map(BTreeMap::values) {
+                    if !partition.is_some_and(|v| v.eq_ignore_ascii_case(header)) {
+                        return Err(UnpartitionedSessionHeader(id.clone(), header.clone()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn resolve(&mut self) -> MartinResult<ServerState> {
-        let resolver = IdResolver::new(RESERVED_KEYWORDS);
+        let mut reserved = RESERVED_KEYWORDS.to_vec();
+        reserved.extend(self.srv.get_health_ids());
+        let resolver = IdResolver::new(&reserved);
         let cache_size = self.cache_size_mb.unwrap_or(512) * 1024 * 1024;
         let cache = if cache_size > 0 {
             info!("Initializing main cache with maximum size {cache_size}B");
@@ -133,6 +198,7 @@ impl Config {
                         }
                     })
                     .max_capacity(cache_size)
+                    .support_invalidation_closures()
                     .build(),
             )
         } else {
@@ -140,45 +206,125 @@ impl Config {
             None
         };
 
+        #[cfg(feature = "postgres")]
+        let (mut tiles, pg_pools) = self.resolve_tile_sources(&resolver, cache.clone()).await?;
+        #[cfg(not(feature = "postgres"))]
+        let mut tiles = self.resolve_tile_sources(&resolver, cache.clone()).await?;
+        #[cfg(feature = "postgres")]
+        crate::pg::PgPool::check_max_connections(&pg_pools).await?;
+        #[cfg(feature = "postgres")]
+        let mut pg_refreshes = Vec::new();
+        #[cfg(feature = "postgres")]
+        for (pg, pool) in self.postgres.iter().zip(&pg_pools) {
+            pg_refreshes.extend(pg.get_view_refreshes(pool).await?);
+        }
+
+        if let Some(tms) = &self.tile_matrix_sets {
+            apply_tile_matrix_sets(&mut tiles, tms);
+        }
+        if let Some(ids) = &self.srv.transcode_webp {
+            check_webp_sources(&tiles, ids)?;
+        }
+        if let Some(headers) = &self.srv.response_headers {
+            check_response_headers(&tiles, headers)?;
+        }
+        if let Some(max) = self.srv.max_sources_per_request {
+            tiles = tiles.with_max_sources(max);
+        }
+
         Ok(ServerState {
-            tiles: self.resolve_tile_sources(&resolver, cache.clone()).await?,
+            tiles,
+            #[cfg(feature = "postgres")]
+            pg_pools,
+            #[cfg(feature = "postgres")]
+            pg_refreshes,
             #[cfg(feature = "sprites")]
             sprites: SpriteSources::resolve(&mut self.sprites)?,
             #[cfg(feature = "fonts")]
-            fonts: FontSources::resolve(&mut self.fonts)?,
+            fonts: FontSources::resolve(
+                &mut self.fonts,
+                FontSourcesOptions {
+                    face_cache_size: self.face_cache_size,
+                    max_font_range: self.max_font_range,
+                    skip_missing: self.skip_missing_fonts.unwrap_or_default(),
+                    // Render the glyphs on as many threads as the CPU budget allows
+                    render_threads: self
+                        .srv
+                        .cpu_budget
+                        .as_ref()
+                        .map(crate::srv::CpuBudgetConfig::glyph_permits),
+                },
+            )?,
             cache,
         })
     }
 
+    #[cfg(feature = "postgres")]
+    async fn resolve_tile_sources(
+        &mut self,
+        idr: &IdResolver,
+        cache: OptMainCache,
+    ) -> MartinResult<(TileSources, Vec<crate::pg::PgPool>)> {
+        let pg_sources = try_join_all(self.postgres.iter_mut().map(|s| s.resolve(idr.clone())));
+        let file_sources = Self::resolve_file_sources(
+            #[cfg(feature = "pmtiles")]
+            &mut self.pmtiles,
+            #[cfg(feature = "mbtiles")]
+            &mut self.mbtiles,
+            idr,
+            cache,
+        );
+        let (pg_sources, mut sources) = try_join(pg_sources, file_sources).await?;
+
+        let mut pools = Vec::with_capacity(pg_sources.len());
+        for (src, pool) in pg_sources {
+            sources.push(src);
+            pools.push(pool);
+        }
+
+        Ok((TileSources::new(sources), pools))
+    }
+
+    #[cfg(not(feature = "postgres"))]
     async fn resolve_tile_sources(
         &mut self,
+        idr: &IdResolver,
+        cache: OptMainCache,
+    ) -> MartinResult<TileSources> {
+        let sources = Self::resolve_file_sources(
+            #[cfg(feature = "pmtiles")]
+            &mut self.pmtiles,
+            #[cfg(feature = "mbtiles")]
+            &mut self.mbtiles,
+            idr,
+            cache,
+        );
+        Ok(TileSources::new(sources.await?))
+    }
+
+    async fn resolve_file_sources(
+        #[cfg(feature = "pmtiles")] pmtiles: &mut FileConfigEnum<crate::pmtiles::PmtConfig>,
+        #[cfg(feature = "mbtiles")] mbtiles: &mut FileConfigEnum<crate::mbtiles::MbtConfig>,
         #[allow(unused_variables)] idr: &IdResolver,
         #[allow(unused_variables)] cache: OptMainCache,
-    ) -> MartinResult<TileSources> {
+    ) -> MartinResult<Vec<TileInfoSources>> {
         #[allow(unused_mut)]
         let mut sources: Vec<Pin<Box<dyn Future<Output = MartinResult<TileInfoSources>>>>> =
             Vec::new();
 
-        #[cfg(feature = "postgres")]
-        for s in self.postgres.iter_mut() {
-            sources.push(Box::pin(s.resolve(idr.clone())));
-        }
-
         #[cfg(feature = "pmtiles")]
-        if !self.pmtiles.is_empty() {
-            let cfg = &mut self.pmtiles;
-            let val = crate::file_config::resolve_files(cfg, idr, cache.clone(), "pmtiles");
+        if !pmtiles.is_empty() {
+            let val = crate::file_config::resolve_files(pmtiles, idr, cache.clone(), "pmtiles");
             sources.push(Box::pin(val));
         }
 
         #[cfg(feature = "mbtiles")]
-        if !self.mbtiles.is_empty() {
-            let cfg = &mut self.mbtiles;
-            let val = crate::file_config::resolve_files(cfg, idr, cache.clone(), "mbtiles");
+        if !mbtiles.is_empty() {
+            let val = crate::file_config::resolve_files(mbtiles, idr, cache.clone(), "mbtiles");
             sources.push(Box::pin(val));
         }
 
-        Ok(TileSources::new(try_join_all(sources).await?))
+        try_join_all(sources).await
     }
 
     pub fn save_to_file(&self, file_name: PathBuf) -> MartinResult<()> {
@@ -238,6 +384,8 @@ where
 #[cfg(feature = "postgres")]
 #[cfg(test)]
 pub mod tests {
+    use indoc::indoc;
+
     use super::*;
     use crate::config::Config;
     use crate::test_utils::FauxEnv;
@@ -252,4 +400,38 @@ pub mod tests {
         assert!(res.is_empty(), "unrecognized config: {res:?}");
         assert_eq!(&config, expected);
     }
+
+    #[test]
+    fn test_invalid_server_header() {
+        let mut config = parse_cfg("server_header: \"martin\\nX-Injected: 1\"");
+        let err = config.finalize().unwrap_err();
+        assert!(
+            matches!(&err, ServerHeaderError(v) if v == "martin\nX-Injected: 1"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_unpartitioned_session_headers() {
+        let yaml = indoc! {"
+            postgres:
+              connection_string: postgres://postgres@localhost:5432/db
+              tables:
+                tenants:
+                  schema: public
+                  table: tenants
+                  srid: 4326
+                  geometry_column: geom
+                  session_headers:
+                    app.tenant_id: X-Tenant-Id
+        "};
+        let err = parse_cfg(yaml).finalize().unwrap_err();
+        assert!(
+            matches!(&err, UnpartitionedSessionHeader(id, h) if id == "tenants" && h == "X-Tenant-Id"),
+            "{err}"
+        );
+
+        let yaml = format!("cache_partition:\n  header: x-tenant-id\n{yaml}");
+        assert!(parse_cfg(&yaml).finalize().is_ok());
+    }
 }
@@ -14,8 +14,8 @@ use crate::file_config::FileError::{
 };
 use crate::source::{Source, TileInfoSources};
 use crate::utils::{IdResolver, OptMainCache, OptOneMany};
-use crate::MartinResult;
 use crate::OptOneMany::{Many, One};
+use crate::{MartinResult, OverzoomSource, TileJsonFileSource};
 
 pub type FileResult<T> = Result<T, FileError>;
 
@@ -39,6 +39,9 @@ pub enum FileError {
     #[error(r"Unable to parse metadata in file {1}: {0}")]
     InvalidUrlMetadata(String, Url),
 
+    #[error(r"Unable to parse TileJSON file {}: {0}", .1.display())]
+    InvalidTileJson(String, PathBuf),
+
     #[error(r#"Unable to acquire connection to file: {0}"#)]
     AcquireConnError(String),
 
@@ -216,15 +219,109 @@ impl FileConfigSrc {
         }
     }
 
+    #[must_use]
+    pub fn get_overzoom(&self) -> Option<u8> {
+        match self {
+            Self::Path(_) => None,
+            Self::Obj(o) => o.overzoom,
+        }
+    }
+
+    #[must_use]
+    pub fn get_tilejson_path(&self) -> Option<&PathBuf> {
+        match self {
+            Self::Path(_) => None,
+            Self::Obj(o) => o.tilejson_path.as_ref(),
+        }
+    }
+
     pub fn abs_path(&self) -> FileResult<PathBuf> {
         let path = self.get_path();
         path.canonicalize().map_err(|e| IoError(e, path.clone()))
     }
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct FileConfigSource {
     pub path: PathBuf,
+    /// Serve the zoom levels above the `maxzoom` of a raster source up to this level,
+    /// by scaling up the parts of its `maxzoom` tiles
+    pub overzoom: Option<u8>,
+    /// Serve this `TileJSON` file instead of the generated one, with the `tiles` URL of the source
+    pub tilejson_path: Option<PathBuf>,
+}
+
+/// A font file or directory, either as a plain path or with the caching policy of its glyphs,
+/// or a map of custom font IDs to the font files, e.g. `noto-sc: ./fonts/NotoSansCJKsc-Regular.otf`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FontConfigSrc {
+    Path(PathBuf),
+    Obj(FontConfigSource),
+    Ids(BTreeMap<String, FontIdSrc>),
+}
+
+impl FontConfigSrc {
+    /// The font file or directory, or `None` for a map of custom font IDs to the font files
+    #[must_use]
+    pub fn get_path(&self) -> Option<&PathBuf> {
+        match self {
+            Self::Path(p) => Some(p),
+            Self::Obj(o) => Some(&o.path),
+            Self::Ids(_) => None,
+        }
+    }
+}
+
+/// A font file published under a custom ID, either as a plain path or with the caching policy of its glyphs
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FontIdSrc {
+    Path(PathBuf),
+    Obj(FontConfigSource),
+}
+
+impl FontIdSrc {
+    #[must_use]
+    pub fn get_path(&self) -> &PathBuf {
+        match self {
+            Self::Path(p) => p,
+            Self::Obj(o) => &o.path,
+        }
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FontConfigSource {
+    pub path: PathBuf,
+    /// The fonts never change, e.g. because their path contains a version hash,
+    /// so the clients may cache the glyphs forever
+    pub immutable: Option<bool>,
+    /// How long (in seconds) the clients may cache the glyphs
+    pub max_age: Option<u32>,
+    /// Padding around each glyph in pixels, overriding the default SDF buffer
+    pub buffer: Option<usize>,
+    /// How far (in pixels) the signed distance field spreads from the glyph outline, overriding the default radius
+    pub radius: Option<usize>,
+    /// Fraction of the distance range used for the outside of the glyph (from 0 to 1), overriding the default cutoff
+    pub cutoff: Option<f64>,
+    /// How to name the fonts with the same name as an already configured font
+    pub duplicate_names: Option<DuplicateFontNames>,
+}
+
+/// How to name a font with the same family and style as an already configured font
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateFontNames {
+    /// Ignore the font, keeping the one configured first
+    #[default]
+    Ignore,
+    /// Append a counter to the name, e.g. `Roboto Bold 2`
+    Counter,
+    /// Append the file name to the name, e.g. `Roboto Bold (roboto-bold-v2)`
+    FileName,
 }
 
 pub async fn resolve_files<T: SourceConfigExtras>(
@@ -259,8 +356,9 @@ async fn resolve_int<T: SourceConfigExtras>(
                 let dup = !files.insert(source.get_path().clone());
                 let dup = if dup { "duplicate " } else { "" };
                 let id = idr.resolve(&id, url.to_string());
+                let src = cfg.custom.new_sources_url(id.clone(), url.clone()).await?;
+                results.push(with_source_config(src, &source)?);
                 configs.insert(id.clone(), source);
-                results.push(cfg.custom.new_sources_url(id.clone(), url.clone()).await?);
                 info!("Configured {dup}source {id} from {}", sanitize_url(&url));
             } else {
                 let can = source.abs_path()?;
@@ -273,8 +371,12 @@ async fn resolve_int<T: SourceConfigExtras>(
                 let dup = if dup { "duplicate " } else { "" };
                 let id = idr.resolve(&id, can.to_string_lossy().to_string());
                 info!("Configured {dup}source {id} from {}", can.display());
-                configs.insert(id.clone(), source.clone());
-                results.push(cfg.custom.new_sources(id, source.into_path()).await?);
+                let src = cfg
+                    .custom
+                    .new_sources(id.clone(), source.get_path().clone())
+                    .await?;
+                results.push(with_source_config(src, &source)?);
+                configs.insert(id, source);
             }
         }
     }
@@ -333,6 +435,18 @@ async fn resolve_int<T: SourceConfigExtras>(
     Ok(results)
 }
 
+/// Apply the per-source settings of the configuration to a new source
+fn with_source_config(source: Box<dyn Source>, cfg: &FileConfigSrc) -> FileResult<Box<dyn Source>> {
+    let source = match cfg.get_overzoom() {
+        Some(overzoom) => OverzoomSource::wrap(source, overzoom),
+        None => source,
+    };
+    match cfg.get_tilejson_path() {
+        Some(path) => TileJsonFileSource::wrap(source, path),
+        None => Ok(source),
+    }
+}
+
 fn dir_to_paths(path: &Path, extension: &str) -> Result<Vec<PathBuf>, FileError> {
     Ok(path
         .read_dir()
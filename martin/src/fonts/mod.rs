@@ -1,23 +1,34 @@
-use std::collections::hash_map::Entry;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::ffi::OsStr;
-use std::fmt::Debug;
-use std::path::PathBuf;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::OnceLock;
 
 use bit_set::BitSet;
 use itertools::Itertools as _;
 use log::{debug, info, warn};
-use pbf_font_tools::freetype::{Face, Library};
+use pbf_font_tools::freetype::{Error as FtError, Face, Library};
 use pbf_font_tools::protobuf::Message;
 use pbf_font_tools::{render_sdf_glyph, Fontstack, Glyphs, PbfFontError};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::file_config::{DuplicateFontNames, FontConfigSource, FontConfigSrc, FontIdSrc};
 use crate::OptOneMany;
 
-const MAX_UNICODE_CP: usize = 0xFFFF;
+/// The last Unicode codepoint, including the supplementary planes beyond the Basic Multilingual Plane
+const MAX_UNICODE_CP: usize = 0x0010_FFFF;
 const CP_RANGE_SIZE: usize = 256;
+/// Extensions of the font files. `FreeType` decompresses the WOFF and WOFF2 web fonts by itself.
+const FONT_EXTENSIONS: &[&str] = &["otf", "ttf", "ttc", "woff", "woff2"];
+const WEB_FONT_EXTENSIONS: &[&str] = &["woff", "woff2"];
+/// Number of glyphs rendered by one parallel task, so that a range is split between several threads
+const GLYPH_CHUNK_SIZE: usize = 16;
 const FONT_SIZE: usize = 24;
 #[allow(clippy::cast_possible_wrap)]
 const CHAR_HEIGHT: isize = (FONT_SIZE as isize) << 6;
@@ -25,8 +36,14 @@ const BUFFER_SIZE: usize = 3;
 const RADIUS: usize = 8;
 const CUTOFF: f64 = 0.25_f64;
 
-/// Each range is 256 codepoints long, so the highest range ID is 0xFFFF / 256 = 255.
-const MAX_UNICODE_CP_RANGE_ID: usize = MAX_UNICODE_CP / CP_RANGE_SIZE;
+/// Default number of font faces kept open by each server thread
+pub const FACE_CACHE_SIZE_DEFAULT: usize = 8;
+
+/// Default maximum number of codepoints a single font range request may render
+pub const MAX_FONT_RANGE_DEFAULT: usize = 4096;
+
+/// Immutable glyphs are cached for a year, the maximum recommended by RFC 2616
+const IMMUTABLE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
 
 pub type FontResult<T> = Result<T, FontError>;
 
@@ -49,20 +66,43 @@ pub enum FontError {
     #[error("Given font range {0}-{1} is invalid. It must be {CP_RANGE_SIZE} characters long (e.g. 0-255, 256-511, ...)")]
     InvalidFontRange(u32, u32),
 
+    #[error("Font range {0}-{1} is too large. At most {2} characters can be requested at once (max_font_range)")]
+    FontRangeTooLarge(u32, u32, usize),
+
+    #[error("Font range {0}-{1} is beyond the last Unicode character U+10FFFF")]
+    FontRangeBeyondUnicode(u32, u32),
+
+    #[error("max_font_range is {0}, but it must be at least {CP_RANGE_SIZE}")]
+    InvalidMaxFontRange(usize),
+
     #[error(transparent)]
     FreeType(#[from] pbf_font_tools::freetype::Error),
 
     #[error("IO error accessing {}: {0}", .1.display())]
     IoError(std::io::Error, PathBuf),
 
+    #[error("Unable to load the web font {}. The FreeType library may have been built without WOFF or WOFF2 support: {0}", .1.display())]
+    UnsupportedWebFont(FtError, PathBuf),
+
     #[error("Invalid font file {}", .0.display())]
     InvalidFontFilePath(PathBuf),
 
     #[error("No font files found in {}", .0.display())]
     NoFontFilesFound(PathBuf),
 
-    #[error("Font {0} is missing a family name")]
-    MissingFamilyName(PathBuf),
+    #[error("Invalid SDF parameters of the fonts in {}: {1}", .0.display())]
+    InvalidSdfParams(PathBuf, &'static str),
+
+    #[error("Font ID {0} is configured more than once, the second time for {}", .1.display())]
+    DuplicateFontId(String, PathBuf),
+
+    #[error(
+        "Font ID \"{0}\" must not be empty, or contain slashes, commas, or repeated whitespace"
+    )]
+    InvalidFontId(String),
+
+    #[error("Unable to start the glyph rendering threads: {0}")]
+    RenderPoolError(#[from] rayon::ThreadPoolBuildError),
 
     #[error(transparent)]
     PbfFontError(#[from] PbfFontError),
@@ -71,40 +111,235 @@ pub enum FontError {
     ErrorSerializingProtobuf(#[from] pbf_font_tools::protobuf::Error),
 }
 
+thread_local! {
+    /// FreeType faces are not thread-safe, so each thread keeps its own recently used faces
+    static FACE_CACHE: RefCell<FaceCache> = RefCell::new(FaceCache::default());
+}
+
+/// Total number of faces cached by all threads
+static CACHED_FACES: AtomicUsize = AtomicUsize::new(0);
+
+/// A least-recently-used cache with a fixed capacity. The most recently used entries are at the back.
+#[derive(Debug)]
+struct LruCache<K, V> {
+    capacity: usize,
+    entries: VecDeque<(K, V)>,
+}
+
+impl<K, V> Default for LruCache<K, V> {
+    fn default() -> Self {
+        Self {
+            capacity: FACE_CACHE_SIZE_DEFAULT,
+            entries: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: PartialEq, V> LruCache<K, V> {
+    /// Get a value by its key, and mark it as the most recently used
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let idx = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(idx)?;
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, v)| v)
+    }
+
+    /// Add a new value, and return the least recently used values that no longer fit
+    fn insert(&mut self, key: K, value: V) -> Vec<(K, V)> {
+        self.entries.push_back((key, value));
+        self.shrink()
+    }
+
+    /// Change the capacity, and return the least recently used values that no longer fit
+    fn set_capacity(&mut self, capacity: usize) -> Vec<(K, V)> {
+        self.capacity = capacity;
+        self.shrink()
+    }
+
+    fn shrink(&mut self) -> Vec<(K, V)> {
+        let count = self.entries.len().saturating_sub(self.capacity);
+        self.entries.drain(..count).collect()
+    }
+}
+
+#[derive(Default)]
+struct FaceCache {
+    lib: Option<Library>,
+    faces: LruCache<(PathBuf, isize), Face>,
+}
+
+impl FaceCache {
+    /// Run `f` with the requested font face, opening it if it is not cached yet
+    fn with_face<T>(
+        &mut self,
+        font: &FontSource,
+        capacity: usize,
+        f: impl FnOnce(&Face) -> FontResult<T>,
+    ) -> FontResult<T> {
+        let evicted = self.faces.set_capacity(capacity).len();
+        CACHED_FACES.fetch_sub(evicted, Relaxed);
+
+        let key = (font.path.clone(), font.face_index);
+        if let Some(face) = self.faces.get(&key) {
+            return f(face);
+        }
+
+        let lib = match &self.lib {
+            Some(lib) => lib,
+            None => self.lib.insert(Library::init()?),
+        };
+        let face = lib.new_face(&font.path, font.face_index)?;
+
+        // FreeType conventions: char width or height of zero means "use the same value"
+        // and setting both resolution values to zero results in the default value
+        // of 72 dpi.
+        //
+        // See https://www.freetype.org/freetype2/docs/reference/ft2-base_interface.html#ft_set_char_size
+        // and https://www.freetype.org/freetype2/docs/tutorial/step1.html for details.
+        face.set_char_size(0, CHAR_HEIGHT, 0, 0)?;
+
+        let result = f(&face);
+        let evicted = self.faces.insert(key, face).len();
+        CACHED_FACES.fetch_add(1, Relaxed);
+        CACHED_FACES.fetch_sub(evicted, Relaxed);
+        result
+    }
+}
+
 type GetGlyphInfo = (BitSet, usize, Vec<(usize, usize)>, usize, usize);
 
+/// Get the codepoints of the face by walking its character map, instead of probing every Unicode codepoint
 fn get_available_codepoints(face: &mut Face) -> Option<GetGlyphInfo> {
-    let mut codepoints = BitSet::with_capacity(MAX_UNICODE_CP);
-    let mut spans = Vec::new();
-    let mut first: Option<usize> = None;
-    let mut count = 0;
-
-    for cp in 0..=MAX_UNICODE_CP {
-        if face.get_char_index(cp).is_ok() {
-            codepoints.insert(cp);
-            count += 1;
-            if first.is_none() {
-                first = Some(cp);
-            }
-        } else if let Some(start) = first {
-            spans.push((start, cp - 1));
-            first = None;
+    let codepoints = face
+        .chars()
+        .map(|(cp, _)| cp)
+        .filter(|cp| *cp <= MAX_UNICODE_CP)
+        .collect();
+    get_glyph_info(codepoints)
+}
+
+/// Get the number of codepoints, their contiguous spans, and the first and the last codepoint,
+/// or `None` if there are no codepoints
+fn get_glyph_info(codepoints: BitSet) -> Option<GetGlyphInfo> {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for cp in &codepoints {
+        match spans.last_mut() {
+            Some((_, end)) if *end + 1 == cp => *end = cp,
+            _ => spans.push((cp, cp)),
         }
     }
+    let (start, _) = *spans.first()?;
+    let (_, end) = *spans.last()?;
+    let count = codepoints.len();
+    Some((codepoints, count, spans, start, end))
+}
 
-    if count == 0 {
-        None
-    } else {
-        let start = spans[0].0;
-        let end = spans[spans.len() - 1].1;
-        Some((codepoints, count, spans, start, end))
-    }
+/// Settings of the font sources that apply to all configured fonts
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FontSourcesOptions {
+    /// Maximum number of faces cached by each server thread
+    pub face_cache_size: Option<usize>,
+    /// Maximum number of codepoints in a requested glyph range
+    pub max_font_range: Option<usize>,
+    /// Skip the missing fonts of a composite font instead of failing the whole request
+    pub skip_missing: bool,
+    /// Number of threads rendering the glyphs. Defaults to the number of CPUs.
+    pub render_threads: Option<usize>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct FontSources {
     fonts: HashMap<String, FontSource>,
-    masks: Vec<BitSet>,
+    face_cache_size: usize,
+    max_font_range: usize,
+    /// Skip the missing fonts of a composite font instead of failing the whole request
+    skip_missing: bool,
+    /// Dedicated threads rendering the glyphs, so that a large range does not occupy the global rayon pool
+    render_pool: Option<Arc<ThreadPool>>,
+}
+
+/// Usage of the font face caches, reported by the `/status` endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FontCacheStatus {
+    /// Maximum number of faces cached by each server thread
+    pub face_cache_size: usize,
+    /// Number of faces currently cached by all threads
+    pub cached_faces: usize,
+}
+
+/// The `Cache-Control` policy for the glyphs of a font
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FontCacheControl {
+    pub max_age: u32,
+    pub immutable: bool,
+}
+
+impl FontCacheControl {
+    fn from_config(cfg: &FontConfigSource) -> Option<Self> {
+        let immutable = cfg.immutable.unwrap_or_default();
+        let max_age = match cfg.max_age {
+            Some(v) => v,
+            None if immutable => IMMUTABLE_MAX_AGE,
+            None => None?,
+        };
+        Some(Self { max_age, immutable })
+    }
+
+    /// Combine the policies of several fonts into one that is valid for all of them
+    #[must_use]
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            max_age: self.max_age.min(other.max_age),
+            immutable: self.immutable && other.immutable,
+        }
+    }
+}
+
+impl Display for FontCacheControl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "public, max-age={}", self.max_age)?;
+        if self.immutable {
+            write!(f, ", immutable")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of the signed distance field rendering of the glyphs of a font
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfParams {
+    pub buffer: usize,
+    pub radius: usize,
+    pub cutoff: f64,
+}
+
+impl Default for SdfParams {
+    fn default() -> Self {
+        Self {
+            buffer: BUFFER_SIZE,
+            radius: RADIUS,
+            cutoff: CUTOFF,
+        }
+    }
+}
+
+impl SdfParams {
+    fn from_config(cfg: &FontConfigSource) -> FontResult<Self> {
+        let default = Self::default();
+        let params = Self {
+            buffer: cfg.buffer.unwrap_or(default.buffer),
+            radius: cfg.radius.unwrap_or(default.radius),
+            cutoff: cfg.cutoff.unwrap_or(default.cutoff),
+        };
+        let err = |msg| Err(FontError::InvalidSdfParams(cfg.path.clone(), msg));
+        if params.radius == 0 {
+            return err("radius must be greater than 0");
+        }
+        if !(0.0..1.0).contains(&params.cutoff) {
+            return err("cutoff must be at least 0 and less than 1");
+        }
+        Ok(params)
+    }
 }
 
 pub type FontCatalog = BTreeMap<String, CatalogFontEntry>;
@@ -120,30 +355,64 @@ pub struct CatalogFontEntry {
 }
 
 impl FontSources {
-    pub fn resolve(config: &mut OptOneMany<PathBuf>) -> FontResult<Self> {
+    pub fn resolve(
+        config: &mut OptOneMany<FontConfigSrc>,
+        options: FontSourcesOptions,
+    ) -> FontResult<Self> {
+        let FontSourcesOptions {
+            face_cache_size,
+            max_font_range,
+            skip_missing,
+            render_threads,
+        } = options;
+        let max_font_range = max_font_range.unwrap_or(MAX_FONT_RANGE_DEFAULT);
+        if max_font_range < CP_RANGE_SIZE {
+            return Err(FontError::InvalidMaxFontRange(max_font_range));
+        }
         if config.is_empty() {
-            return Ok(Self::default());
+            return Ok(Self {
+                max_font_range,
+                skip_missing,
+                ..Self::default()
+            });
         }
 
         let mut fonts = HashMap::new();
         let lib = Library::init()?;
 
-        for path in config.iter() {
-            recurse_dirs(&lib, path.clone(), &mut fonts, true)?;
+        for src in config.iter() {
+            let (path, opts) = match src {
+                FontConfigSrc::Path(path) => (path, FontOptions::default()),
+                FontConfigSrc::Obj(cfg) => (&cfg.path, FontOptions::from_config(cfg)?),
+                FontConfigSrc::Ids(ids) => {
+                    for (id, src) in ids {
+                        let opts = match src {
+                            FontIdSrc::Path(_) => FontOptions::default(),
+                            FontIdSrc::Obj(cfg) => FontOptions::from_config(cfg)?,
+                        };
+                        add_font_with_id(&lib, &mut fonts, id, src.get_path(), opts)?;
+                    }
+                    continue;
+                }
+            };
+            recurse_dirs(&lib, path.clone(), &mut fonts, true, opts)?;
         }
-
-        let mut masks = Vec::with_capacity(MAX_UNICODE_CP_RANGE_ID + 1);
-
-        let mut bs = BitSet::with_capacity(CP_RANGE_SIZE);
-        for v in 0..=MAX_UNICODE_CP {
-            bs.insert(v);
-            if v % CP_RANGE_SIZE == (CP_RANGE_SIZE - 1) {
-                masks.push(bs);
-                bs = BitSet::with_capacity(CP_RANGE_SIZE);
-            }
+        if skip_missing {
+            warn!("skip_missing_fonts is enabled, so the composite fonts are rendered without their missing fonts");
         }
 
-        Ok(Self { fonts, masks })
+        let render_pool = ThreadPoolBuilder::new()
+            .num_threads(render_threads.unwrap_or_else(num_cpus::get).max(1))
+            .thread_name(|idx| format!("martin-glyphs-{idx}"))
+            .build()?;
+
+        Ok(Self {
+            fonts,
+            face_cache_size: face_cache_size.unwrap_or(FACE_CACHE_SIZE_DEFAULT),
+            max_font_range,
+            skip_missing,
+            render_pool: Some(Arc::new(render_pool)),
+        })
     }
 
     #[must_use]
@@ -155,12 +424,47 @@ impl FontSources {
             .collect()
     }
 
+    #[must_use]
+    pub fn get_cache_status(&self) -> FontCacheStatus {
+        FontCacheStatus {
+            face_cache_size: self.face_cache_size,
+            cached_faces: CACHED_FACES.load(Relaxed),
+        }
+    }
+
+    /// Given a list of IDs in a format "id1,id2,id3", return the caching policy valid for all of them,
+    /// or `None` if any of the fonts has no caching policy configured.
+    #[must_use]
+    pub fn get_cache_control(&self, ids: &str) -> Option<FontCacheControl> {
+        ids.split(',')
+            .map(|id| self.fonts.get(id).and_then(|v| v.cache_control))
+            .reduce(|a, b| Some(a?.merge(b?)))
+            .flatten()
+    }
+
+    /// Get the sorted contiguous spans of the codepoints available in a single font
+    pub fn get_coverage(&self, id: &str) -> FontResult<&[(usize, usize)]> {
+        self.fonts
+            .get(id)
+            .map(|v| v.spans.as_slice())
+            .ok_or_else(|| FontError::FontNotFound(id.to_string()))
+    }
+
     /// Given a list of IDs in a format "id1,id2,id3", return a combined font.
+    /// If `skip_missing` is set, the missing fonts are skipped as long as at least one of the fonts exists.
     #[allow(clippy::cast_possible_truncation)]
     pub fn get_font_range(&self, ids: &str, start: u32, end: u32) -> FontResult<Vec<u8>> {
         if start > end {
             return Err(FontError::InvalidFontRangeStartEnd(start, end));
         }
+        // Reject huge ranges before anything else, as each codepoint may need to be rendered
+        if u64::from(end - start) >= self.max_font_range as u64 {
+            return Err(FontError::FontRangeTooLarge(
+                start,
+                end,
+                self.max_font_range,
+            ));
+        }
         if start % (CP_RANGE_SIZE as u32) != 0 {
             return Err(FontError::InvalidFontRangeStart(start));
         }
@@ -170,13 +474,22 @@ impl FontSources {
         if (end - start) != (CP_RANGE_SIZE as u32 - 1) {
             return Err(FontError::InvalidFontRange(start, end));
         }
+        if end as usize > MAX_UNICODE_CP {
+            return Err(FontError::FontRangeBeyondUnicode(start, end));
+        }
 
-        let mut needed = self.masks[(start as usize) / CP_RANGE_SIZE].clone();
+        let mut needed: BitSet = (start as usize..=end as usize).collect();
+        let mut found = false;
         let fonts = ids
             .split(',')
             .filter_map(|id| match self.fonts.get(id) {
+                None if self.skip_missing => {
+                    debug!("Skipping missing font {id} of the composite font {ids}");
+                    None
+                }
                 None => Some(Err(FontError::FontNotFound(id.to_string()))),
                 Some(v) => {
+                    found = true;
                     let mut ds = needed.clone();
                     ds.intersect_with(&v.codepoints);
                     if ds.is_empty() {
@@ -189,11 +502,13 @@ impl FontSources {
             })
             .collect::<FontResult<Vec<_>>>()?;
 
+        if !found {
+            return Err(FontError::FontNotFound(ids.to_string()));
+        }
         if fonts.is_empty() {
             return Ok(Vec::new());
         }
 
-        let lib = Library::init()?;
         let mut stack = Fontstack::new();
 
         for (id, font, ds) in fonts {
@@ -205,20 +520,40 @@ impl FontSources {
                 stack.set_name(id.to_string());
             }
 
-            let face = lib.new_face(&font.path, font.face_index)?;
-
-            // FreeType conventions: char width or height of zero means "use the same value"
-            // and setting both resolution values to zero results in the default value
-            // of 72 dpi.
-            //
-            // See https://www.freetype.org/freetype2/docs/reference/ft2-base_interface.html#ft_set_char_size
-            // and https://www.freetype.org/freetype2/docs/tutorial/step1.html for details.
-            face.set_char_size(0, CHAR_HEIGHT, 0, 0)?;
-
-            for cp in &ds {
-                let glyph = render_sdf_glyph(&face, cp as u32, BUFFER_SIZE, RADIUS, CUTOFF)?;
-                stack.glyphs.push(glyph);
-            }
+            let SdfParams {
+                buffer,
+                radius,
+                cutoff,
+            } = font.sdf;
+            // FreeType faces are not thread-safe, so each rayon thread renders its chunks with its own cached face.
+            // The chunks are collected in their original order, keeping the glyphs sorted by their codepoints.
+            let codepoints: Vec<usize> = ds.iter().collect();
+            let render = || {
+                codepoints
+                    .par_chunks(GLYPH_CHUNK_SIZE)
+                    .map(|chunk| {
+                        FACE_CACHE.with(|cache| {
+                            cache
+                                .borrow_mut()
+                                .with_face(font, self.face_cache_size, |face| {
+                                    chunk
+                                        .iter()
+                                        .map(|cp| {
+                                            Ok(render_sdf_glyph(
+                                                face, *cp as u32, buffer, radius, cutoff,
+                                            )?)
+                                        })
+                                        .collect::<FontResult<Vec<_>>>()
+                                })
+                        })
+                    })
+                    .collect::<FontResult<Vec<_>>>()
+            };
+            let chunks = match &self.render_pool {
+                Some(pool) => pool.install(render)?,
+                None => render()?,
+            };
+            stack.glyphs.extend(chunks.into_iter().flatten());
         }
 
         stack.set_range(format!("{start}-{end}"));
@@ -236,7 +571,29 @@ pub struct FontSource {
     path: PathBuf,
     face_index: isize,
     codepoints: BitSet,
+    /// Sorted contiguous spans of the available codepoints, as inclusive `(first, last)` pairs
+    spans: Vec<(usize, usize)>,
     catalog_entry: CatalogFontEntry,
+    cache_control: Option<FontCacheControl>,
+    sdf: SdfParams,
+}
+
+/// Settings of a configured font path, applied to all the fonts found in it
+#[derive(Debug, Clone, Copy, Default)]
+struct FontOptions {
+    cache_control: Option<FontCacheControl>,
+    sdf: SdfParams,
+    duplicates: DuplicateFontNames,
+}
+
+impl FontOptions {
+    fn from_config(cfg: &FontConfigSource) -> FontResult<Self> {
+        Ok(Self {
+            cache_control: FontCacheControl::from_config(cfg),
+            sdf: SdfParams::from_config(cfg)?,
+            duplicates: cfg.duplicate_names.unwrap_or_default(),
+        })
+    }
 }
 
 fn recurse_dirs(
@@ -244,6 +601,7 @@ fn recurse_dirs(
     path: PathBuf,
     fonts: &mut HashMap<String, FontSource>,
     is_top_level: bool,
+    opts: FontOptions,
 ) -> FontResult<()> {
     let start_count = fonts.len();
     if path.is_dir() {
@@ -252,42 +610,128 @@ fn recurse_dirs(
             .map_err(|e| FontError::IoError(e, path.clone()))?
             .flatten()
         {
-            recurse_dirs(lib, dir_entry.path(), fonts, false)?;
+            recurse_dirs(lib, dir_entry.path(), fonts, false, opts)?;
         }
         if is_top_level && fonts.len() == start_count {
             return Err(FontError::NoFontFilesFound(path));
         }
+    } else if path
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|e| FONT_EXTENSIONS.contains(&e))
+    {
+        // The faces of a font file may all be skipped, e.g. as ignored duplicates, which is not an error
+        parse_font(lib, fonts, path, opts, None)?;
+    } else if is_top_level {
+        return Err(FontError::InvalidFontFilePath(path));
+    }
+
+    Ok(())
+}
+
+/// Add the first face of a font file under a custom ID instead of its family and style
+fn add_font_with_id(
+    lib: &Library,
+    fonts: &mut HashMap<String, FontSource>,
+    id: &str,
+    path: &Path,
+    opts: FontOptions,
+) -> FontResult<()> {
+    if id.is_empty() || clean_font_name(id) != id {
+        return Err(FontError::InvalidFontId(id.to_string()));
+    }
+    if fonts.contains_key(id) {
+        return Err(FontError::DuplicateFontId(
+            id.to_string(),
+            path.to_path_buf(),
+        ));
+    }
+    if path.is_file() {
+        parse_font(lib, fonts, path.to_path_buf(), opts, Some(id))?;
+    }
+    if fonts.contains_key(id) {
+        Ok(())
     } else {
-        if path
-            .extension()
-            .and_then(OsStr::to_str)
-            .is_some_and(|e| ["otf", "ttf", "ttc"].contains(&e))
-        {
-            parse_font(lib, fonts, path.clone())?;
-        }
-        if is_top_level && fonts.len() == start_count {
-            return Err(FontError::InvalidFontFilePath(path));
+        Err(FontError::InvalidFontFilePath(path.to_path_buf()))
+    }
+}
+
+/// Find a unique name for a font with the same name as an already configured one,
+/// or `None` if the duplicate should be ignored
+fn dedup_font_name(
+    fonts: &HashMap<String, FontSource>,
+    name: &str,
+    path: &Path,
+    duplicates: DuplicateFontNames,
+) -> Option<String> {
+    if duplicates == DuplicateFontNames::Ignore {
+        return None;
+    }
+    if duplicates == DuplicateFontNames::FileName {
+        if let Some(stem) = path.file_stem().and_then(OsStr::to_str) {
+            let new_name = clean_font_name(&format!("{name} ({stem})"));
+            if !fonts.contains_key(&new_name) {
+                return Some(new_name);
+            }
         }
     }
+    // Fall back to a counter if the file name does not make the name unique.
+    // There are more counters than configured fonts, so one of them is always free.
+    (2..=fonts.len() + 2)
+        .map(|idx| format!("{name} {idx}"))
+        .find(|v| !fonts.contains_key(v))
+}
 
-    Ok(())
+/// Make sure font name has no slashes or commas, replacing them with spaces and de-duplicating spaces
+fn clean_font_name(name: &str) -> String {
+    static RE_SPACES: OnceLock<Regex> = OnceLock::new();
+    RE_SPACES
+        .get_or_init(|| Regex::new(r"(\s|/|,)+").unwrap())
+        .replace_all(name, " ")
+        .to_string()
 }
 
 fn parse_font(
     lib: &Library,
     fonts: &mut HashMap<String, FontSource>,
     path: PathBuf,
+    opts: FontOptions,
+    custom_id: Option<&str>,
 ) -> FontResult<()> {
-    static RE_SPACES: OnceLock<Regex> = OnceLock::new();
-
-    let mut face = lib.new_face(&path, 0)?;
-    let num_faces = face.num_faces() as isize;
+    let mut face = lib.new_face(&path, 0).map_err(|e| {
+        // Without the WOFF or WOFF2 support, FreeType does not recognize the web fonts at all.
+        // Other errors, e.g. of a corrupt web font, are reported as they are.
+        let is_web_font = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| WEB_FONT_EXTENSIONS.contains(&ext));
+        if is_web_font && matches!(e, FtError::UnknownFileFormat) {
+            FontError::UnsupportedWebFont(e, path.clone())
+        } else {
+            FontError::FreeType(e)
+        }
+    })?;
+    // A custom ID names only the first face of the file
+    let num_faces = if custom_id.is_some() {
+        1
+    } else {
+        face.num_faces() as isize
+    };
     for face_index in 0..num_faces {
         if face_index > 0 {
             face = lib.new_face(&path, face_index)?;
         }
-        let Some(family) = face.family_name() else {
-            return Err(FontError::MissingFamilyName(path));
+        let family = match (face.family_name(), custom_id) {
+            (Some(family), _) => family,
+            // The custom ID names the font, so it does not need a family name
+            (None, Some(id)) => id.to_string(),
+            (None, None) => {
+                warn!(
+                    "Ignoring font face #{face_index} in {} because it has no family name",
+                    path.display()
+                );
+                continue;
+            }
         };
         let mut name = family.clone();
         let style = face.style_name();
@@ -295,64 +739,422 @@ fn parse_font(
             name.push(' ');
             name.push_str(style);
         }
-        // Make sure font name has no slashes or commas, replacing them with spaces and de-duplicating spaces
-        name = RE_SPACES
-            .get_or_init(|| Regex::new(r"(\s|/|,)+").unwrap())
-            .replace_all(name.as_str(), " ")
-            .to_string();
+        name = match custom_id {
+            Some(id) => id.to_string(),
+            None => clean_font_name(&name),
+        };
 
-        match fonts.entry(name) {
-            Entry::Occupied(v) => {
+        if let Some(existing) = fonts.get(&name) {
+            let Some(new_name) = dedup_font_name(fonts, &name, &path, opts.duplicates) else {
                 warn!(
-                    "Ignoring duplicate font {} from {} because it was already configured from {}",
-                    v.key(),
+                    "Ignoring duplicate font {name} from {} because it was already configured from {}",
                     path.display(),
-                    v.get().path.display()
+                    existing.path.display()
                 );
-            }
-            Entry::Vacant(v) => {
-                let key = v.key();
-                let Some((codepoints, glyphs, ranges, start, end)) =
-                    get_available_codepoints(&mut face)
-                else {
-                    warn!(
-                        "Ignoring font {key} from {} because it has no available glyphs",
-                        path.display()
-                    );
-                    continue;
-                };
+                continue;
+            };
+            info!(
+                "Renaming duplicate font {name} from {} to {new_name} because it was already configured from {}",
+                path.display(),
+                existing.path.display()
+            );
+            name = new_name;
+        }
 
-                info!(
-                    "Configured font {key} with {glyphs} glyphs ({start:04X}-{end:04X}) from {}",
-                    path.display()
-                );
-                debug!(
-                    "Available font ranges: {}",
-                    ranges
-                        .iter()
-                        .map(|(s, e)| if s == e {
-                            format!("{s:02X}")
-                        } else {
-                            format!("{s:02X}-{e:02X}")
-                        })
-                        .join(", "),
-                );
+        let Some((codepoints, glyphs, spans, start, end)) = get_available_codepoints(&mut face)
+        else {
+            warn!(
+                "Ignoring font {name} from {} because it has no available glyphs",
+                path.display()
+            );
+            continue;
+        };
 
-                v.insert(FontSource {
-                    path: path.clone(),
-                    face_index,
-                    codepoints,
-                    catalog_entry: CatalogFontEntry {
-                        family,
-                        style,
-                        glyphs,
-                        start,
-                        end,
-                    },
-                });
-            }
-        }
+        info!(
+            "Configured font {name} with {glyphs} glyphs ({start:04X}-{end:04X}) from {}",
+            path.display()
+        );
+        debug!(
+            "Available font ranges: {}",
+            spans
+                .iter()
+                .map(|(s, e)| if s == e {
+                    format!("{s:02X}")
+                } else {
+                    format!("{s:02X}-{e:02X}")
+                })
+                .join(", "),
+        );
+
+        fonts.insert(
+            name,
+            FontSource {
+                path: path.clone(),
+                face_index,
+                codepoints,
+                spans,
+                catalog_entry: CatalogFontEntry {
+                    family,
+                    style,
+                    glyphs,
+                    start,
+                    end,
+                },
+                cache_control: opts.cache_control,
+                sdf: opts.sdf,
+            },
+        );
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn test_cache_control() {
+        let mut config = OptOneMany::Many(vec![
+            FontConfigSrc::Obj(FontConfigSource {
+                path: PathBuf::from("../tests/fixtures/fonts/overpass-mono-regular.ttf"),
+                immutable: Some(true),
+                ..Default::default()
+            }),
+            FontConfigSrc::Obj(FontConfigSource {
+                path: PathBuf::from("../tests/fixtures/fonts/sub_dir"),
+                max_age: Some(3600),
+                ..Default::default()
+            }),
+        ]);
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+
+        let regular = fonts.get_cache_control("Overpass Mono Regular").unwrap();
+        assert_eq!(regular.to_string(), "public, max-age=31536000, immutable");
+        let light = fonts.get_cache_control("Overpass Mono Light").unwrap();
+        assert_eq!(light.to_string(), "public, max-age=3600");
+
+        let both = fonts.get_cache_control("Overpass Mono Regular,Overpass Mono Light");
+        assert_eq!(both, Some(light));
+        assert_eq!(fonts.get_cache_control("Unknown Font"), None);
+    }
+
+    #[test]
+    fn test_duplicate_font_names() {
+        let font = |duplicate_names| {
+            FontConfigSrc::Obj(FontConfigSource {
+                path: PathBuf::from("../tests/fixtures/fonts/overpass-mono-regular.ttf"),
+                duplicate_names,
+                ..Default::default()
+            })
+        };
+        for (duplicates, expected) in [
+            (None, vec!["Overpass Mono Regular"]),
+            (
+                Some(DuplicateFontNames::Counter),
+                vec!["Overpass Mono Regular", "Overpass Mono Regular 2"],
+            ),
+            (
+                Some(DuplicateFontNames::FileName),
+                vec![
+                    "Overpass Mono Regular",
+                    "Overpass Mono Regular (overpass-mono-regular)",
+                ],
+            ),
+        ] {
+            let mut config = OptOneMany::Many(vec![font(None), font(duplicates)]);
+            let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+            let catalog = fonts.get_catalog();
+            assert_eq!(catalog.keys().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn test_font_ids() {
+        let path = PathBuf::from("../tests/fixtures/fonts/overpass-mono-regular.ttf");
+        let ids = |id: &str| {
+            let src = FontIdSrc::Path(path.clone());
+            FontConfigSrc::Ids(BTreeMap::from([(id.to_string(), src)]))
+        };
+
+        let mut config = OptOneMany::Many(vec![ids("overpass"), FontConfigSrc::Path(path.clone())]);
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+        let catalog = fonts.get_catalog();
+        assert_eq!(
+            catalog.keys().collect::<Vec<_>>(),
+            vec!["Overpass Mono Regular", "overpass"]
+        );
+        assert_eq!(catalog["overpass"].family, "Overpass Mono");
+        assert!(!fonts.get_font_range("overpass", 0, 255).unwrap().is_empty());
+
+        let mut config = OptOneMany::Many(vec![ids("overpass"), ids("overpass")]);
+        let err = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap_err();
+        assert!(matches!(err, FontError::DuplicateFontId(id, _) if id == "overpass"));
+
+        for id in ["", "a,b", "a/b", "a  b"] {
+            let mut config = OptOneMany::One(ids(id));
+            let err = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap_err();
+            assert!(matches!(err, FontError::InvalidFontId(_)), "{id}");
+        }
+
+        // The custom IDs have the same settings as the other fonts
+        let mut config: OptOneMany<FontConfigSrc> = serde_yaml::from_str(indoc! {"
+            overpass:
+              path: ../tests/fixtures/fonts/overpass-mono-regular.ttf
+              max_age: 3600
+              radius: 16
+        "})
+        .unwrap();
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+        assert_eq!(
+            fonts.get_cache_control("overpass").unwrap().to_string(),
+            "public, max-age=3600"
+        );
+        assert_eq!(fonts.fonts["overpass"].sdf.radius, 16);
+
+        // The custom ID names the face even if it has no family name
+        let nameless = PathBuf::from("../tests/fixtures/nameless_fonts/nameless.ttf");
+        let mut config = OptOneMany::One(FontConfigSrc::Ids(BTreeMap::from([(
+            "nameless".to_string(),
+            FontIdSrc::Path(nameless),
+        )])));
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+        assert_eq!(fonts.get_catalog()["nameless"].family, "nameless");
+        assert!(!fonts.get_font_range("nameless", 0, 255).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_nameless_font() {
+        let mut config = OptOneMany::One(FontConfigSrc::Path(PathBuf::from(
+            "../tests/fixtures/nameless_fonts",
+        )));
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+        let catalog = fonts.get_catalog();
+        assert_eq!(
+            catalog.keys().collect::<Vec<_>>(),
+            vec!["Overpass Mono Light"]
+        );
+    }
+
+    #[test]
+    fn test_sdf_params() {
+        let mut config = OptOneMany::Many(vec![
+            FontConfigSrc::Obj(FontConfigSource {
+                path: PathBuf::from("../tests/fixtures/fonts/overpass-mono-regular.ttf"),
+                radius: Some(2),
+                ..Default::default()
+            }),
+            FontConfigSrc::Obj(FontConfigSource {
+                path: PathBuf::from("../tests/fixtures/fonts/sub_dir"),
+                radius: Some(16),
+                ..Default::default()
+            }),
+        ]);
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+
+        // Number of the pixels of the glyph "A" covered by the distance field
+        let coverage = |id: &str| {
+            let data = fonts.get_font_range(id, 0, 255).unwrap();
+            let glyphs = Glyphs::parse_from_bytes(&data).unwrap();
+            let glyph = glyphs.stacks[0].glyphs.iter().find(|g| g.id() == 65);
+            glyph.unwrap().bitmap().iter().filter(|v| **v > 0).count()
+        };
+        let small = coverage("Overpass Mono Regular");
+        let large = coverage("Overpass Mono Light");
+        assert!(
+            large > small,
+            "radius 16 covers {large}, radius 2 covers {small}"
+        );
+
+        for (radius, cutoff) in [(Some(0), None), (None, Some(1.0)), (None, Some(-0.1))] {
+            let mut config = OptOneMany::One(FontConfigSrc::Obj(FontConfigSource {
+                path: PathBuf::from("../tests/fixtures/fonts/overpass-mono-regular.ttf"),
+                radius,
+                cutoff,
+                ..Default::default()
+            }));
+            assert!(matches!(
+                FontSources::resolve(&mut config, FontSourcesOptions::default()),
+                Err(FontError::InvalidSdfParams(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::default();
+        assert!(cache.set_capacity(2).is_empty());
+        assert!(cache.insert(1, "a").is_empty());
+        assert!(cache.insert(2, "b").is_empty());
+
+        // Using the first entry makes the second one the least recently used
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.insert(3, "c"), vec![(2, "b")]);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+
+        assert_eq!(cache.set_capacity(1), vec![(1, "a")]);
+        assert_eq!(cache.get(&3), Some(&"c"));
+    }
+
+    #[test]
+    fn test_max_font_range() {
+        let mut config = OptOneMany::One(FontConfigSrc::Path(PathBuf::from(
+            "../tests/fixtures/fonts/overpass-mono-regular.ttf",
+        )));
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+        let id = "Overpass Mono Regular";
+        assert!(fonts.get_font_range(id, 0, 255).is_ok());
+        assert!(matches!(
+            fonts.get_font_range(id, 0, 0x1_FFFF),
+            Err(FontError::FontRangeTooLarge(
+                0,
+                0x1_FFFF,
+                MAX_FONT_RANGE_DEFAULT
+            ))
+        ));
+        assert!(matches!(
+            fonts.get_font_range(id, 0, u32::MAX),
+            Err(FontError::FontRangeTooLarge(..))
+        ));
+
+        let fonts = FontSources::resolve(
+            &mut config,
+            FontSourcesOptions {
+                max_font_range: Some(1024),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            fonts.get_font_range(id, 0, 2047),
+            Err(FontError::FontRangeTooLarge(0, 2047, 1024))
+        ));
+
+        assert!(matches!(
+            FontSources::resolve(
+                &mut config,
+                FontSourcesOptions {
+                    max_font_range: Some(100),
+                    ..Default::default()
+                },
+            ),
+            Err(FontError::InvalidMaxFontRange(100))
+        ));
+    }
+
+    #[test]
+    fn test_astral_glyph_info() {
+        // A font with only a few glyphs beyond the Basic Multilingual Plane, up to the last codepoint
+        let codepoints: BitSet = [0x1F600, 0x1F601, 0x1F602, 0x20000, MAX_UNICODE_CP]
+            .into_iter()
+            .collect();
+        let (_, count, spans, start, end) = get_glyph_info(codepoints).unwrap();
+        assert_eq!(count, 5);
+        assert_eq!(
+            spans,
+            vec![
+                (0x1F600, 0x1F602),
+                (0x20000, 0x20000),
+                (MAX_UNICODE_CP, MAX_UNICODE_CP)
+            ]
+        );
+        assert_eq!((start, end), (0x1F600, MAX_UNICODE_CP));
+
+        assert!(get_glyph_info(BitSet::new()).is_none());
+    }
+
+    #[test]
+    fn test_astral_font_range() {
+        let mut config = OptOneMany::One(FontConfigSrc::Path(PathBuf::from(
+            "../tests/fixtures/fonts/overpass-mono-regular.ttf",
+        )));
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+
+        // The font has no glyphs in the supplementary planes, but the ranges are valid
+        let font = "Overpass Mono Regular";
+        assert!(fonts.get_font_range(font, 65536, 65791).unwrap().is_empty());
+        assert!(fonts
+            .get_font_range(font, 0x10FF00, 0x10FFFF)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            fonts.get_font_range(font, 0x110000, 0x1100FF),
+            Err(FontError::FontRangeBeyondUnicode(0x110000, 0x1100FF))
+        ));
+    }
+
+    #[test]
+    fn test_skip_missing_fonts() {
+        let font = "../tests/fixtures/fonts/overpass-mono-regular.ttf";
+        let resolve = |skip_missing| {
+            let mut config = OptOneMany::One(FontConfigSrc::Path(PathBuf::from(font)));
+            FontSources::resolve(
+                &mut config,
+                FontSourcesOptions {
+                    skip_missing,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let ids = "Missing Font,Overpass Mono Regular,Another Missing Font";
+
+        assert!(matches!(
+            resolve(false).get_font_range(ids, 0, 255),
+            Err(FontError::FontNotFound(id)) if id == "Missing Font"
+        ));
+
+        let fonts = resolve(true);
+        let data = fonts.get_font_range(ids, 0, 255).unwrap();
+        let glyphs = Glyphs::parse_from_bytes(&data).unwrap();
+        assert_eq!(glyphs.stacks[0].name(), "Overpass Mono Regular");
+        assert!(!glyphs.stacks[0].glyphs.is_empty());
+
+        // At least one of the fonts must exist
+        assert!(matches!(
+            fonts.get_font_range("Missing Font,Another Missing Font", 0, 255),
+            Err(FontError::FontNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_broken_web_font() {
+        let path = PathBuf::from("../tests/fixtures/webfonts/broken.woff2");
+        let mut config = OptOneMany::One(FontConfigSrc::Path(path.clone()));
+        let err = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap_err();
+        assert!(
+            matches!(&err, FontError::UnsupportedWebFont(_, p) if *p == path),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_glyph_order() {
+        let mut config = OptOneMany::One(FontConfigSrc::Path(PathBuf::from(
+            "../tests/fixtures/fonts/overpass-mono-regular.ttf",
+        )));
+        let fonts = FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap();
+        let font = &fonts.fonts["Overpass Mono Regular"];
+        let expected: Vec<u32> = font
+            .codepoints
+            .iter()
+            .take_while(|cp| *cp < 256)
+            .map(|cp| u32::try_from(cp).unwrap())
+            .collect();
+        assert!(expected.len() > GLYPH_CHUNK_SIZE);
+
+        // The glyphs are rendered in parallel, but always returned in the order of their codepoints
+        for _ in 0..3 {
+            let data = fonts
+                .get_font_range("Overpass Mono Regular", 0, 255)
+                .unwrap();
+            let glyphs = Glyphs::parse_from_bytes(&data).unwrap();
+            let ids: Vec<u32> = glyphs.stacks[0].glyphs.iter().map(|g| g.id()).collect();
+            assert_eq!(ids, expected);
+        }
+    }
+}
@@ -5,7 +5,16 @@ mod config;
 pub use config::{read_config, Config, ServerState};
 
 mod source;
-pub use source::{CatalogSourceEntry, Source, Tile, TileData, TileSources, UrlQuery};
+pub use source::{CatalogSourceEntry, MemorySource, Source, Tile, TileData, TileSources, UrlQuery};
+
+mod overzoom;
+pub use overzoom::OverzoomSource;
+
+mod tilejson_file;
+pub use tilejson_file::TileJsonFileSource;
+
+mod tms;
+pub use tms::{apply_tile_matrix_sets, TileMatrix, TileMatrixSet, TileMatrixSetConfig, TmsSource};
 
 mod utils;
 pub use utils::{
@@ -176,6 +176,8 @@ mod tests {
                     "pm-src2".to_string(),
                     FileConfigSrc::Obj(FileConfigSource {
                         path: PathBuf::from("/tmp/file.ext"),
+                        overzoom: None,
+                        tilejson_path: None,
                     })
                 ),
                 (
@@ -186,6 +188,8 @@ mod tests {
                     "pm-src4".to_string(),
                     FileConfigSrc::Obj(FileConfigSource {
                         path: PathBuf::from("https://example.org/file4.ext"),
+                        overzoom: None,
+                        tilejson_path: None,
                     })
                 ),
             ]))
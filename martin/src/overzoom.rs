@@ -0,0 +1,185 @@
+use async_trait::async_trait;
+use log::warn;
+use martin_tile_utils::{Format, TileInfo};
+use tilejson::TileJSON;
+
+use crate::source::{Source, TileData, TileInfoSource, UrlQuery};
+use crate::utils::overzoom_image;
+use crate::MartinError::{OverzoomError, UnsupportedTileFormat};
+use crate::{MartinResult, TileCoord};
+
+/// Deeper overzooming would scale up less than a pixel even of the largest tiles
+const MAX_OVERZOOM_LEVELS: u8 = 16;
+
+/// A raster source that serves the zoom levels above its `maxzoom` by scaling up
+/// the corresponding part of its `maxzoom` tiles, published as `fillzoom` in the `TileJSON`
+#[derive(Clone, Debug)]
+pub struct OverzoomSource {
+    source: TileInfoSource,
+    fillzoom: u8,
+    tilejson: TileJSON,
+}
+
+impl OverzoomSource {
+    /// Let the source serve tiles up to the `overzoom` level. Only PNG, JPEG, and WebP sources
+    /// with a known `maxzoom` below `overzoom` can be overzoomed, the others are returned as is.
+    #[must_use]
+    pub fn wrap(source: TileInfoSource, overzoom: u8) -> TileInfoSource {
+        let id = source.get_id();
+        let format = source.get_tile_info().format;
+        if !matches!(format, Format::Png | Format::Jpeg | Format::Webp) {
+            warn!("Source {id} cannot be overzoomed because its tiles are {format}, but only PNG, JPEG, and WebP tiles are supported");
+            return source;
+        }
+        let Some(fillzoom) = source.get_tilejson().maxzoom else {
+            warn!("Source {id} cannot be overzoomed because its maxzoom is unknown");
+            return source;
+        };
+        if overzoom <= fillzoom {
+            warn!("Ignoring overzoom={overzoom} of source {id} because it is not above its maxzoom={fillzoom}");
+            return source;
+        }
+        let max_overzoom = fillzoom.saturating_add(MAX_OVERZOOM_LEVELS);
+        if overzoom > max_overzoom {
+            warn!("Limiting overzoom={overzoom} of source {id} to {max_overzoom}");
+        }
+        let overzoom = overzoom.min(max_overzoom);
+
+        let mut tilejson = source.get_tilejson().clone();
+        tilejson.fillzoom = Some(fillzoom);
+        tilejson.maxzoom = Some(overzoom);
+        Box::new(Self {
+            source,
+            fillzoom,
+            tilejson,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for OverzoomSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        if xyz.z <= self.fillzoom {
+            return self.source.get_tile(xyz, url_query).await;
+        }
+        let dz = xyz.z - self.fillzoom;
+        let parent = TileCoord {
+            z: self.fillzoom,
+            x: xyz.x >> dz,
+            y: xyz.y >> dz,
+        };
+        let data = self.source.get_tile(parent, url_query).await?;
+        if data.is_empty() {
+            return Ok(data);
+        }
+        // Position of the requested tile within the parent tile
+        let mask = (1_u32 << dz) - 1;
+        let format = self.get_tile_info().format;
+        overzoom_image(&data, format, dz, xyz.x & mask, xyz.y & mask)
+            .map_err(|e| OverzoomError(self.get_id().to_string(), xyz, e))
+    }
+
+    fn get_alt_formats(&self) -> Vec<Format> {
+        self.source.get_alt_formats()
+    }
+
+    async fn get_alt_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        format: Format,
+    ) -> MartinResult<TileData> {
+        // Only the images themselves can be scaled up
+        if xyz.z > self.fillzoom {
+            return Err(UnsupportedTileFormat(self.get_id().to_string(), format));
+        }
+        self.source.get_alt_tile(xyz, url_query, format).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use martin_tile_utils::Encoding;
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::MemorySource;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    #[actix_rt::test]
+    async fn test_overzoom() {
+        // The top left quarter of the only tile is red, the rest is blue
+        let img = RgbaImage::from_fn(8, 8, |x, y| if x < 4 && y < 4 { RED } else { BLUE });
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let mut tj = tilejson! { tiles: vec![] };
+        tj.maxzoom = Some(0);
+        let info = TileInfo::new(Format::Png, Encoding::Internal);
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        let src = MemorySource::new("raster", tj, info).with_tile(xyz(0, 0, 0), png.clone());
+        let src = OverzoomSource::wrap(Box::new(src), 2);
+
+        let tj = src.get_tilejson();
+        assert_eq!(tj.fillzoom, Some(0));
+        assert_eq!(tj.maxzoom, Some(2));
+        assert!(src.is_valid_tile(xyz(2, 3, 3)));
+        assert!(!src.is_valid_tile(xyz(3, 0, 0)));
+
+        // The maxzoom tile is served as is
+        assert_eq!(src.get_tile(xyz(0, 0, 0), None).await.unwrap(), png);
+
+        // The overzoomed tiles are scaled up parts of the maxzoom tile, with the same size
+        for (tile, color) in [
+            (xyz(1, 0, 0), RED),
+            (xyz(1, 1, 0), BLUE),
+            (xyz(2, 1, 1), RED),
+            (xyz(2, 2, 1), BLUE),
+        ] {
+            let data = src.get_tile(tile, None).await.unwrap();
+            let img = image::load_from_memory(&data).unwrap().to_rgba8();
+            assert_eq!(img.dimensions(), (8, 8));
+            assert!(img.pixels().all(|p| *p == color), "{tile}");
+        }
+    }
+
+    #[test]
+    fn test_overzoom_unsupported() {
+        let mut tj = tilejson! { tiles: vec![] };
+        tj.maxzoom = Some(5);
+        let mvt = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let src = OverzoomSource::wrap(Box::new(MemorySource::new("a", tj.clone(), mvt)), 10);
+        assert_eq!(src.get_tilejson().maxzoom, Some(5));
+
+        let png = TileInfo::new(Format::Png, Encoding::Internal);
+        let src = OverzoomSource::wrap(Box::new(MemorySource::new("b", tj, png)), 4);
+        assert_eq!(src.get_tilejson().fillzoom, None);
+    }
+}
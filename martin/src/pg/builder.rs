@@ -1,24 +1,26 @@
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
-use futures::future::join_all;
 use itertools::Itertools as _;
 use log::{debug, error, info, warn};
+use tilejson::Bounds;
 
 use crate::args::BoundsCalcType;
-use crate::pg::config::{PgConfig, PgInfo};
+use crate::pg::config::{ClientMinMessages, PgConfig, PgInfo};
 use crate::pg::config_function::{FuncInfoSources, FunctionInfo};
 use crate::pg::config_table::{TableInfo, TableInfoSources};
 use crate::pg::pg_source::{PgSource, PgSqlInfo};
-use crate::pg::pool::PgPool;
+use crate::pg::pool::{PgPool, POOL_SIZE_DEFAULT};
 use crate::pg::query_functions::query_available_function;
 use crate::pg::query_tables::{query_available_tables, table_to_query};
-use crate::pg::utils::{find_info, find_kv_ignore_case, normalize_key, InfoMap};
-use crate::pg::PgError::InvalidTableExtent;
+use crate::pg::utils::{
+    find_info, find_kv_ignore_case, glob_match, join_bounded, normalize_key, InfoMap,
+};
+use crate::pg::PgError::{InvalidTableExtent, MissingSpatialIndex, TooManyProperties};
 use crate::pg::{PgCfgPublish, PgCfgPublishFuncs, PgResult};
 use crate::source::TileInfoSources;
 use crate::utils::IdResolver;
-use crate::utils::OptOneMany::NoVals;
+use crate::utils::OptOneMany::{self, NoVals};
 use crate::OptBoolObj::{Bool, NoValue, Object};
 
 pub type SqlFuncInfoMapMap = InfoMap<InfoMap<(PgSqlInfo, FunctionInfo)>>;
@@ -29,8 +31,19 @@ pub type SqlTableInfoMapMapMap = InfoMap<InfoMap<InfoMap<TableInfo>>>;
 pub struct PgBuilder {
     pool: PgPool,
     default_srid: Option<i32>,
+    schema_default_srids: BTreeMap<String, i32>,
     auto_bounds: BoundsCalcType,
+    default_bounds: Option<Bounds>,
+    fail_on_missing_index: bool,
     max_feature_count: Option<usize>,
+    query_timeout_ms: Option<u64>,
+    function_retries: Option<u32>,
+    function_retry_delay_ms: Option<u64>,
+    client_min_messages: Option<ClientMinMessages>,
+    /// Maximum number of tables to set up at the same time, e.g. computing their bounds
+    discovery_concurrency: usize,
+    include_tables: Vec<String>,
+    exclude_tables: Vec<String>,
     auto_functions: Option<PgBuilderFuncs>,
     auto_tables: Option<PgBuilderTables>,
     id_resolver: IdResolver,
@@ -51,10 +64,13 @@ pub struct PgBuilderTables {
     #[cfg_attr(test, serde(serialize_with = "crate::pg::utils::sorted_opt_set"))]
     schemas: Option<HashSet<String>>,
     source_id_format: String,
+    layer_id_format: Option<String>,
     id_columns: Option<Vec<String>>,
     clip_geom: Option<bool>,
     buffer: Option<u32>,
+    buffer_px: Option<u32>,
     extent: Option<u32>,
+    max_properties: Option<usize>,
 }
 
 /// Combine `from_schema` field from the `config.auto_publish` and `config.auto_publish.tables/functions`
@@ -86,8 +102,21 @@ impl PgBuilder {
         Ok(Self {
             pool,
             default_srid: config.default_srid,
+            schema_default_srids: config.schema_default_srids.clone().unwrap_or_default(),
             auto_bounds: config.auto_bounds.unwrap_or_default(),
+            default_bounds: config.default_bounds,
+            fail_on_missing_index: config.fail_on_missing_index.unwrap_or_default(),
             max_feature_count: config.max_feature_count,
+            query_timeout_ms: config.query_timeout_ms,
+            function_retries: config.function_retries,
+            function_retry_delay_ms: config.function_retry_delay_ms,
+            client_min_messages: config.client_min_messages,
+            discovery_concurrency: config
+                .discovery_concurrency
+                .or(config.pool_size)
+                .unwrap_or(POOL_SIZE_DEFAULT),
+            include_tables: config.include_tables.iter().cloned().collect(),
+            exclude_tables: config.exclude_tables.iter().cloned().collect(),
             id_resolver,
             tables: config.tables.clone().unwrap_or_default(),
             functions: config.functions.clone().unwrap_or_default(),
@@ -104,14 +133,28 @@ impl PgBuilder {
         self.pool.get_id()
     }
 
+    pub fn get_pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    fn default_srid_for(&self, schema: &str) -> Option<i32> {
+        schema_default_srid(&self.schema_default_srids, self.default_srid, schema)
+    }
+
     // FIXME: this function has gotten too long due to the new formatting rules, need to be refactored
     #[allow(clippy::too_many_lines)]
     pub async fn instantiate_tables(&self) -> PgResult<(TileInfoSources, TableInfoSources)> {
         let mut db_tables_info = query_available_tables(&self.pool).await?;
+        retain_allowed_tables(
+            &mut db_tables_info,
+            &self.include_tables,
+            &self.exclude_tables,
+        );
 
         // Match configured sources with the discovered ones and add them to the pending list.
         let mut used = HashSet::<(&str, &str, &str)>::new();
         let mut pending = Vec::new();
+        let mut missing_index = Vec::new();
         for (id, cfg_inf) in &self.tables {
             // TODO: move this validation to serde somehow?
             if let Some(extent) = cfg_inf.extent {
@@ -139,16 +182,21 @@ impl PgBuilder {
             let dup = if dup { "duplicate " } else { "" };
 
             let id2 = self.resolve_id(id, cfg_inf);
-            let Some(merged_inf) = db_inf.append_cfg_info(cfg_inf, &id2, self.default_srid) else {
+            let default_srid = self.default_srid_for(&cfg_inf.schema);
+            let Some(merged_inf) = db_inf.append_cfg_info(cfg_inf, &id2, default_srid) else {
                 continue;
             };
             warn_on_rename(id, &id2, "Table");
             info!("Configured {dup}source {id2} from {}", summary(&merged_inf));
+            if is_missing_index(&merged_inf) {
+                missing_index.push(merged_inf.format_id());
+            }
             pending.push(table_to_query(
                 id2,
                 merged_inf,
                 self.pool.clone(),
                 self.auto_bounds,
+                self.default_bounds,
                 self.max_feature_count,
             ));
         }
@@ -171,27 +219,39 @@ impl PgBuilder {
                 };
                 let db_tables = db_tables_info.remove(&schema).unwrap();
                 for (table, geoms) in db_tables.into_iter().sorted_by(by_key) {
-                    for (geom_column, mut db_inf) in geoms.into_iter().sorted_by(by_key) {
+                    let geoms = geoms.into_iter().sorted_by(by_key).enumerate();
+                    for (geom_index, (geom_column, mut db_inf)) in geoms {
                         if used.contains(&(schema.as_str(), table.as_str(), geom_column.as_str())) {
                             continue;
                         }
-                        let source_id = auto_tables
-                            .source_id_format
-                            .replace("{schema}", &schema)
-                            .replace("{table}", &table)
-                            .replace("{column}", &geom_column);
+                        let source_id = auto_table_id(
+                            &auto_tables.source_id_format,
+                            &schema,
+                            &table,
+                            &geom_column,
+                            geom_index,
+                        );
                         let id2 = self.resolve_id(&source_id, &db_inf);
-                        let Some(srid) = db_inf.calc_srid(&id2, 0, self.default_srid) else {
+                        let default_srid = self.default_srid_for(&schema);
+                        let Some(srid) = db_inf.calc_srid(&id2, 0, default_srid) else {
                             continue;
                         };
                         db_inf.srid = srid;
                         update_auto_fields(&id2, &mut db_inf, auto_tables);
+                        if let Err(e) = check_max_properties(&id2, &db_inf, auto_tables) {
+                            error!("{e}");
+                            continue;
+                        }
                         info!("Discovered source {id2} from {}", summary(&db_inf));
+                        if is_missing_index(&db_inf) {
+                            missing_index.push(db_inf.format_id());
+                        }
                         pending.push(table_to_query(
                             id2,
                             db_inf,
                             self.pool.clone(),
                             self.auto_bounds,
+                            self.default_bounds,
                             self.max_feature_count,
                         ));
                     }
@@ -199,18 +259,27 @@ impl PgBuilder {
             }
         }
 
+        if self.fail_on_missing_index && !missing_index.is_empty() {
+            return Err(MissingSpatialIndex(missing_index));
+        }
+
         let mut res = TileInfoSources::default();
         let mut info_map = TableInfoSources::new();
-        let pending = join_all(pending).await;
+        let pending = join_bounded(pending, self.discovery_concurrency, "tables").await;
         for src in pending {
             match src {
                 Err(v) => {
                     error!("Failed to create a source: {v}");
                     continue;
                 }
-                Ok((id, pg_sql, src_inf)) => {
+                Ok((id, mut pg_sql, src_inf)) => {
                     debug!("{id} query: {}", pg_sql.sql_query);
-                    self.add_func_src(&mut res, id.clone(), &src_inf, pg_sql.clone());
+                    pg_sql.query_timeout_ms = src_inf.query_timeout_ms.or(self.query_timeout_ms);
+                    pg_sql.session_settings = src_inf.session_settings.clone().unwrap_or_default();
+                    pg_sql.session_headers = src_inf.session_headers.clone().unwrap_or_default();
+                    pg_sql.client_min_messages =
+                        src_inf.client_min_messages.or(self.client_min_messages);
+                    self.add_func_src(&mut res, id.clone(), &src_inf, pg_sql);
                     info_map.insert(id, src_inf);
                 }
             }
@@ -243,6 +312,15 @@ impl PgBuilder {
             let dup = !used.insert((&cfg_inf.schema, func_name));
             let dup = if dup { "duplicate " } else { "" };
             let id2 = self.resolve_id(id, &merged_inf);
+            let mut pg_sql = self.func_sql_info(pg_sql);
+            pg_sql.session_settings = merged_inf.session_settings.clone().unwrap_or_default();
+            pg_sql.session_headers = merged_inf.session_headers.clone().unwrap_or_default();
+            pg_sql.default_params = merged_inf.default_params.clone().unwrap_or_default();
+            pg_sql.required_params = merged_inf.required_params.clone().unwrap_or_default();
+            pg_sql.param_ranges = merged_inf.param_ranges.clone().unwrap_or_default();
+            if let Some(level) = merged_inf.client_min_messages {
+                pg_sql.client_min_messages = Some(level);
+            }
             self.add_func_src(&mut res, id2.clone(), &merged_inf, pg_sql.clone());
             warn_on_rename(id, &id2, "Function");
             let signature = &pg_sql.signature;
@@ -277,6 +355,7 @@ impl PgBuilder {
                         .replace("{schema}", &schema)
                         .replace("{function}", &func);
                     let id2 = self.resolve_id(&source_id, &db_inf);
+                    let pg_sql = self.func_sql_info(&pg_sql);
                     self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone());
                     info!("Discovered source {id2} from function {}", pg_sql.signature);
                     debug!("{id2} query: {}", pg_sql.sql_query);
@@ -287,6 +366,18 @@ impl PgBuilder {
         Ok((res, info_map))
     }
 
+    /// Apply the connection-wide settings that are specific to the function sources
+    fn func_sql_info(&self, pg_sql: &PgSqlInfo) -> PgSqlInfo {
+        let mut pg_sql = pg_sql.clone();
+        pg_sql.query_timeout_ms = self.query_timeout_ms;
+        pg_sql.client_min_messages = self.client_min_messages;
+        pg_sql.retries = self.function_retries.unwrap_or_default();
+        if let Some(delay) = self.function_retry_delay_ms {
+            pg_sql.retry_delay_ms = delay;
+        }
+        pg_sql
+    }
+
     fn resolve_id<T: PgInfo>(&self, id: &str, src_inf: &T) -> String {
         let signature = format!("{}.{}", self.pool.get_id(), src_inf.format_id());
         self.id_resolver.resolve(id, signature)
@@ -305,13 +396,99 @@ impl PgBuilder {
     }
 }
 
+/// Generate a source ID for an auto-discovered table. If the format does not use `{column}`,
+/// the first geometry column of the table keeps the plain ID, and the name of each other
+/// geometry column is appended to it to keep the source IDs distinct.
+fn auto_table_id(
+    source_id_format: &str,
+    schema: &str,
+    table: &str,
+    geom_column: &str,
+    geom_index: usize,
+) -> String {
+    let mut id = source_id_format
+        .replace("{schema}", schema)
+        .replace("{table}", table)
+        .replace("{column}", geom_column);
+    if geom_index > 0 && !source_id_format.contains("{column}") {
+        id.push('.');
+        id.push_str(geom_column);
+    }
+    id
+}
+
+/// Generate an MVT layer ID for an auto-discovered table, where `id` is its source ID
+fn auto_layer_id(layer_id_format: &str, id: &str, inf: &TableInfo) -> String {
+    layer_id_format
+        .replace("{schema}", &inf.schema)
+        .replace("{table}", &inf.table)
+        .replace("{column}", &inf.geometry_column)
+        .replace("{id}", id)
+}
+
+/// The SRID to use for the tables with SRID 0 in a schema, falling back to the global default
+fn schema_default_srid(
+    schema_srids: &BTreeMap<String, i32>,
+    default_srid: Option<i32>,
+    schema: &str,
+) -> Option<i32> {
+    schema_srids.get(schema).copied().or(default_srid)
+}
+
+/// Remove the tables that are not allowed by the `include_tables` and `exclude_tables` glob patterns,
+/// so that they can never become sources
+fn retain_allowed_tables(
+    tables: &mut SqlTableInfoMapMapMap,
+    include: &[String],
+    exclude: &[String],
+) {
+    if include.is_empty() && exclude.is_empty() {
+        return;
+    }
+    for (schema, schema_tables) in tables.iter_mut() {
+        schema_tables.retain(|table, _| {
+            let name = format!("{schema}.{table}");
+            let allowed = (include.is_empty() || include.iter().any(|p| glob_match(p, &name)))
+                && !exclude.iter().any(|p| glob_match(p, &name));
+            if !allowed {
+                debug!("Table {name} is not allowed by include_tables/exclude_tables, ignoring it");
+            }
+            allowed
+        });
+    }
+    tables.retain(|_, schema_tables| !schema_tables.is_empty());
+}
+
+/// Make sure an auto-discovered table does not expose more columns as tile properties than allowed
+fn check_max_properties(id: &str, inf: &TableInfo, auto_tables: &PgBuilderTables) -> PgResult<()> {
+    let count = inf.properties.as_ref().map_or(0, BTreeMap::len);
+    match auto_tables.max_properties {
+        Some(max) if count > max => Err(TooManyProperties(
+            id.to_string(),
+            inf.format_id(),
+            count,
+            max,
+        )),
+        _ => Ok(()),
+    }
+}
+
 fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTables) {
+    if inf.layer_id.is_none() {
+        inf.layer_id = auto_tables
+            .layer_id_format
+            .as_ref()
+            .map(|fmt| auto_layer_id(fmt, id, inf));
+    }
     if inf.clip_geom.is_none() {
         inf.clip_geom = auto_tables.clip_geom;
     }
     if inf.buffer.is_none() {
         inf.buffer = auto_tables.buffer;
     }
+    if inf.buffer_px.is_none() {
+        inf.buffer_px = auto_tables.buffer_px;
+    }
     if inf.extent.is_none() {
         inf.extent = auto_tables.extent;
     }
@@ -348,7 +525,7 @@ fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTabl
             continue;
         }
 
-        inf.id_column = Some(column.to_string());
+        inf.id_column = OptOneMany::One(column.clone());
         let mut final_props = props.clone();
         final_props.remove(column);
         inf.properties = Some(final_props);
@@ -377,10 +554,13 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
                     .as_deref()
                     .unwrap_or("{table}")
                     .to_string(),
+                layer_id_format: v.layer_id_format.clone(),
                 id_columns: v.id_columns.opt_iter().map(|v| v.cloned().collect()),
                 clip_geom: v.clip_geom,
                 buffer: v.buffer,
+                buffer_px: v.buffer_px,
                 extent: v.extent,
+                max_properties: v.max_properties,
             }
         } else {
             PgBuilderTables {
@@ -448,6 +628,11 @@ fn warn_on_rename(old_id: &String, new_id: &String, typ: &str) {
     }
 }
 
+/// Views are exempt, as they cannot have indices, and will generally use the indices of their tables
+fn is_missing_index(info: &TableInfo) -> bool {
+    info.geometry_index == Some(false) && info.is_view == Some(false)
+}
+
 fn summary(info: &TableInfo) -> String {
     let relkind = match info.is_view {
         Some(true) => "view",
@@ -634,4 +819,137 @@ mod tests {
             auto_funcs: ~
             "###);
     }
+
+    #[test]
+    fn test_auto_table_id() {
+        let ids: Vec<_> = ["geom", "geom2"]
+            .iter()
+            .enumerate()
+            .map(|(idx, col)| auto_table_id("{table}", "public", "tbl", col, idx))
+            .collect();
+        assert_eq!(ids, vec!["tbl", "tbl.geom2"]);
+
+        let id = auto_table_id("{schema}.{table}.{column}", "public", "tbl", "geom2", 1);
+        assert_eq!(id, "public.tbl.geom2");
+    }
+
+    #[test]
+    fn test_auto_layer_id() {
+        let auto_tables = PgBuilderTables {
+            source_id_format: "{schema}.{table}".to_string(),
+            layer_id_format: Some("{table}".to_string()),
+            ..Default::default()
+        };
+        let table = TableInfo {
+            schema: "public".to_string(),
+            table: "tbl".to_string(),
+            geometry_column: "geom".to_string(),
+            ..Default::default()
+        };
+
+        let mut inf = table.clone();
+        update_auto_fields("public.tbl", &mut inf, &auto_tables);
+        assert_eq!(inf.layer_id.as_deref(), Some("tbl"));
+        assert_eq!(
+            inf.to_tilejson("public.tbl".to_string())
+                .vector_layers
+                .unwrap()[0]
+                .id,
+            "tbl"
+        );
+
+        // An explicit layer ID is kept
+        let mut inf = TableInfo {
+            layer_id: Some("abc".to_string()),
+            ..table.clone()
+        };
+        update_auto_fields("public.tbl", &mut inf, &auto_tables);
+        assert_eq!(inf.layer_id.as_deref(), Some("abc"));
+
+        let id = auto_layer_id("{id}_{schema}_{column}", "public.tbl", &table);
+        assert_eq!(id, "public.tbl_public_geom");
+    }
+
+    #[test]
+    fn test_max_properties() {
+        let wide = TableInfo {
+            schema: "public".to_string(),
+            table: "wide".to_string(),
+            geometry_column: "geom".to_string(),
+            properties: Some(
+                (0..10)
+                    .map(|i| (format!("col{i}"), "int4".to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let auto_tables = |max_properties| PgBuilderTables {
+            max_properties,
+            ..Default::default()
+        };
+
+        assert!(check_max_properties("wide", &wide, &auto_tables(None)).is_ok());
+        assert!(check_max_properties("wide", &wide, &auto_tables(Some(10))).is_ok());
+        let err = check_max_properties("wide", &wide, &auto_tables(Some(5))).unwrap_err();
+        assert!(matches!(err, TooManyProperties(_, _, 10, 5)), "{err}");
+        assert!(err.to_string().contains("public.wide.geom"));
+    }
+
+    #[test]
+    fn test_retain_allowed_tables() {
+        let tables = |names: &[(&str, &str)]| {
+            let mut res = SqlTableInfoMapMapMap::new();
+            for (schema, table) in names {
+                res.entry((*schema).to_string())
+                    .or_default()
+                    .entry((*table).to_string())
+                    .or_default()
+                    .insert("geom".to_string(), TableInfo::default());
+            }
+            res
+        };
+        let all = tables(&[
+            ("internal", "secrets"),
+            ("internal", "users"),
+            ("public", "points"),
+            ("public", "points_tmp"),
+        ]);
+
+        let mut res = all.clone();
+        retain_allowed_tables(&mut res, &[], &[]);
+        assert_eq!(res, all);
+
+        let mut res = all.clone();
+        retain_allowed_tables(&mut res, &[], &["internal.*".to_string()]);
+        assert_eq!(
+            res,
+            tables(&[("public", "points"), ("public", "points_tmp")])
+        );
+
+        let mut res = all.clone();
+        let include = ["public.*".to_string(), "internal.users".to_string()];
+        retain_allowed_tables(&mut res, &include, &["*_tmp".to_string()]);
+        assert_eq!(res, tables(&[("internal", "users"), ("public", "points")]));
+    }
+
+    #[test]
+    fn test_schema_default_srid() {
+        let srids = BTreeMap::from([("legacy".to_string(), 27700)]);
+        let table = |schema: &str| TableInfo {
+            schema: schema.to_string(),
+            table: "tbl".to_string(),
+            geometry_column: "geom".to_string(),
+            srid: 0,
+            ..Default::default()
+        };
+        let resolve = |schema: &str, default_srid| {
+            let default_srid = schema_default_srid(&srids, default_srid, schema);
+            table(schema).calc_srid("tbl", 0, default_srid)
+        };
+
+        assert_eq!(resolve("legacy", Some(4326)), Some(27700));
+        assert_eq!(resolve("modern", Some(4326)), Some(4326));
+        assert_eq!(resolve("legacy", None), Some(27700));
+        assert_eq!(resolve("modern", None), None);
+    }
 }
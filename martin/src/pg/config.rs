@@ -1,18 +1,22 @@
+use std::collections::BTreeMap;
 use std::ops::Add;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use enum_display::EnumDisplay;
 use futures::future::try_join;
 use log::warn;
 use serde::{Deserialize, Serialize};
-use tilejson::TileJSON;
+use tilejson::{Bounds, TileJSON};
 
 use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::config::{copy_unrecognized_config, UnrecognizedValues};
 use crate::pg::builder::PgBuilder;
 use crate::pg::config_function::FuncInfoSources;
 use crate::pg::config_table::TableInfoSources;
+use crate::pg::refresh::{get_view_refreshes, ViewRefresh};
 use crate::pg::utils::on_slow;
-use crate::pg::PgResult;
+use crate::pg::{PgPool, PgResult};
 use crate::source::TileInfoSources;
 use crate::utils::{IdResolver, OptBoolObj, OptOneMany};
 use crate::MartinResult;
@@ -36,16 +40,68 @@ pub struct PgSslCerts {
     pub ssl_root_cert: Option<std::path::PathBuf>,
 }
 
+/// Lowest severity of the Postgres messages sent to Martin, which logs them. Same as the `client_min_messages`
+/// setting, except that it cannot suppress errors, so they are always logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, EnumDisplay)]
+#[serde(rename_all = "lowercase")]
+#[enum_display(case = "Kebab")]
+pub enum ClientMinMessages {
+    Debug,
+    Log,
+    Notice,
+    Warning,
+    Error,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct PgConfig {
     pub connection_string: Option<String>,
+    /// File with the password of the connection, e.g. a Docker secret.
+    /// Overrides the password of the `connection_string`.
+    pub password_file: Option<PathBuf>,
     #[serde(flatten)]
     pub ssl_certificates: PgSslCerts,
     pub default_srid: Option<i32>,
+    /// SRIDs to use for the tables with SRID 0 in the given schemas, instead of `default_srid`
+    pub schema_default_srids: Option<BTreeMap<String, i32>>,
     pub auto_bounds: Option<BoundsCalcType>,
+    /// Bounds to use for the tables whose bounds are not configured and could not be computed
+    pub default_bounds: Option<Bounds>,
+    /// Refuse to start if any of the published tables has no spatial index
+    pub fail_on_missing_index: Option<bool>,
     pub max_feature_count: Option<usize>,
     pub pool_size: Option<usize>,
+    /// Number of connections to open at startup and keep idle, so that the first requests do not wait for them
+    pub min_idle: Option<usize>,
+    /// How long to wait for a free connection from the pool before giving up, in milliseconds
+    pub pool_timeout_ms: Option<u64>,
+    /// Close the connections that have not been used for this long instead of reusing them, in milliseconds
+    pub idle_timeout_ms: Option<u64>,
+    /// Close the connections that were opened this long ago instead of reusing them, in milliseconds
+    pub max_lifetime_ms: Option<u64>,
+    /// Maximum time to get a single tile from a source, in milliseconds.
+    /// Table sources may override it with their own `query_timeout_ms`.
+    pub query_timeout_ms: Option<u64>,
+    /// Number of times to retry getting a tile from a function source after a transient error,
+    /// e.g. a lock timeout or a serialization failure
+    pub function_retries: Option<u32>,
+    /// Delay before the first retry in milliseconds, doubled after each failed retry
+    pub function_retry_delay_ms: Option<u64>,
+    /// Lowest severity of the messages, e.g. NOTICEs raised by the functions, to log for the tile queries.
+    /// Sources may override it with their own `client_min_messages`.
+    pub client_min_messages: Option<ClientMinMessages>,
+    /// Maximum number of discovered and configured tables to set up at the same time, e.g. computing their bounds.
+    /// Defaults to `pool_size`.
+    pub discovery_concurrency: Option<usize>,
+    /// Only the tables whose `schema.table` name matches one of these glob patterns can become sources,
+    /// e.g. `public.*`. Supports `*` and `?` wildcards. All tables are allowed by default.
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub include_tables: OptOneMany<String>,
+    /// The tables whose `schema.table` name matches one of these glob patterns never become sources,
+    /// even if they are configured explicitly, e.g. `internal.*`
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub exclude_tables: OptOneMany<String>,
     #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
     pub auto_publish: OptBoolObj<PgCfgPublish>,
     pub tables: Option<TableInfoSources>,
@@ -71,6 +127,9 @@ pub struct PgCfgPublishTables {
     pub from_schemas: OptOneMany<String>,
     #[serde(alias = "id_format")]
     pub source_id_format: Option<String>,
+    /// How to generate the MVT layer ID of the tables that do not set `layer_id`, e.g. `{table}`.
+    /// Supports `{schema}`, `{table}`, `{column}`, and `{id}` for the source ID. Defaults to the source ID.
+    pub layer_id_format: Option<String>,
     /// A table column to use as the feature ID
     /// If a table has no column with this name, `id_column` will not be set for that table.
     /// If a list of strings is given, the first found column will be treated as a feature ID.
@@ -79,7 +138,12 @@ pub struct PgCfgPublishTables {
     pub id_columns: OptOneMany<String>,
     pub clip_geom: Option<bool>,
     pub buffer: Option<u32>,
+    /// Buffer in pixels of a 256px tile, used if `buffer` is not set
+    pub buffer_px: Option<u32>,
     pub extent: Option<u32>,
+    /// Do not publish the tables that have more columns than this, because all of them would become tile properties.
+    /// Such tables must be configured explicitly with the list of their `properties`.
+    pub max_properties: Option<usize>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -113,7 +177,12 @@ impl PgConfig {
         Ok(res)
     }
 
-    pub async fn resolve(&mut self, id_resolver: IdResolver) -> MartinResult<TileInfoSources> {
+    /// Create sources for all configured and auto-discovered tables and functions,
+    /// and return them together with the connection pool they share.
+    pub async fn resolve(
+        &mut self,
+        id_resolver: IdResolver,
+    ) -> MartinResult<(TileInfoSources, PgPool)> {
         let pg = PgBuilder::new(self, id_resolver).await?;
         let inst_tables = on_slow(
             pg.instantiate_tables(),
@@ -133,14 +202,21 @@ impl PgConfig {
         self.tables = Some(tbl_info);
         self.functions = Some(func_info);
         tables.extend(funcs);
-        Ok(tables)
+        Ok((tables, pg.get_pool().clone()))
+    }
+
+    /// Get the materialized views to refresh in the background, once the sources are resolved.
+    /// Only the server runs them, so that e.g. copying the tiles does not change the views.
+    pub async fn get_view_refreshes(&self, pool: &PgPool) -> PgResult<Vec<ViewRefresh>> {
+        match &self.tables {
+            Some(tables) => get_view_refreshes(tables, pool).await,
+            None => Ok(Vec::new()),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeMap;
-
     use indoc::indoc;
     use tilejson::Bounds;
 
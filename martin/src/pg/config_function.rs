@@ -1,12 +1,26 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
 use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON};
 
 use crate::config::UnrecognizedValues;
-use crate::pg::config::PgInfo;
+use crate::pg::config::{ClientMinMessages, PgInfo};
 use crate::pg::utils::{patch_json, InfoMap};
 
 pub type FuncInfoSources = InfoMap<FunctionInfo>;
 
+/// Allowed range of a numeric URL query parameter of a function source
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ParamRange {
+    /// Smallest allowed value, inclusive
+    pub min: Option<f64>,
+    /// Largest allowed value, inclusive
+    pub max: Option<f64>,
+    /// Clamp the out-of-range values to the range instead of rejecting the request
+    pub clamp: Option<bool>,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct FunctionInfo {
@@ -28,6 +42,28 @@ pub struct FunctionInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// Postgres settings to `SET LOCAL` before getting each tile, e.g. for row-level security.
+    /// Maps the setting names to the URL query parameters with their values.
+    pub session_settings: Option<BTreeMap<String, String>>,
+
+    /// Postgres settings to `SET LOCAL` before getting each tile, mapped to the request headers with their values.
+    /// Unlike the URL query parameters, the headers can be set by a trusted proxy, e.g. to the authenticated tenant.
+    pub session_headers: Option<BTreeMap<String, String>>,
+
+    /// Lowest severity of the Postgres messages to log for the tile queries, e.g. to hide the NOTICEs of the function.
+    /// Overrides the `client_min_messages` of the connection.
+    pub client_min_messages: Option<ClientMinMessages>,
+
+    /// Default values of the URL query parameters, used if the client does not provide them
+    pub default_params: Option<HashMap<String, String>>,
+
+    /// URL query parameters that must be given by the client or by `default_params`.
+    /// Requests without them are rejected before calling the function.
+    pub required_params: Option<BTreeSet<String>>,
+
+    /// Allowed ranges of the numeric URL query parameters, checked before calling the function
+    pub param_ranges: Option<BTreeMap<String, ParamRange>>,
+
     /// TileJSON provided by the SQL function comment. Not serialized.
     #[serde(skip)]
     pub tilejson: Option<serde_json::Value>,
@@ -80,6 +116,15 @@ impl PgInfo for FunctionInfo {
         tilejson.minzoom = self.minzoom;
         tilejson.maxzoom = self.maxzoom;
         tilejson.bounds = self.bounds;
+        if let Some(params) = &self.default_params {
+            let params: serde_json::Map<_, _> = params
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone().into()))
+                .collect();
+            tilejson
+                .other
+                .insert("default_params".to_string(), params.into());
+        }
         patch_json(tilejson, self.tilejson.as_ref())
     }
 }
@@ -5,11 +5,15 @@ use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON, VectorLayer};
 
 use crate::config::UnrecognizedValues;
-use crate::pg::config::PgInfo;
+use crate::pg::config::{ClientMinMessages, PgInfo};
 use crate::pg::utils::{normalize_key, patch_json, InfoMap};
+use crate::OptOneMany;
 
 pub type TableInfoSources = InfoMap<TableInfo>;
 
+/// Name of the feature attribute with the source ID, see [`TableInfo::source_attribute`]
+pub const SOURCE_ATTRIBUTE: &str = "source";
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct TableInfo {
@@ -36,8 +40,10 @@ pub struct TableInfo {
     #[serde(skip)]
     pub is_view: Option<bool>,
 
-    /// Feature id column name
-    pub id_column: Option<String>,
+    /// Feature id column name, or a list of the columns of a composite key,
+    /// which are hashed into a single integer feature id
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub id_column: OptOneMany<String>,
 
     /// An integer specifying the minimum zoom level
     pub minzoom: Option<u8>,
@@ -51,21 +57,75 @@ pub struct TableInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// Bounds to use if `bounds` is not set and could not be computed, e.g. for an empty table.
+    /// Overrides the `default_bounds` of the connection.
+    pub default_bounds: Option<Bounds>,
+
     /// Tile extent in tile coordinate space
     pub extent: Option<u32>,
 
     /// Buffer distance in tile coordinate space to optionally clip geometries
     pub buffer: Option<u32>,
 
+    /// Buffer distance in pixels of a 256px tile, converted to the tile coordinate space using the `extent`.
+    /// Ignored if `buffer` is also set.
+    pub buffer_px: Option<u32>,
+
     /// Boolean to control if geometries should be clipped or encoded as is
     pub clip_geom: Option<bool>,
 
+    /// Clip line and polygon geometries with the faster `ST_ClipByBox2D` instead of `ST_AsMVTGeom`.
+    /// The clipped polygons may be invalid, e.g. have self-intersections.
+    pub fast_clip: Option<bool>,
+
+    /// Maximum time to get a single tile, in milliseconds. Overrides the `query_timeout_ms` of the connection.
+    pub query_timeout_ms: Option<u64>,
+
+    /// Refresh the materialized view of this source every `refresh_interval` seconds,
+    /// and evict its tiles from the cache afterwards
+    pub refresh_interval: Option<u64>,
+
+    /// Postgres settings to `SET LOCAL` before getting each tile, e.g. for row-level security.
+    /// Maps the setting names to the URL query parameters with their values.
+    pub session_settings: Option<BTreeMap<String, String>>,
+
+    /// Postgres settings to `SET LOCAL` before getting each tile, mapped to the request headers with their values.
+    /// Unlike the URL query parameters, the headers can be set by a trusted proxy, e.g. to the authenticated tenant.
+    pub session_headers: Option<BTreeMap<String, String>>,
+
+    /// Lowest severity of the Postgres messages to log for the tile queries.
+    /// Overrides the `client_min_messages` of the connection.
+    pub client_min_messages: Option<ClientMinMessages>,
+
     /// Geometry type
     pub geometry_type: Option<String>,
 
+    /// For point tables, cluster points into grid cells at zoom levels below this value
+    pub cluster_below_zoom: Option<u8>,
+
+    /// Size of a clustering grid cell in tile coordinate space
+    pub cluster_radius: Option<u32>,
+
+    /// Skip features with a bounding box larger than this many tiles at the requested zoom level
+    pub max_feature_area: Option<f64>,
+
+    /// Add a constant `source` attribute with the source ID to each feature,
+    /// so that the layers of the composite tiles can be told apart
+    pub source_attribute: Option<bool>,
+
+    /// Corner of the tile grid with the first row of tiles, published as the `scheme` of the `TileJSON`
+    pub tile_origin: Option<TileOrigin>,
+
+    /// Also serve the tiles as a `GeoJSON` `FeatureCollection` clipped to the tile,
+    /// to the clients that request `application/geo+json` or `application/json`
+    pub geojson: Option<bool>,
+
     /// List of columns, that should be encoded as tile properties
     pub properties: Option<BTreeMap<String, String>>,
 
+    /// Truncate the values of these text properties to the given number of characters
+    pub max_property_length: Option<BTreeMap<String, u32>>,
+
     /// Mapping of properties to the actual table columns
     #[serde(skip)]
     pub prop_mapping: HashMap<String, String>,
@@ -78,6 +138,28 @@ pub struct TableInfo {
     pub tilejson: Option<serde_json::Value>,
 }
 
+/// Corner of the tile grid where the `y` tile coordinate starts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileOrigin {
+    /// The standard XYZ grid, with `y` increasing southwards
+    #[default]
+    TopLeft,
+    /// The TMS grid, with `y` increasing northwards
+    BottomLeft,
+}
+
+impl TileOrigin {
+    /// Name of the tile scheme in the `TileJSON`
+    #[must_use]
+    pub fn scheme(self) -> &'static str {
+        match self {
+            Self::TopLeft => "xyz",
+            Self::BottomLeft => "tms",
+        }
+    }
+}
+
 impl PgInfo for TableInfo {
     fn format_id(&self) -> String {
         format!("{}.{}.{}", self.schema, self.table, self.geometry_column)
@@ -92,15 +174,29 @@ impl PgInfo for TableInfo {
         tilejson.minzoom = self.minzoom;
         tilejson.maxzoom = self.maxzoom;
         tilejson.bounds = self.bounds;
+        tilejson.scheme = self.tile_origin.map(|v| v.scheme().to_string());
+        let mut other = BTreeMap::default();
+        // The generic GEOMETRY type tells the clients nothing about the features
+        if let Some(geometry_type) = self.geometry_type.as_ref().filter(|v| *v != "GEOMETRY") {
+            other.insert("geometry_type".to_string(), geometry_type.clone().into());
+        }
+        let mut fields = self.properties.clone().unwrap_or_default();
+        if self.use_source_attribute() {
+            fields.insert(SOURCE_ATTRIBUTE.to_string(), "text".to_string());
+        }
         let layer = VectorLayer {
-            id: source_id,
-            fields: self.properties.clone().unwrap_or_default(),
+            id: self.layer_id.clone().unwrap_or(source_id),
+            fields,
             description: None,
             maxzoom: None,
             minzoom: None,
-            other: BTreeMap::default(),
+            other,
         };
         tilejson.vector_layers = Some(vec![layer]);
+        if let Some(extent) = self.extent {
+            // Not part of the TileJSON spec, but clients need it to decode the tiles
+            tilejson.other.insert("extent".to_string(), extent.into());
+        }
         patch_json(tilejson, self.tilejson.as_ref())
     }
 }
@@ -133,17 +229,19 @@ impl TableInfo {
         match (&self.geometry_type, &cfg_inf.geometry_type) {
             (Some(src), Some(cfg)) if src != cfg => {
                 warn!(
-                    r#"Table {} has geometry type={src}, but source {new_id} has {cfg}"#,
+                    r#"Table {} has geometry type={src}, but source {new_id} has {cfg}, which will be used instead"#,
                     self.format_id()
                 );
             }
+            // Keep the detected type unless the configuration overrides it
+            (src, None) => inf.geometry_type.clone_from(src),
             _ => {}
         }
 
         let empty = BTreeMap::new();
         let props = self.properties.as_ref().unwrap_or(&empty);
 
-        if let Some(id_column) = &cfg_inf.id_column {
+        for id_column in cfg_inf.id_column.iter() {
             let prop = normalize_key(props, id_column.as_str(), "id_column", new_id)?;
             inf.prop_mapping.insert(id_column.clone(), prop);
         }
@@ -158,6 +256,17 @@ impl TableInfo {
         Some(inf)
     }
 
+    /// Check if the features get the `source` attribute, which is only added
+    /// if it is enabled and the table has no property with the same name
+    #[must_use]
+    pub fn use_source_attribute(&self) -> bool {
+        self.source_attribute.unwrap_or_default()
+            && !self
+                .properties
+                .as_ref()
+                .is_some_and(|p| p.contains_key(SOURCE_ATTRIBUTE))
+    }
+
     /// Determine the SRID value to use for a table, or None if unknown, assuming self is a table info from the database
     #[must_use]
     pub fn calc_srid(&self, new_id: &str, cfg_srid: i32, default_srid: Option<i32>) -> Option<i32> {
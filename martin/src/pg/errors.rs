@@ -22,6 +22,9 @@ pub enum PgError {
     #[error("Cannot parse certificate file {}: {0}", .1.display())]
     CannotParseCert(#[source] io::Error, PathBuf),
 
+    #[error("Cannot read password file {}: {0}", .1.display())]
+    CannotReadPasswordFile(#[source] io::Error, PathBuf),
+
     #[error("Unable to parse PEM RSA key file {}", .0.display())]
     InvalidPrivateKey(PathBuf),
 
@@ -43,6 +46,15 @@ pub enum PgError {
     #[error("Unable to get a Postgres connection from the pool {1}: {0}")]
     PostgresPoolConnError(#[source] PoolError, String),
 
+    #[error("Timed out after {1}ms waiting for a Postgres connection from the pool {0}")]
+    PoolTimeout(String, u64),
+
+    #[error("Postgres pool {0} has min_idle={1}, which is larger than its pool_size={2}")]
+    InvalidMinIdle(String, usize, usize),
+
+    #[error("Postgres pools {0} have min_idle={1} in total, but their server only allows {2} non-superuser connections")]
+    TooManyIdleConnections(String, usize, i64),
+
     #[error("Unable to parse connection string {1}: {0}")]
     BadConnectionString(#[source] TokioPgError, String),
 
@@ -52,12 +64,30 @@ pub enum PgError {
     #[error("PostGIS version {0} is too old, minimum required is {1}")]
     PostgisTooOld(Version, Version),
 
+    #[error("Some tables have no spatial index. Create the missing indices, or disable fail_on_missing_index: {}", .0.join(", "))]
+    MissingSpatialIndex(Vec<String>),
+
     #[error("Invalid extent setting in source {0} for table {1}: extent=0")]
     InvalidTableExtent(String, String),
 
+    #[error("Source {0} was not published because table {1} has {2} columns, more than max_properties={3}. Configure the table explicitly with the list of its properties, or raise max_properties")]
+    TooManyProperties(String, String, usize, usize),
+
+    #[error("Source {0} has a refresh_interval, but {1} is not a materialized view")]
+    NotMaterializedView(String, String),
+
     #[error("Error preparing a query for the tile '{1}' ({2}): {3} {0}")]
     PrepareQueryError(#[source] TokioPgError, String, String, String),
 
+    #[error("Source {0} timed out after {1}ms (query_timeout_ms) while getting tile {2:#}")]
+    QueryTimeout(String, u64, TileCoord),
+
+    #[error("Invalid value of the query parameter {1} of source {0}: {2}")]
+    InvalidQueryParam(String, String, String),
+
+    #[error("Source {0} requires the query parameter {1}, e.g. ?{1}=...")]
+    MissingQueryParam(String, String),
+
     #[error(r#"Unable to get tile {2:#} from {1}: {0}"#)]
     GetTileError(#[source] TokioPgError, String, TileCoord),
 
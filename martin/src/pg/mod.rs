@@ -7,12 +7,17 @@ mod pg_source;
 mod pool;
 mod query_functions;
 mod query_tables;
+mod refresh;
 mod tls;
 mod utils;
 
-pub use config::{PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts};
-pub use config_function::FunctionInfo;
+pub use config::{
+    ClientMinMessages, PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts,
+};
+pub use config_function::{FunctionInfo, ParamRange};
 pub use config_table::TableInfo;
 pub use errors::{PgError, PgResult};
-pub use pool::{PgPool, POOL_SIZE_DEFAULT};
+pub use pg_source::{PgSource, PgSqlInfo};
+pub use pool::{PgPool, PgPoolStatus, POOL_SIZE_DEFAULT};
 pub use query_functions::query_available_function;
+pub use refresh::{schedule_refreshes, ViewRefresh};
@@ -1,16 +1,41 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::time::Duration;
+
 use async_trait::async_trait;
+use deadpool_postgres::tokio_postgres::error::SqlState;
 use deadpool_postgres::tokio_postgres::types::{ToSql, Type};
-use log::debug;
+use deadpool_postgres::{GenericClient, Object};
+use itertools::Itertools as _;
+use log::{debug, warn};
 use martin_tile_utils::Encoding::Uncompressed;
-use martin_tile_utils::Format::Mvt;
-use martin_tile_utils::TileInfo;
+use martin_tile_utils::Format::{Json, Mvt};
+use martin_tile_utils::{Format, TileInfo};
+use postgres_protocol::escape::{escape_identifier, escape_literal};
 use tilejson::TileJSON;
 
+use crate::pg::config::ClientMinMessages;
+use crate::pg::config_function::ParamRange;
 use crate::pg::pool::PgPool;
 use crate::pg::utils::query_to_json;
-use crate::pg::PgError::{GetTileError, GetTileWithQueryError, PrepareQueryError};
+use crate::pg::PgError::{
+    GetTileError, GetTileWithQueryError, InvalidQueryParam, MissingQueryParam, PostgresError,
+    PrepareQueryError, QueryTimeout,
+};
+use crate::pg::PgResult;
 use crate::source::{Source, TileData, UrlQuery};
-use crate::{MartinResult, TileCoord};
+use crate::utils::{request_header, statement_timeout_ms};
+use crate::{MartinError, MartinResult, TileCoord};
+
+/// Delay before the first retry of a failed tile query, doubled after each retry
+const RETRY_DELAY_MS_DEFAULT: u64 = 50;
+
+/// Errors that are likely to go away if the same query is simply retried
+const RETRYABLE_ERRORS: &[SqlState] = &[
+    SqlState::T_R_SERIALIZATION_FAILURE,
+    SqlState::T_R_DEADLOCK_DETECTED,
+    SqlState::LOCK_NOT_AVAILABLE,
+];
 
 #[derive(Clone, Debug)]
 pub struct PgSource {
@@ -30,43 +55,132 @@ impl PgSource {
             tilejson,
         }
     }
-}
 
-#[async_trait]
-impl Source for PgSource {
-    fn get_id(&self) -> &str {
-        &self.id
-    }
+    /// Get a tile with the given SQL query, checking the URL query parameters and retrying the transient errors
+    async fn get_tile_with_query(
+        &self,
+        sql: &str,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let mut query = None;
+        if !self.info.default_params.is_empty() {
+            query = Some(with_default_params(&self.info.default_params, url_query));
+        }
+        if !self.info.required_params.is_empty() {
+            check_required_params(
+                &self.id,
+                &self.info.required_params,
+                query.as_ref().or(url_query),
+            )?;
+        }
+        if !self.info.param_ranges.is_empty() {
+            if let Some(url_query) = query.as_ref().or(url_query) {
+                query = Some(check_param_ranges(
+                    &self.id,
+                    &self.info.param_ranges,
+                    url_query,
+                )?);
+            }
+        }
+        let url_query = query.as_ref().or(url_query);
 
-    fn get_tilejson(&self) -> &TileJSON {
-        &self.tilejson
+        let mut attempt = 0;
+        loop {
+            // The error is not Send, so it must be dropped before the sleep
+            let delay = match self.query_tile_with_timeout(sql, xyz, url_query).await {
+                Err(e) if attempt < self.info.retries && is_retryable(&e) => {
+                    let delay = self
+                        .info
+                        .retry_delay_ms
+                        .saturating_mul(2_u64.saturating_pow(attempt));
+                    warn!("Retrying in {delay}ms (attempt {}): {e}", attempt + 1);
+                    delay
+                }
+                res => return res,
+            };
+            attempt += 1;
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
     }
 
-    fn get_tile_info(&self) -> TileInfo {
-        TileInfo::new(Mvt, Uncompressed)
+    async fn query_tile_with_timeout(
+        &self,
+        sql: &str,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let Some(timeout_ms) = self.info.query_timeout_ms else {
+            return self.query_tile(sql, xyz, url_query).await;
+        };
+        tokio::time::timeout(
+            Duration::from_millis(timeout_ms),
+            self.query_tile(sql, xyz, url_query),
+        )
+        .await
+        .map_err(|_| QueryTimeout(self.id.clone(), timeout_ms, xyz))?
     }
 
-    fn clone_source(&self) -> Box<dyn Source> {
-        Box::new(self.clone())
+    async fn query_tile(
+        &self,
+        sql: &str,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        // If the request is dropped before the tile is ready, the query is cancelled on the server
+        let mut conn = self.pool.get_cancellable().await?;
+        let tile = self.query_tile_on(&mut conn, sql, xyz, url_query).await;
+        conn.release();
+        tile
     }
 
-    fn support_url_query(&self) -> bool {
-        self.info.use_url_query
+    async fn query_tile_on(
+        &self,
+        conn: &mut Object,
+        sql: &str,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let header_values = session_header_values(&self.info.session_headers);
+        let Some(preamble) = session_preamble(
+            &self.info.session_settings,
+            &header_values,
+            self.info.client_min_messages,
+            statement_timeout_ms(),
+            url_query,
+        ) else {
+            return self.query_tile_with(&*conn, sql, xyz, url_query).await;
+        };
+
+        // SET LOCAL only lasts until the end of the transaction, so it never leaks to other requests
+        let tx = conn
+            .transaction()
+            .await
+            .map_err(|e| PostgresError(e, "starting a transaction"))?;
+        tx.batch_execute(&preamble)
+            .await
+            .map_err(|e| PostgresError(e, "setting session settings"))?;
+        let tile = self.query_tile_with(&tx, sql, xyz, url_query).await?;
+        tx.commit()
+            .await
+            .map_err(|e| PostgresError(e, "committing a transaction"))?;
+        Ok(tile)
     }
 
-    async fn get_tile(
+    async fn query_tile_with(
         &self,
+        conn: &impl GenericClient,
+        sql: &str,
         xyz: TileCoord,
         url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData> {
-        let conn = self.pool.get().await?;
-        let param_types: &[Type] = if self.support_url_query() {
+        let use_url_query = self.info.use_url_query;
+        let param_types: &[Type] = if use_url_query {
             &[Type::INT2, Type::INT8, Type::INT8, Type::JSON]
         } else {
             &[Type::INT2, Type::INT8, Type::INT8]
         };
 
-        let sql = &self.info.sql_query;
         let prep_query = conn
             .prepare_typed_cached(sql, param_types)
             .await
@@ -75,11 +189,11 @@ impl Source for PgSource {
                     e,
                     self.id.to_string(),
                     self.info.signature.to_string(),
-                    self.info.sql_query.to_string(),
+                    sql.to_string(),
                 )
             })?;
 
-        let tile = if self.support_url_query() {
+        let tile = if use_url_query {
             let json = query_to_json(url_query);
             debug!("SQL: {sql} [{xyz}, {json:?}]");
             let params: &[&(dyn ToSql + Sync)] = &[
@@ -101,7 +215,7 @@ impl Source for PgSource {
         let tile = tile
             .map(|row| row.and_then(|r| r.get::<_, Option<TileData>>(0)))
             .map_err(|e| {
-                if self.support_url_query() {
+                if use_url_query {
                     GetTileWithQueryError(e, self.id.to_string(), xyz, url_query.cloned())
                 } else {
                     GetTileError(e, self.id.to_string(), xyz)
@@ -113,11 +227,195 @@ impl Source for PgSource {
     }
 }
 
+#[async_trait]
+impl Source for PgSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        TileInfo::new(Mvt, Uncompressed)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        // The session settings are taken from the URL query
+        self.info.use_url_query || !self.info.session_settings.is_empty()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        self.get_tile_with_query(&self.info.sql_query, xyz, url_query)
+            .await
+    }
+
+    fn get_alt_formats(&self) -> Vec<Format> {
+        if self.info.geojson_query.is_some() {
+            vec![Json]
+        } else {
+            Vec::new()
+        }
+    }
+
+    async fn get_alt_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        format: Format,
+    ) -> MartinResult<TileData> {
+        match &self.info.geojson_query {
+            Some(sql) if format == Json => self.get_tile_with_query(sql, xyz, url_query).await,
+            _ => Err(MartinError::UnsupportedTileFormat(self.id.clone(), format)),
+        }
+    }
+}
+
+/// Check if the error was caused by a transient condition like a lock timeout,
+/// based on the SQLSTATE code reported by Postgres
+fn is_retryable(err: &MartinError) -> bool {
+    let MartinError::PostgresError(
+        PrepareQueryError(e, ..) | GetTileError(e, ..) | GetTileWithQueryError(e, ..),
+    ) = err
+    else {
+        return false;
+    };
+    e.code().is_some_and(is_retryable_code)
+}
+
+fn is_retryable_code(code: &SqlState) -> bool {
+    RETRYABLE_ERRORS.contains(code)
+}
+
+/// Add the default values of the URL query parameters that were not given by the client
+fn with_default_params(defaults: &UrlQuery, url_query: Option<&UrlQuery>) -> UrlQuery {
+    let mut query = defaults.clone();
+    if let Some(url_query) = url_query {
+        query.extend(url_query.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    query
+}
+
+/// Make sure all required URL query parameters were given, either by the client or by their defaults
+fn check_required_params(
+    id: &str,
+    required: &BTreeSet<String>,
+    url_query: Option<&UrlQuery>,
+) -> PgResult<()> {
+    match required
+        .iter()
+        .find(|param| url_query.map_or(true, |q| !q.contains_key(*param)))
+    {
+        Some(param) => Err(MissingQueryParam(id.to_string(), param.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Check the numeric URL query parameters against their allowed ranges, and clamp the out-of-range values if allowed.
+/// Parameters that were not given are not checked.
+fn check_param_ranges(
+    id: &str,
+    ranges: &BTreeMap<String, ParamRange>,
+    url_query: &UrlQuery,
+) -> PgResult<UrlQuery> {
+    let mut query = url_query.clone();
+    for (param, range) in ranges {
+        let Some(value) = query.get_mut(param) else {
+            continue;
+        };
+        let err = |reason: String| InvalidQueryParam(id.to_string(), param.clone(), reason);
+        let num: f64 = value
+            .parse()
+            .ok()
+            .filter(|v: &f64| v.is_finite())
+            .ok_or_else(|| err(format!("'{value}' is not a number")))?;
+        let min = range.min.unwrap_or(f64::NEG_INFINITY);
+        let max = range.max.unwrap_or(f64::INFINITY);
+        if num < min || num > max {
+            if !range.clamp.unwrap_or_default() {
+                let fmt = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+                let (min, max) = (fmt(range.min), fmt(range.max));
+                return Err(err(format!(
+                    "{num} is outside of the allowed range {min}..={max}"
+                )));
+            }
+            *value = num.max(min).min(max).to_string();
+        }
+    }
+    Ok(query)
+}
+
+/// Get the values of the header settings from the headers of the current request.
+/// The headers are only available while serving a request, e.g. not in `martin-cp`.
+fn session_header_values(headers: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|(name, header)| Some((name.clone(), request_header(header)?)))
+        .collect()
+}
+
+/// Generate the `SET LOCAL` statements for the session settings, using the values of their URL query parameters,
+/// and the values of the header settings, which are already taken from the request headers.
+/// Both the setting names and the values are escaped. Settings whose parameter is missing are not changed.
+fn session_preamble(
+    settings: &BTreeMap<String, String>,
+    header_values: &BTreeMap<String, String>,
+    client_min_messages: Option<ClientMinMessages>,
+    statement_timeout: Option<u64>,
+    url_query: Option<&UrlQuery>,
+) -> Option<String> {
+    let mut preamble = client_min_messages
+        .map(|level| format!("SET LOCAL client_min_messages = {level};\n"))
+        .unwrap_or_default();
+    if let Some(timeout) = statement_timeout {
+        writeln!(preamble, "SET LOCAL statement_timeout = {timeout};").unwrap();
+    }
+    let url_values = url_query.into_iter().flat_map(|url_query| {
+        settings
+            .iter()
+            .filter_map(move |(name, param)| Some((name, url_query.get(param)?)))
+    });
+    for (name, value) in url_values.chain(header_values) {
+        let name = name.split('.').map(escape_identifier).join(".");
+        writeln!(preamble, "SET LOCAL {name} = {};", escape_literal(value)).unwrap();
+    }
+    (!preamble.is_empty()).then_some(preamble)
+}
+
 #[derive(Clone, Debug)]
 pub struct PgSqlInfo {
     pub sql_query: String,
     pub use_url_query: bool,
     pub signature: String,
+    /// Maximum time to get a single tile, if any
+    pub query_timeout_ms: Option<u64>,
+    /// Number of times to retry the query after a transient error
+    pub retries: u32,
+    /// Delay before the first retry, in milliseconds
+    pub retry_delay_ms: u64,
+    /// Postgres settings to `SET LOCAL` before the query, mapped to their URL query parameters
+    pub session_settings: BTreeMap<String, String>,
+    /// Postgres settings to `SET LOCAL` before the query, mapped to their request headers
+    pub session_headers: BTreeMap<String, String>,
+    /// Default values of the URL query parameters, overridden by the ones given by the client
+    pub default_params: UrlQuery,
+    /// Lowest severity of the Postgres messages to log, if different from the server's default
+    pub client_min_messages: Option<ClientMinMessages>,
+    /// URL query parameters that must be present after applying the defaults
+    pub required_params: BTreeSet<String>,
+    /// Allowed ranges of the numeric URL query parameters
+    pub param_ranges: BTreeMap<String, ParamRange>,
+    /// Query to get a tile as a `GeoJSON` feature collection, if the source also serves `GeoJSON`
+    pub geojson_query: Option<String>,
 }
 
 impl PgSqlInfo {
@@ -127,6 +425,201 @@ impl PgSqlInfo {
             sql_query: query,
             use_url_query: has_query_params,
             signature,
+            query_timeout_ms: None,
+            retries: 0,
+            retry_delay_ms: RETRY_DELAY_MS_DEFAULT,
+            session_settings: BTreeMap::new(),
+            session_headers: BTreeMap::new(),
+            default_params: UrlQuery::new(),
+            client_min_messages: None,
+            required_params: BTreeSet::new(),
+            param_ranges: BTreeMap::new(),
+            geojson_query: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::*;
+    use crate::utils::{with_deadline, with_request_headers, MAX_DEADLINE_MS};
+
+    #[test]
+    fn test_retryable_codes() {
+        // The codes as they are reported by the server
+        let retryable = |code: &str| is_retryable_code(&SqlState::from_code(code));
+        assert!(retryable("40001"), "serialization_failure");
+        assert!(retryable("40P01"), "deadlock_detected");
+        assert!(retryable("55P03"), "lock_not_available");
+        assert!(!retryable("42601"), "syntax_error");
+        assert!(!retryable("42883"), "undefined_function");
+        assert!(!retryable("57014"), "query_canceled");
+        assert!(!retryable("P0001"), "raise_exception");
+
+        assert_eq!(
+            SqlState::from_code("40001"),
+            SqlState::T_R_SERIALIZATION_FAILURE
+        );
+    }
+
+    #[test]
+    fn test_default_params() {
+        let defaults = UrlQuery::from([
+            ("year".to_string(), "2020".to_string()),
+            ("lang".to_string(), "en".to_string()),
+        ]);
+        let query = UrlQuery::from([("year".to_string(), "2024".to_string())]);
+
+        assert_eq!(with_default_params(&defaults, None), defaults);
+        assert_eq!(
+            with_default_params(&defaults, Some(&query)),
+            UrlQuery::from([
+                ("year".to_string(), "2024".to_string()),
+                ("lang".to_string(), "en".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_required_params() {
+        let required = BTreeSet::from(["year".to_string()]);
+        let query = UrlQuery::from([("year".to_string(), "2024".to_string())]);
+        assert!(check_required_params("src", &required, Some(&query)).is_ok());
+        assert!(check_required_params("src", &BTreeSet::new(), None).is_ok());
+
+        let err = check_required_params("src", &required, Some(&UrlQuery::new())).unwrap_err();
+        assert!(matches!(&err, MissingQueryParam(id, p) if id == "src" && p == "year"));
+        assert!(check_required_params("src", &required, None).is_err());
+
+        // A default value satisfies the requirement
+        let defaults = with_default_params(&query, None);
+        assert!(check_required_params("src", &required, Some(&defaults)).is_ok());
+    }
+
+    #[test]
+    fn test_session_preamble() {
+        let settings = BTreeMap::from([("app.tenant_id".to_string(), "tenant".to_string())]);
+        let query = |v: &str| UrlQuery::from([("tenant".to_string(), v.to_string())]);
+        let none = BTreeMap::new();
+
+        assert_eq!(
+            session_preamble(&settings, &none, None, None, Some(&query("42"))).unwrap(),
+            "SET LOCAL \"app\".\"tenant_id\" = '42';\n"
+        );
+        assert_eq!(
+            session_preamble(
+                &settings,
+                &none,
+                None,
+                None,
+                Some(&query("1'; DROP TABLE t; --"))
+            )
+            .unwrap(),
+            "SET LOCAL \"app\".\"tenant_id\" = '1''; DROP TABLE t; --';\n"
+        );
+        assert_eq!(
+            session_preamble(&settings, &none, None, None, Some(&UrlQuery::new())),
+            None
+        );
+        assert_eq!(session_preamble(&settings, &none, None, None, None), None);
+        assert_eq!(
+            session_preamble(&BTreeMap::new(), &none, None, None, Some(&query("42"))),
+            None
+        );
+
+        let level = Some(ClientMinMessages::Warning);
+        assert_eq!(
+            session_preamble(&BTreeMap::new(), &none, level, None, None).unwrap(),
+            "SET LOCAL client_min_messages = warning;\n"
+        );
+        assert_eq!(
+            session_preamble(&settings, &none, level, None, Some(&query("42"))).unwrap(),
+            "SET LOCAL client_min_messages = warning;\nSET LOCAL \"app\".\"tenant_id\" = '42';\n"
+        );
+        assert_eq!(
+            session_preamble(&BTreeMap::new(), &none, None, Some(50), None).unwrap(),
+            "SET LOCAL statement_timeout = 50;\n"
+        );
+
+        let headers = BTreeMap::from([("app.tenant_id".to_string(), "7'".to_string())]);
+        assert_eq!(
+            session_preamble(&BTreeMap::new(), &headers, None, None, None).unwrap(),
+            "SET LOCAL \"app\".\"tenant_id\" = '7''';\n"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_session_header_values() {
+        let settings = BTreeMap::from([
+            ("app.tenant_id".to_string(), "x-tenant-id".to_string()),
+            ("app.user_id".to_string(), "x-user-id".to_string()),
+        ]);
+        assert_eq!(session_header_values(&settings), BTreeMap::new());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-tenant-id"),
+            HeaderValue::from_static("42"),
+        );
+        let values = with_request_headers(headers, async { session_header_values(&settings) });
+        assert_eq!(
+            values.await,
+            BTreeMap::from([("app.tenant_id".to_string(), "42".to_string())])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_statement_timeout() {
+        assert_eq!(statement_timeout_ms(), None);
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let timeout = with_deadline(Some(deadline), async { statement_timeout_ms() }).await;
+        assert!(timeout.is_some_and(|v| v > 0 && v <= 100), "{timeout:?}");
+
+        // An expired deadline must not disable the timeout with zero
+        let deadline = Instant::now();
+        let timeout = with_deadline(Some(deadline), async { statement_timeout_ms() }).await;
+        assert_eq!(timeout, Some(1));
+
+        // A distant deadline must not exceed the largest `statement_timeout` of Postgres
+        let deadline = Instant::now() + Duration::from_secs(30 * 24 * 3600);
+        let timeout = with_deadline(Some(deadline), async { statement_timeout_ms() }).await;
+        assert_eq!(timeout, Some(MAX_DEADLINE_MS));
+    }
+
+    #[test]
+    fn test_param_ranges() {
+        let ranges = BTreeMap::from([(
+            "level".to_string(),
+            ParamRange {
+                min: Some(0.0),
+                max: Some(10.0),
+                clamp: None,
+            },
+        )]);
+        let query = |v: &str| UrlQuery::from([("level".to_string(), v.to_string())]);
+
+        for value in ["0", "10", "2.5"] {
+            let res = check_param_ranges("src", &ranges, &query(value)).unwrap();
+            assert_eq!(res, query(value));
+        }
+        for value in ["11", "-1", "abc", "NaN"] {
+            let res = check_param_ranges("src", &ranges, &query(value));
+            assert!(matches!(res, Err(InvalidQueryParam(..))), "{value}");
+        }
+        let res = check_param_ranges("src", &ranges, &UrlQuery::new()).unwrap();
+        assert!(res.is_empty());
+
+        let mut ranges = ranges;
+        ranges.get_mut("level").unwrap().clamp = Some(true);
+        let res = check_param_ranges("src", &ranges, &query("11")).unwrap();
+        assert_eq!(res, query("10"));
+        let res = check_param_ranges("src", &ranges, &query("-0.5")).unwrap();
+        assert_eq!(res, query("0"));
+        assert!(check_param_ranges("src", &ranges, &query("abc")).is_err());
+    }
+}
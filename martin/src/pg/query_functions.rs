@@ -9,6 +9,7 @@ use crate::pg::builder::SqlFuncInfoMapMap;
 use crate::pg::config_function::FunctionInfo;
 use crate::pg::pg_source::PgSqlInfo;
 use crate::pg::pool::PgPool;
+use crate::pg::utils::comment_to_tilejson;
 use crate::pg::PgError::PostgresError;
 use crate::pg::PgResult;
 
@@ -34,13 +35,7 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
             let input_types = jsonb_to_vec(row.get("input_types")).expect("Can't get input types");
             let input_names = jsonb_to_vec(row.get("input_names")).expect("Can't get input names");
             let tilejson = if let Some(text) = row.get("description") {
-                match serde_json::from_str::<Value>(text) {
-                    Ok(v) => Some(v),
-                    Err(e) => {
-                        warn!("Unable to deserialize SQL comment on {schema}.{function} as tilejson, a default description will be used: {e}");
-                        None
-                    }
-                }
+                Some(comment_to_tilejson(text, &format!("{schema}.{function}")))
             } else {
                 debug!("Unable to find a SQL comment on {schema}.{function}, a default function description will be used");
                 None
@@ -4,23 +4,33 @@ use futures::pin_mut;
 use log::{debug, warn};
 use postgis::ewkb;
 use postgres_protocol::escape::{escape_identifier, escape_literal};
-use serde_json::Value;
 use tilejson::Bounds;
 use tokio::time::timeout;
 
 use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::pg::builder::SqlTableInfoMapMapMap;
 use crate::pg::config::PgInfo;
-use crate::pg::config_table::TableInfo;
+use crate::pg::config_table::{TableInfo, TileOrigin, SOURCE_ATTRIBUTE};
 use crate::pg::pg_source::PgSqlInfo;
 use crate::pg::pool::PgPool;
-use crate::pg::utils::{json_to_hashmap, polygon_to_bbox};
+use crate::pg::utils::{comment_to_tilejson, json_to_hashmap, polygon_to_bbox};
 use crate::pg::PgError::PostgresError;
 use crate::pg::PgResult;
 
 static DEFAULT_EXTENT: u32 = 4096;
 static DEFAULT_BUFFER: u32 = 64;
 static DEFAULT_CLIP_GEOM: bool = true;
+/// Size of a tile in pixels, used to convert `buffer_px` to the tile coordinate space
+static TILE_SIZE_PX: u32 = 256;
+static DEFAULT_CLUSTER_RADIUS: u32 = 256;
+/// Column types that can be truncated with `max_property_length`
+static TEXT_TYPES: &[&str] = &["text", "varchar", "bpchar", "citext"];
+/// Name of the feature id hashed from the columns of a composite key, see [`TableInfo::id_column`]
+static COMPOSITE_ID_COLUMN: &str = "__martin_feature_id";
+/// Number of decimal digits of the `GeoJSON` coordinates, about 10 cm at the equator
+static GEOJSON_MAX_DECIMAL_DIGITS: u32 = 6;
+/// Length of the equator in the EPSG:3857 projection, in meters
+static EARTH_CIRCUMFERENCE: f64 = 40_075_016.685_578_5;
 
 /// Examine a database to get a list of all tables that have geometry columns.
 pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMapMap> {
@@ -35,13 +45,7 @@ pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMa
         let schema: String = row.get("schema");
         let table: String = row.get("name");
         let tilejson = if let Some(text) = row.get("description") {
-            match serde_json::from_str::<Value>(text) {
-                Ok(v) => Some(v),
-                Err(e) => {
-                    warn!("Unable to deserialize SQL comment on {schema}.{table} as tilejson, the automatically generated tilejson would be used: {e}");
-                    None
-                }
-            }
+            Some(comment_to_tilejson(text, &format!("{schema}.{table}")))
         } else {
             debug!("Unable to find a  SQL comment on {schema}.{table}, the tilejson would be generated automatically");
             None
@@ -98,6 +102,32 @@ fn escape_with_alias(mapping: &HashMap<String, String>, field: &str) -> String {
     }
 }
 
+/// Hash the columns of a composite key into a single non-negative 63-bit integer feature id, taken from the MD5 hash.
+/// A 32-bit hash like `hashtext` would make the collisions likely with as few as tens of thousands of features.
+/// The JSON array keeps the values apart even if they are NULL or contain any separator, e.g.
+/// `('x' || left(md5(jsonb_build_array("region", "code")::text), 16))::bit(64)::bigint & 9223372036854775807`
+fn escape_composite_id(mapping: &HashMap<String, String>, fields: &[String]) -> String {
+    let columns = fields
+        .iter()
+        .map(|field| escape_identifier(mapping.get(field).map_or(field, |v| v.as_str())))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        ", ('x' || left(md5(jsonb_build_array({columns})::text), 16))::bit(64)::bigint & 9223372036854775807 AS {}",
+        escape_identifier(COMPOSITE_ID_COLUMN)
+    )
+}
+
+/// Select the first `max_length` characters of a text column, keeping the property name
+fn escape_truncated(mapping: &HashMap<String, String>, field: &str, max_length: u32) -> String {
+    let column = mapping.get(field).map_or(field, |v| v.as_str());
+    format!(
+        ", LEFT({}, {max_length}) AS {}",
+        escape_identifier(column),
+        escape_identifier(field),
+    )
+}
+
 /// Generate a query to fetch tiles from a table.
 /// The function is async because it may need to query the database for the table bounds (could be very slow).
 pub async fn table_to_query(
@@ -105,6 +135,7 @@ pub async fn table_to_query(
     mut info: TableInfo,
     pool: PgPool,
     bounds_type: BoundsCalcType,
+    default_bounds: Option<Bounds>,
     max_feature_count: Option<usize>,
 ) -> PgResult<(String, PgSqlInfo, TableInfo)> {
     let schema = escape_identifier(&info.schema);
@@ -143,47 +174,176 @@ pub async fn table_to_query(
                 "The computed bounds for {id} from {} are {bounds}",
                 info.format_id()
             );
+        } else if let Some(bounds) = info.default_bounds.or(default_bounds) {
+            debug!(
+                "Unable to compute bounds for {id} from {}, using the default bounds {bounds}",
+                info.format_id()
+            );
+            info.bounds = Some(bounds);
         }
     }
 
-    let properties = if let Some(props) = &info.properties {
-        props
-            .keys()
-            .map(|column| escape_with_alias(&info.prop_mapping, column))
-            .collect::<String>()
-    } else {
-        String::new()
+    let query = build_query(&id, &info, pool.supports_tile_margin(), max_feature_count);
+    let mut sql_info = PgSqlInfo::new(query, false, info.format_id());
+    if info.geojson.unwrap_or_default() {
+        sql_info.geojson_query = Some(build_geojson_query(&id, &info, max_feature_count));
+    }
+
+    Ok((id, sql_info, info))
+}
+
+/// Get the property columns of the features, truncating the long text values if configured
+fn get_properties(id: &str, info: &TableInfo) -> String {
+    let Some(props) = &info.properties else {
+        return String::new();
     };
+    let max_lengths = get_max_property_lengths(id, info);
+    props
+        .keys()
+        .map(|column| match max_lengths.get(column.as_str()) {
+            Some(max_length) => escape_truncated(&info.prop_mapping, column, *max_length),
+            None => escape_with_alias(&info.prop_mapping, column),
+        })
+        .collect()
+}
 
-    let (id_name, id_field) = if let Some(id_column) = &info.id_column {
-        (
+/// Get the name of the feature id for `ST_AsMVT`, and the column (or hashed columns) to select as the id
+fn get_id_field(info: &TableInfo) -> (String, String) {
+    match info.id_column.as_slice() {
+        [] => (String::new(), String::new()),
+        [id_column] => (
             format!(", {}", escape_literal(id_column)),
             escape_with_alias(&info.prop_mapping, id_column),
+        ),
+        id_columns => (
+            format!(", {}", escape_literal(COMPOSITE_ID_COLUMN)),
+            escape_composite_id(&info.prop_mapping, id_columns),
+        ),
+    }
+}
+
+/// Huge features like country polygons are dropped if their bounding box covers too many tiles.
+/// The size of a tile in `EPSG:3857` units is `EARTH_CIRCUMFERENCE / 2^zoom`.
+fn get_area_filter(info: &TableInfo, geometry_column: &str) -> String {
+    info.max_feature_area.map_or(String::new(), |v| {
+        format!(
+            "\n    AND ST_Area(ST_Transform(ST_Envelope({geometry_column}), 3857)) < {v} * ({EARTH_CIRCUMFERENCE} / 2 ^ $1::integer) ^ 2"
         )
-    } else {
-        (String::new(), String::new())
+    })
+}
+
+/// Generate the SQL query to fetch a tile from a table as a `GeoJSON` `FeatureCollection`,
+/// with the geometries clipped to the tile and transformed to WGS84. The points are never clustered.
+fn build_geojson_query(id: &str, info: &TableInfo, max_feature_count: Option<usize>) -> String {
+    let schema = escape_identifier(&info.schema);
+    let table = escape_identifier(&info.table);
+    let geometry_column = escape_identifier(&info.geometry_column);
+    let srid = info.srid;
+    let properties = get_properties(id, info);
+    let (_, id_field) = get_id_field(info);
+    let tile_xyz = get_tile_xyz(info);
+    let area_filter = get_area_filter(info, &geometry_column);
+    let source_attr = get_source_attribute(id, info);
+    let limit_clause = max_feature_count.map_or(String::new(), |v| format!("LIMIT {v}"));
+    // The id column becomes the feature id rather than one of its properties, like in the MVT tiles
+    let (feature_id, id_property) = match info.id_column.as_slice() {
+        [] => (String::new(), String::new()),
+        [id_column] => get_geojson_id(id_column),
+        _ => get_geojson_id(COMPOSITE_ID_COLUMN),
     };
 
+    // An empty tile has no rows, rather than a collection without features
+    format!(
+        r#"
+SELECT
+  convert_to(json_build_object('type', 'FeatureCollection', 'features', features)::text, 'UTF8')
+FROM (
+  SELECT
+    json_agg(json_build_object(
+      'type', 'Feature',{feature_id}
+      'geometry', ST_AsGeoJSON(tile.geom, {GEOJSON_MAX_DECIMAL_DIGITS})::json,
+      'properties', to_jsonb(tile.*) - 'geom'{id_property}
+    )) AS features
+  FROM (
+    SELECT
+      ST_Transform(
+          ST_ClipByBox2D(
+              ST_Transform(ST_CurveToLine({geometry_column}), 3857),
+              ST_TileEnvelope({tile_xyz})
+          ),
+          4326
+      ) AS geom
+      {id_field}{properties}{source_attr}
+    FROM
+      {schema}.{table}
+    WHERE
+      {geometry_column} && ST_Transform(ST_TileEnvelope({tile_xyz}), {srid}){area_filter}
+    {limit_clause}
+  ) AS tile
+  WHERE NOT ST_IsEmpty(tile.geom)
+) AS collection
+WHERE features IS NOT NULL;
+"#
+    )
+    .trim()
+    .to_string()
+}
+
+/// Get the `id` member of a `GeoJSON` feature, and the property to remove because it is the id
+fn get_geojson_id(column: &str) -> (String, String) {
+    (
+        format!("\n      'id', tile.{},", escape_identifier(column)),
+        format!(" - {}", escape_literal(column)),
+    )
+}
+
+/// Generate the SQL query to fetch a tile from a table.
+#[allow(clippy::too_many_lines)]
+fn build_query(
+    id: &str,
+    info: &TableInfo,
+    supports_tile_margin: bool,
+    max_feature_count: Option<usize>,
+) -> String {
+    let schema = escape_identifier(&info.schema);
+    let table = escape_identifier(&info.table);
+    let geometry_column = escape_identifier(&info.geometry_column);
+    let srid = info.srid;
+
+    let properties = get_properties(id, info);
+    let (id_name, id_field) = get_id_field(info);
+
     let extent = info.extent.unwrap_or(DEFAULT_EXTENT);
-    let buffer = info.buffer.unwrap_or(DEFAULT_BUFFER);
+    let buffer = get_buffer(id, info, extent);
+    let tile_xyz = get_tile_xyz(info);
 
     let bbox_search = if buffer == 0 {
-        "ST_TileEnvelope($1::integer, $2::integer, $3::integer)".to_string()
-    } else if pool.supports_tile_margin() {
+        format!("ST_TileEnvelope({tile_xyz})")
+    } else if supports_tile_margin {
         let margin = f64::from(buffer) / f64::from(extent);
-        format!("ST_TileEnvelope($1::integer, $2::integer, $3::integer, margin => {margin})")
+        format!("ST_TileEnvelope({tile_xyz}, margin => {margin})")
     } else {
         // TODO: we should use ST_Expand here, but it may require a bit more math work,
         //       so might not be worth it as it is only used for PostGIS < v3.1.
         //       v3.1 has been out for 2+ years (december 2020)
         // let val = EARTH_CIRCUMFERENCE * buffer as f64 / extent as f64;
         // format!("ST_Expand(ST_TileEnvelope($1::integer, $2::integer, $3::integer), {val}/2^$1::integer)")
-        "ST_TileEnvelope($1::integer, $2::integer, $3::integer)".to_string()
+        format!("ST_TileEnvelope({tile_xyz})")
     };
 
+    let area_filter = get_area_filter(info, &geometry_column);
+
+    let source_attr = get_source_attribute(id, info);
     let limit_clause = max_feature_count.map_or(String::new(), |v| format!("LIMIT {v}"));
-    let layer_id = escape_literal(info.layer_id.as_ref().unwrap_or(&id));
+    let layer_id = escape_literal(info.layer_id.as_deref().unwrap_or(id));
     let clip_geom = info.clip_geom.unwrap_or(DEFAULT_CLIP_GEOM);
+    let mut geom = format!("ST_Transform(ST_CurveToLine({geometry_column}), 3857)");
+    let mut mvt_clip_geom = clip_geom;
+    if use_fast_clip(id, info) {
+        // The geometry is already clipped to the tile with its buffer, so ST_AsMVTGeom does not need to do it again
+        geom = format!("ST_ClipByBox2D({geom}, {bbox_search})");
+        mvt_clip_geom = false;
+    }
     let query = format!(
         r#"
 SELECT
@@ -191,23 +351,169 @@ SELECT
 FROM (
   SELECT
     ST_AsMVTGeom(
-        ST_Transform(ST_CurveToLine({geometry_column}), 3857),
-        ST_TileEnvelope($1::integer, $2::integer, $3::integer),
-        {extent}, {buffer}, {clip_geom}
+        {geom},
+        ST_TileEnvelope({tile_xyz}),
+        {extent}, {buffer}, {mvt_clip_geom}
     ) AS geom
-    {id_field}{properties}
+    {id_field}{properties}{source_attr}
   FROM
     {schema}.{table}
   WHERE
-    {geometry_column} && ST_Transform({bbox_search}, {srid})
+    {geometry_column} && ST_Transform({bbox_search}, {srid}){area_filter}
   {limit_clause}
-) AS tile;
+) AS tile
+"#
+    )
+    .trim()
+    .to_string();
+
+    let Some(cluster_zoom) = get_cluster_zoom(id, info) else {
+        return format!("{query};");
+    };
+
+    // Points are grouped by snapping them to a grid with the cell size given in tile coordinates,
+    // and each group is replaced with its centroid and the number of points in it.
+    // The points are filtered and limited the same way as without clustering.
+    let radius = info.cluster_radius.unwrap_or(DEFAULT_CLUSTER_RADIUS);
+    let cell_size = EARTH_CIRCUMFERENCE * f64::from(radius) / f64::from(extent);
+    let cluster_query = format!(
+        r#"
+SELECT
+  ST_AsMVT(tile, {layer_id}, {extent}, 'geom')
+FROM (
+  SELECT
+    ST_AsMVTGeom(
+        ST_Centroid(ST_Collect(geom)),
+        ST_TileEnvelope({tile_xyz}),
+        {extent}, {buffer}, {clip_geom}
+    ) AS geom,
+    count(*) AS point_count{source_attr}
+  FROM (
+    SELECT
+      ST_Transform({geometry_column}, 3857) AS geom
+    FROM
+      {schema}.{table}
+    WHERE
+      {geometry_column} && ST_Transform({bbox_search}, {srid}){area_filter}
+    {limit_clause}
+  ) AS points
+  GROUP BY ST_SnapToGrid(geom, {cell_size} / 2 ^ $1::integer)
+) AS tile
 "#
     )
     .trim()
     .to_string();
 
-    Ok((id, PgSqlInfo::new(query, false, info.format_id()), info))
+    format!(
+        "SELECT CASE WHEN $1::integer < {cluster_zoom} THEN ({cluster_query}) ELSE ({query}) END;"
+    )
+}
+
+/// Get the `ST_TileEnvelope` arguments of the requested tile. `ST_TileEnvelope` expects the top-left origin,
+/// so the `y` of a bottom-left origin grid is flipped.
+fn get_tile_xyz(info: &TableInfo) -> &'static str {
+    match info.tile_origin.unwrap_or_default() {
+        TileOrigin::TopLeft => "$1::integer, $2::integer, $3::integer",
+        TileOrigin::BottomLeft => "$1::integer, $2::integer, (1 << $1::integer) - 1 - $3::integer",
+    }
+}
+
+/// Get the buffer in the tile coordinate space, either as configured, or converted from `buffer_px`.
+fn get_buffer(id: &str, info: &TableInfo, extent: u32) -> u32 {
+    match (info.buffer, info.buffer_px) {
+        (Some(buffer), Some(buffer_px)) => {
+            warn!(
+                "Source {id} has both buffer={buffer} and buffer_px={buffer_px} in {}. Using buffer={buffer}.",
+                info.format_id()
+            );
+            buffer
+        }
+        (Some(buffer), None) => buffer,
+        (None, Some(buffer_px)) => {
+            let buffer = u64::from(buffer_px) * u64::from(extent) / u64::from(TILE_SIZE_PX);
+            u32::try_from(buffer).unwrap_or(u32::MAX)
+        }
+        (None, None) => DEFAULT_BUFFER,
+    }
+}
+
+/// Get the maximum lengths of the text properties to truncate, skipping the unknown and non-text properties.
+fn get_max_property_lengths<'a>(id: &str, info: &'a TableInfo) -> HashMap<&'a str, u32> {
+    let Some(max_lengths) = &info.max_property_length else {
+        return HashMap::new();
+    };
+    max_lengths
+        .iter()
+        .filter(|(prop, _)| {
+            match info.properties.as_ref().and_then(|p| p.get(*prop)) {
+                Some(typ) if TEXT_TYPES.contains(&typ.as_str()) => return true,
+                Some(typ) => warn!(
+                    "Property {prop} of source {id} has type {typ}, but only text properties can be truncated with max_property_length in {}",
+                    info.format_id()
+                ),
+                None => warn!(
+                    "Source {id} has no property {prop} to truncate with max_property_length in {}",
+                    info.format_id()
+                ),
+            }
+            false
+        })
+        .map(|(prop, max_length)| (prop.as_str(), *max_length))
+        .collect()
+}
+
+/// Get the column selecting the constant `source` attribute with the source ID, or an empty string if disabled.
+fn get_source_attribute(id: &str, info: &TableInfo) -> String {
+    if info.use_source_attribute() {
+        format!(
+            ", {} AS {}",
+            escape_literal(id),
+            escape_identifier(SOURCE_ATTRIBUTE)
+        )
+    } else {
+        if info.source_attribute.unwrap_or_default() {
+            warn!(
+                "Source {id} already has a {SOURCE_ATTRIBUTE} property in {}, so the source_attribute is not added.",
+                info.format_id()
+            );
+        }
+        String::new()
+    }
+}
+
+/// Get the zoom level below which the points should be clustered, or `None` if clustering is disabled.
+fn get_cluster_zoom(id: &str, info: &TableInfo) -> Option<u8> {
+    let cluster_zoom = info.cluster_below_zoom?;
+    if matches!(info.geometry_type.as_deref(), Some("POINT" | "MULTIPOINT")) {
+        Some(cluster_zoom)
+    } else {
+        warn!(
+            "Clustering is only supported for POINT and MULTIPOINT geometries, but source {id} has {} in {}. Clustering is disabled.",
+            info.geometry_type.as_deref().unwrap_or("unknown geometry type"),
+            info.format_id()
+        );
+        None
+    }
+}
+
+/// Check if the geometries should be clipped with `ST_ClipByBox2D`. Only lines and polygons are clipped this way.
+fn use_fast_clip(id: &str, info: &TableInfo) -> bool {
+    if !info.fast_clip.unwrap_or_default() {
+        return false;
+    }
+    if matches!(
+        info.geometry_type.as_deref(),
+        Some("LINESTRING" | "MULTILINESTRING" | "POLYGON" | "MULTIPOLYGON")
+    ) {
+        true
+    } else {
+        warn!(
+            "Fast clipping is only supported for line and polygon geometries, but source {id} has {} in {}. Fast clipping is disabled.",
+            info.geometry_type.as_deref().unwrap_or("unknown geometry type"),
+            info.format_id()
+        );
+        false
+    }
 }
 
 /// Compute the bounds of a table. This could be slow if the table is large or has no geo index.
@@ -238,3 +544,331 @@ FROM {schema}.{table};
         .get::<_, Option<ewkb::Polygon>>("bounds")
         .and_then(|p| polygon_to_bbox(&p)))
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use martin_tile_utils::{Encoding, Format, TileInfo};
+
+    use super::*;
+    use crate::srv::DynTileSource;
+    use crate::test_utils::some;
+    use crate::{MemorySource, OptOneMany, TileCoord, TileSources};
+
+    fn point_table() -> TableInfo {
+        TableInfo {
+            schema: "public".to_string(),
+            table: "points".to_string(),
+            srid: 4326,
+            geometry_column: "geom".to_string(),
+            geometry_type: some("POINT"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cluster_query() {
+        let query = build_query("points", &point_table(), true, None);
+        assert!(!query.contains("point_count"));
+
+        let info = TableInfo {
+            cluster_below_zoom: Some(10),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        let (clustered, raw) = query.split_once(" ELSE ").unwrap();
+        assert!(clustered.starts_with("SELECT CASE WHEN $1::integer < 10 THEN ("));
+        assert!(clustered.contains("ST_SnapToGrid(geom, 2504688.54"));
+        assert!(clustered.contains("count(*) AS point_count"));
+        assert!(!raw.contains("point_count"));
+        assert!(raw.contains("ST_Transform(ST_CurveToLine(\"geom\"), 3857)"));
+
+        let info = TableInfo {
+            cluster_below_zoom: Some(10),
+            geometry_type: some("POLYGON"),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(!query.contains("point_count"));
+    }
+
+    #[test]
+    fn test_cluster_query_limits() {
+        let info = TableInfo {
+            cluster_below_zoom: Some(10),
+            max_feature_area: Some(2.5),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, Some(1000));
+        let (clustered, raw) = query.split_once(" ELSE ").unwrap();
+        for query in [clustered, raw] {
+            assert_eq!(query.matches("LIMIT 1000").count(), 1, "{query}");
+            assert_eq!(query.matches("AND ST_Area(").count(), 1, "{query}");
+        }
+        let (points, _) = clustered.split_once(") AS points").unwrap();
+        assert!(points.contains("LIMIT 1000"));
+        assert!(points.contains("AND ST_Area("));
+    }
+
+    #[test]
+    fn test_source_attribute_query() {
+        let layer_fields = |info: &TableInfo| {
+            let tj = info.to_tilejson("points".to_string());
+            tj.vector_layers.unwrap()[0].fields.clone()
+        };
+        let query = build_query("points", &point_table(), true, None);
+        assert!(!query.contains("AS \"source\""));
+        assert!(!layer_fields(&point_table()).contains_key("source"));
+
+        let info = TableInfo {
+            source_attribute: Some(true),
+            cluster_below_zoom: Some(10),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        let (clustered, raw) = query.split_once(" ELSE ").unwrap();
+        assert!(clustered.contains("count(*) AS point_count, 'points' AS \"source\""));
+        assert!(raw.contains("'points' AS \"source\""));
+        assert_eq!(layer_fields(&info)["source"], "text");
+
+        // A table property with the same name wins
+        let info = TableInfo {
+            source_attribute: Some(true),
+            properties: Some([("source".to_string(), "int4".to_string())].into()),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(!query.contains("'points' AS"));
+        assert_eq!(layer_fields(&info)["source"], "int4");
+    }
+
+    #[test]
+    fn test_composite_id_query() {
+        let info = TableInfo {
+            id_column: OptOneMany::One("gid".to_string()),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(query.contains("ST_AsMVT(tile, 'points', 4096, 'geom', 'gid')"));
+        assert!(!query.contains("md5"));
+
+        let info = TableInfo {
+            id_column: OptOneMany::Many(vec!["region".to_string(), "code".to_string()]),
+            prop_mapping: [("code".to_string(), "Code".to_string())].into(),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(query.contains("ST_AsMVT(tile, 'points', 4096, 'geom', '__martin_feature_id')"));
+        assert!(query.contains(
+            r#"('x' || left(md5(jsonb_build_array("region", "Code")::text), 16))::bit(64)::bigint & 9223372036854775807 AS "__martin_feature_id""#
+        ));
+        assert_eq!(query.matches("md5").count(), 1);
+    }
+
+    #[test]
+    fn test_tile_origin_query() {
+        let query = build_query("points", &point_table(), true, None);
+        assert!(query.contains("ST_TileEnvelope($1::integer, $2::integer, $3::integer)"));
+        assert!(!query.contains("<<"));
+        assert_eq!(point_table().to_tilejson("points".to_string()).scheme, None);
+
+        let info = TableInfo {
+            tile_origin: Some(TileOrigin::BottomLeft),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        let flipped = "$1::integer, $2::integer, (1 << $1::integer) - 1 - $3::integer";
+        assert_eq!(query.matches(flipped).count(), 2, "{query}");
+        assert!(!query.contains("$2::integer, $3::integer"));
+        let tj = info.to_tilejson("points".to_string());
+        assert_eq!(tj.scheme.as_deref(), Some("tms"));
+    }
+
+    #[test]
+    fn test_comment_zoom_range() {
+        let info = TableInfo {
+            tilejson: Some(comment_to_tilejson(
+                r#"{"minzoom": 2, "maxzoom": 12}"#,
+                "public.points",
+            )),
+            ..point_table()
+        };
+        let tj = info.to_tilejson("points".to_string());
+        assert_eq!((tj.minzoom, tj.maxzoom), (Some(2), Some(12)));
+
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let sources = TileSources::new(vec![vec![Box::new(MemorySource::new("points", tj, info))]]);
+        for (z, expected) in [
+            (1, StatusCode::NOT_FOUND),
+            (2, StatusCode::OK),
+            (12, StatusCode::OK),
+            (13, StatusCode::NOT_FOUND),
+        ] {
+            let xyz = TileCoord { z, x: 0, y: 0 };
+            let status =
+                match DynTileSource::new(&sources, "points", Some(xyz), "", None, None, None) {
+                    Ok(_) => StatusCode::OK,
+                    Err(e) => e.as_response_error().status_code(),
+                };
+            assert_eq!(status, expected, "z{z}");
+        }
+    }
+
+    #[test]
+    fn test_geojson_query() {
+        let info = TableInfo {
+            properties: Some([("name".to_string(), "text".to_string())].into()),
+            prop_mapping: [("name".to_string(), "Name".to_string())].into(),
+            cluster_below_zoom: Some(10),
+            ..point_table()
+        };
+        let query = build_geojson_query("points", &info, Some(100));
+        assert!(query.contains("'FeatureCollection'"), "{query}");
+        assert!(query.contains("ST_AsGeoJSON(tile.geom, 6)"), "{query}");
+        assert!(!query.contains("'id'"), "{query}");
+        assert!(query.contains(r#""Name" AS "name""#), "{query}");
+        assert!(query.contains("LIMIT 100"), "{query}");
+        assert!(!query.contains("ST_AsMVT"), "{query}");
+        assert!(!query.contains("point_count"), "{query}");
+
+        let info = TableInfo {
+            id_column: OptOneMany::One("gid".to_string()),
+            ..point_table()
+        };
+        let query = build_geojson_query("points", &info, None);
+        assert!(query.contains(r#"'id', tile."gid","#), "{query}");
+        assert!(
+            query.contains(r#"to_jsonb(tile.*) - 'geom' - 'gid'"#),
+            "{query}"
+        );
+    }
+
+    #[test]
+    fn test_max_property_length_query() {
+        let props = [
+            ("name", "text"),
+            ("description", "varchar"),
+            ("rank", "int4"),
+        ];
+        let info = TableInfo {
+            properties: Some(
+                props
+                    .iter()
+                    .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+                    .collect(),
+            ),
+            prop_mapping: [("name".to_string(), "Name".to_string())].into(),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(!query.contains("LEFT("));
+
+        let info = TableInfo {
+            max_property_length: Some(
+                [
+                    ("name", 10),
+                    ("description", 100),
+                    ("rank", 1),
+                    ("missing", 5),
+                ]
+                .iter()
+                .map(|(k, v)| ((*k).to_string(), *v))
+                .collect(),
+            ),
+            ..info
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(
+            query.contains(", LEFT(\"Name\", 10) AS \"name\""),
+            "{query}"
+        );
+        assert!(query.contains(", LEFT(\"description\", 100) AS \"description\""));
+        // Non-text and unknown properties are not truncated
+        assert!(query.contains(", \"rank\""));
+        assert_eq!(query.matches("LEFT(").count(), 2);
+    }
+
+    #[test]
+    fn test_max_feature_area_query() {
+        let query = build_query("points", &point_table(), true, None);
+        assert!(!query.contains("ST_Area"));
+
+        let info = TableInfo {
+            max_feature_area: Some(2.5),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(query.contains(
+            "AND ST_Area(ST_Transform(ST_Envelope(\"geom\"), 3857)) < 2.5 * (40075016.68"
+        ));
+    }
+
+    #[test]
+    fn test_fast_clip_query() {
+        let polygon_table = TableInfo {
+            geometry_type: some("MULTIPOLYGON"),
+            ..point_table()
+        };
+        let query = build_query("polygons", &polygon_table, true, None);
+        assert!(!query.contains("ST_ClipByBox2D"));
+        assert!(query.contains("4096, 64, true"));
+
+        let info = TableInfo {
+            fast_clip: Some(true),
+            ..polygon_table
+        };
+        let query = build_query("polygons", &info, true, None);
+        assert!(query.contains(
+            "ST_ClipByBox2D(ST_Transform(ST_CurveToLine(\"geom\"), 3857), ST_TileEnvelope($1::integer, $2::integer, $3::integer, margin => 0.015625))"
+        ));
+        assert!(query.contains("4096, 64, false"));
+
+        // Points do not need clipping
+        let info = TableInfo {
+            fast_clip: Some(true),
+            ..point_table()
+        };
+        let query = build_query("points", &info, true, None);
+        assert!(!query.contains("ST_ClipByBox2D"));
+    }
+
+    #[test]
+    fn test_layer_id_query() {
+        let query = build_query("public.points", &point_table(), true, None);
+        assert!(query.contains("ST_AsMVT(tile, 'public.points', 4096, 'geom')"));
+
+        let info = TableInfo {
+            layer_id: some("points"),
+            ..point_table()
+        };
+        let query = build_query("public.points", &info, true, None);
+        assert!(query.contains("ST_AsMVT(tile, 'points', 4096, 'geom')"));
+    }
+
+    #[test]
+    fn test_buffer_px_query() {
+        let query = |extent, buffer, buffer_px| {
+            let info = TableInfo {
+                extent,
+                buffer,
+                buffer_px,
+                ..point_table()
+            };
+            build_query("points", &info, true, None)
+        };
+
+        // 4 pixels are 1/64 of a 256px tile at any extent
+        let q = query(None, None, Some(4));
+        assert!(q.contains("4096, 64, true"));
+        assert!(q.contains("margin => 0.015625"));
+        let q = query(Some(8192), None, Some(4));
+        assert!(q.contains("8192, 128, true"));
+        assert!(q.contains("margin => 0.015625"));
+        let q = query(Some(512), None, Some(4));
+        assert!(q.contains("512, 8, true"));
+
+        // The buffer in the tile coordinate space takes precedence
+        let q = query(Some(8192), Some(10), Some(4));
+        assert!(q.contains("8192, 10, true"));
+    }
+}
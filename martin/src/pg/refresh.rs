@@ -0,0 +1,164 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use postgres_protocol::escape::escape_identifier;
+use tokio::time::MissedTickBehavior;
+
+use crate::pg::config_table::TableInfoSources;
+use crate::pg::PgError::{NotMaterializedView, PostgresError};
+use crate::pg::{PgPool, PgResult};
+use crate::utils::{invalidate_source, OptMainCache};
+
+/// A materialized view that is refreshed in the background by the server
+#[derive(Clone, Debug)]
+pub struct ViewRefresh {
+    id: String,
+    interval: Duration,
+    sql: String,
+    pool: PgPool,
+}
+
+/// Get the refreshes of the table sources with a `refresh_interval`,
+/// making sure that each of them is backed by a materialized view.
+pub async fn get_view_refreshes(
+    tables: &TableInfoSources,
+    pool: &PgPool,
+) -> PgResult<Vec<ViewRefresh>> {
+    let mut refreshes = Vec::new();
+    for (id, info) in tables {
+        let Some(interval) = info.refresh_interval else {
+            continue;
+        };
+        let is_view = pool
+            .get()
+            .await?
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_matviews WHERE schemaname = $1 AND matviewname = $2)",
+                &[&info.schema, &info.table],
+            )
+            .await
+            .map_err(|e| PostgresError(e, "querying materialized views"))?
+            .get::<_, bool>(0);
+        if !is_view {
+            return Err(NotMaterializedView(
+                id.clone(),
+                format!("{}.{}", info.schema, info.table),
+            ));
+        }
+        refreshes.push(ViewRefresh {
+            id: id.clone(),
+            interval: Duration::from_secs(interval),
+            sql: format!(
+                "REFRESH MATERIALIZED VIEW CONCURRENTLY {}.{}",
+                escape_identifier(&info.schema),
+                escape_identifier(&info.table),
+            ),
+            pool: pool.clone(),
+        });
+    }
+    Ok(refreshes)
+}
+
+/// Start a background task for each materialized view,
+/// refreshing it and evicting its tiles from the cache.
+pub fn schedule_refreshes(refreshes: &[ViewRefresh], cache: &OptMainCache) {
+    for refresh in refreshes {
+        info!(
+            "Refreshing source {} every {}s with {}",
+            refresh.id,
+            refresh.interval.as_secs(),
+            refresh.sql
+        );
+        let ViewRefresh { pool, sql, .. } = refresh.clone();
+        tokio::spawn(refresh_loop(
+            refresh.id.clone(),
+            refresh.interval,
+            cache.clone(),
+            move || refresh_view(pool.clone(), sql.clone()),
+        ));
+    }
+}
+
+async fn refresh_view(pool: PgPool, sql: String) -> PgResult<()> {
+    pool.get()
+        .await?
+        .batch_execute(&sql)
+        .await
+        .map_err(|e| PostgresError(e, "refreshing a materialized view"))
+}
+
+/// Run `refresh` every `interval`, evicting the source from the cache after each successful refresh.
+/// The first refresh runs after the first interval, as the view is assumed to be up to date on startup.
+async fn refresh_loop<F, Fut>(id: String, interval: Duration, cache: OptMainCache, mut refresh: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = PgResult<()>>,
+{
+    let mut timer = tokio::time::interval(interval);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        let start = Instant::now();
+        match refresh().await {
+            Ok(()) => {
+                info!("Refreshed source {id} in {:.3?}", start.elapsed());
+                if let Some(cache) = &cache {
+                    invalidate_source(cache, &id);
+                }
+            }
+            Err(e) => error!(
+                "Failed to refresh source {id} after {:.3?}: {e}",
+                start.elapsed()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::utils::{CacheKey, CacheValue, MainCache};
+    use crate::TileCoord;
+
+    #[actix_rt::test]
+    async fn test_refresh_loop() {
+        let cache = MainCache::builder().support_invalidation_closures().build();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let view_key = CacheKey::Tile("view".to_string(), xyz);
+        let other_key = CacheKey::Tile("other".to_string(), xyz);
+        cache.insert(view_key, CacheValue::Tile(vec![1])).await;
+        cache.insert(other_key, CacheValue::Tile(vec![2])).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let task = tokio::spawn(refresh_loop(
+            "view".to_string(),
+            Duration::from_millis(100),
+            Some(cache.clone()),
+            move || {
+                calls2.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            },
+        ));
+
+        // Nothing happens before the first interval has passed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        let view_key = CacheKey::Tile("view".to_string(), xyz);
+        assert!(cache.get(&view_key).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(calls.load(Ordering::SeqCst) >= 2);
+        assert!(cache.get(&view_key).await.is_none());
+        let other_key = CacheKey::Tile("other".to_string(), xyz);
+        assert!(cache.get(&other_key).await.is_some());
+
+        task.abort();
+    }
+}
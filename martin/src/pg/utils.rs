@@ -3,7 +3,7 @@ use std::future::Future;
 use std::time::Duration;
 
 use deadpool_postgres::tokio_postgres::types::Json;
-use futures::pin_mut;
+use futures::{pin_mut, stream, StreamExt as _};
 use itertools::Itertools as _;
 use log::{error, info, warn};
 use postgis::{ewkb, LineString, Point, Polygon};
@@ -43,6 +43,62 @@ pub async fn on_slow<T, S: FnOnce()>(
     }
 }
 
+/// Log the progress of [`join_bounded`] after every this many completed futures
+const PROGRESS_STEP: usize = 100;
+
+/// Run the futures with at most `limit` of them at the same time, and return their results in the original order.
+/// The progress is logged for the large batches, using `what` to name the processed items.
+pub async fn join_bounded<T>(
+    futures: Vec<impl Future<Output = T>>,
+    limit: usize,
+    what: &str,
+) -> Vec<T> {
+    let total = futures.len();
+    let mut done = 0;
+    stream::iter(futures)
+        .buffered(limit.max(1))
+        .inspect(|_| {
+            done += 1;
+            if total >= PROGRESS_STEP && (done % PROGRESS_STEP == 0 || done == total) {
+                info!("Processed {done} of {total} {what}");
+            }
+        })
+        .collect()
+        .await
+}
+
+/// Check if the text matches a glob pattern, where `*` matches any number of characters, and `?` matches one character
+#[must_use]
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and of the text it was matched at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p).copied() {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                // Let the last `*` match one more character, and try again
+                let Some((star, star_t)) = backtrack else {
+                    return false;
+                };
+                backtrack = Some((star, star_t + 1));
+                p = star + 1;
+                t = star_t + 1;
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 #[must_use]
 pub fn json_to_hashmap(value: &serde_json::Value) -> InfoMap<String> {
     let mut result = BTreeMap::new();
@@ -56,6 +112,20 @@ pub fn json_to_hashmap(value: &serde_json::Value) -> InfoMap<String> {
     result
 }
 
+/// Parse the SQL comment of a table or a function as a partial `TileJSON`.
+/// A comment that is not a JSON object is used verbatim as the `TileJSON` `description`.
+#[must_use]
+pub fn comment_to_tilejson(comment: &str, name: &str) -> serde_json::Value {
+    match serde_json::from_str::<serde_json::Value>(comment) {
+        Ok(v) if v.is_object() => return v,
+        Err(e) if comment.trim_start().starts_with('{') => {
+            warn!("Unable to deserialize SQL comment on {name} as tilejson, using it as the description instead: {e}");
+        }
+        _ => {}
+    }
+    serde_json::json!({ "description": comment })
+}
+
 #[must_use]
 pub fn patch_json(target: TileJSON, patch: Option<&serde_json::Value>) -> TileJSON {
     let Some(tj) = patch else {
@@ -187,3 +257,65 @@ pub fn find_kv_ignore_case<'a, T>(
         Err(multiple)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_join_bounded() {
+        let active = AtomicUsize::new(0);
+        let max_active = AtomicUsize::new(0);
+        let futures: Vec<_> = (0..250_u64)
+            .map(|i| {
+                let (active, max_active) = (&active, &max_active);
+                async move {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    // The later futures finish sooner, but the results must keep the original order
+                    tokio::time::sleep(Duration::from_micros(500 * (5 - i % 5))).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    i
+                }
+            })
+            .collect();
+
+        let res = join_bounded(futures, 4, "mock tables").await;
+        assert_eq!(res, (0..250).collect::<Vec<_>>());
+        assert_eq!(max_active.load(Ordering::SeqCst), 4);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("public.*", "public.points"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*.points?", "osm.points2"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("public.*", "internal.points"));
+        assert!(!glob_match("*.points?", "osm.points"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn test_comment_to_tilejson() {
+        let tj = comment_to_tilejson(r#"{"description": "desc", "minzoom": 3}"#, "public.tbl");
+        assert_eq!(tj, json!({ "description": "desc", "minzoom": 3 }));
+
+        for comment in ["Roads of the city", "42", r#"{"description": "#] {
+            let tj = comment_to_tilejson(comment, "public.tbl");
+            assert_eq!(tj, json!({ "description": comment }));
+        }
+
+        let tj = patch_json(
+            tilejson::tilejson! { tiles: vec![] },
+            Some(&comment_to_tilejson("Roads of the city", "public.tbl")),
+        );
+        assert_eq!(tj.description.as_deref(), Some("Roads of the city"));
+    }
+}
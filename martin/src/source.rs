@@ -1,14 +1,16 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 
-use actix_web::error::ErrorNotFound;
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
 use async_trait::async_trait;
+use itertools::Itertools as _;
 use log::debug;
-use martin_tile_utils::TileInfo;
+use martin_tile_utils::{Format, TileInfo};
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
 
-use crate::{MartinResult, TileCoord};
+use crate::srv::{CatalogName, MAX_SOURCES_PER_REQUEST_DEFAULT};
+use crate::{MartinError, MartinResult, TileCoord};
 
 pub type TileData = Vec<u8>;
 pub type UrlQuery = HashMap<String, String>;
@@ -17,46 +19,110 @@ pub type TileInfoSource = Box<dyn Source>;
 
 pub type TileInfoSources = Vec<TileInfoSource>;
 
-#[derive(Default, Clone)]
-pub struct TileSources(HashMap<String, Box<dyn Source>>);
+#[derive(Clone)]
+pub struct TileSources {
+    sources: HashMap<String, Box<dyn Source>>,
+    /// Maximum number of sources that can be combined in a single request
+    max_sources: usize,
+}
 pub type TileCatalog = BTreeMap<String, CatalogSourceEntry>;
 
+impl Default for TileSources {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
 impl TileSources {
     #[must_use]
     pub fn new(sources: Vec<TileInfoSources>) -> Self {
-        Self(
-            sources
+        Self {
+            sources: sources
                 .into_iter()
                 .flatten()
                 .map(|src| (src.get_id().to_string(), src))
                 .collect(),
-        )
+            max_sources: MAX_SOURCES_PER_REQUEST_DEFAULT,
+        }
+    }
+
+    /// Set the maximum number of sources that can be combined in a single request
+    #[must_use]
+    pub fn with_max_sources(mut self, max_sources: usize) -> Self {
+        self.max_sources = max_sources;
+        self
     }
 
     #[must_use]
     pub fn get_catalog(&self) -> TileCatalog {
-        self.0
+        self.get_catalog_with(CatalogName::default())
+    }
+
+    /// Get the catalog, reporting the names that are the same as the source IDs as configured
+    #[must_use]
+    pub fn get_catalog_with(&self, names: CatalogName) -> TileCatalog {
+        self.sources
             .iter()
-            .map(|(id, src)| (id.to_string(), src.get_catalog_entry()))
+            .map(|(id, src)| {
+                let mut entry = src.get_catalog_entry();
+                let name = src.get_tilejson().name.as_ref();
+                match names {
+                    CatalogName::Drop => {}
+                    CatalogName::Keep => entry.name = name.cloned(),
+                    CatalogName::Always => entry.name = Some(name.unwrap_or(id).clone()),
+                }
+                (id.clone(), entry)
+            })
             .collect()
     }
 
+    /// Get all sources, sorted by their IDs
+    #[must_use]
+    pub fn get_all_sources(&self) -> Vec<&dyn Source> {
+        let mut sources: Vec<_> = self.sources.values().map(AsRef::as_ref).collect();
+        sources.sort_by(|a, b| a.get_id().cmp(b.get_id()));
+        sources
+    }
+
     pub fn get_source(&self, id: &str) -> actix_web::Result<&dyn Source> {
         Ok(self
-            .0
+            .sources
             .get(id)
             .ok_or_else(|| ErrorNotFound(format!("Source {id} does not exist")))?
             .as_ref())
     }
 
+    /// Replace a source with a wrapper around it. Returns false if the source does not exist.
+    pub fn wrap_source(
+        &mut self,
+        id: &str,
+        wrap: impl FnOnce(TileInfoSource) -> TileInfoSource,
+    ) -> bool {
+        if let Some(src) = self.sources.remove(id) {
+            self.sources.insert(id.to_string(), wrap(src));
+            true
+        } else {
+            false
+        }
+    }
+
     /// Get a list of sources, and the tile info for the merged sources.
     /// Ensure that all sources have the same format and encoding.
-    /// If zoom is specified, filter out sources that do not support it.
+    /// If tile coordinates are specified, filter out sources that do not support them.
+    /// Requests combining more sources than allowed are rejected before any of them are fetched.
     pub fn get_sources(
         &self,
         source_ids: &str,
-        zoom: Option<u8>,
+        xyz: Option<TileCoord>,
     ) -> actix_web::Result<(Vec<&dyn Source>, bool, TileInfo)> {
+        let count = source_ids.split(',').count();
+        if count > self.max_sources {
+            Err(ErrorBadRequest(format!(
+                "Requested {count} sources, but max_sources_per_request is {}",
+                self.max_sources
+            )))?;
+        }
+
         let mut sources = Vec::new();
         let mut info: Option<TileInfo> = None;
         let mut use_url_query = false;
@@ -77,8 +143,8 @@ impl TileSources {
             }
 
             // TODO: Use chained-if-let once available
-            if match zoom {
-                Some(zoom) if Self::check_zoom(src, id, zoom) => true,
+            if match xyz {
+                Some(xyz) if Self::check_tile(src, id, xyz) => true,
                 None => true,
                 _ => false,
             } {
@@ -90,17 +156,17 @@ impl TileSources {
         Ok((sources, use_url_query, info.unwrap()))
     }
 
-    pub fn check_zoom(src: &dyn Source, id: &str, zoom: u8) -> bool {
-        let is_valid = src.is_valid_zoom(zoom);
+    pub fn check_tile(src: &dyn Source, id: &str, xyz: TileCoord) -> bool {
+        let is_valid = src.is_valid_tile(xyz);
         if !is_valid {
-            debug!("Zoom {zoom} is not valid for source {id}");
+            debug!("Tile {xyz:#} is not valid for source {id}");
         }
         is_valid
     }
 }
 
 #[async_trait]
-pub trait Source: Send + Debug {
+pub trait Source: Send + Sync + Debug {
     fn get_id(&self) -> &str;
 
     fn get_tilejson(&self) -> &TileJSON;
@@ -119,12 +185,38 @@ pub trait Source: Send + Debug {
         url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData>;
 
+    /// Formats other than the one of [`Source::get_tile_info`] that this source can also produce its tiles in,
+    /// e.g. `GeoJSON` for a vector source. The clients select them with the `Accept` header of the tile requests.
+    fn get_alt_formats(&self) -> Vec<Format> {
+        Vec::new()
+    }
+
+    /// Get an uncompressed tile in one of the [`Source::get_alt_formats`] formats
+    async fn get_alt_tile(
+        &self,
+        _xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+        format: Format,
+    ) -> MartinResult<TileData> {
+        Err(MartinError::UnsupportedTileFormat(
+            self.get_id().to_string(),
+            format,
+        ))
+    }
+
     fn is_valid_zoom(&self, zoom: u8) -> bool {
         let tj = self.get_tilejson();
         tj.minzoom.map_or(true, |minzoom| zoom >= minzoom)
             && tj.maxzoom.map_or(true, |maxzoom| zoom <= maxzoom)
     }
 
+    /// Check if the tile is within the tile grid of this source.
+    /// By default, this is the Web Mercator grid with `2^zoom` tiles in each direction.
+    fn is_valid_tile(&self, xyz: TileCoord) -> bool {
+        let size = 1_u64 << xyz.z.min(32);
+        self.is_valid_zoom(xyz.z) && u64::from(xyz.x) < size && u64::from(xyz.y) < size
+    }
+
     fn get_catalog_entry(&self) -> CatalogSourceEntry {
         let id = self.get_id();
         let tilejson = self.get_tilejson();
@@ -135,10 +227,116 @@ pub trait Source: Send + Debug {
             name: tilejson.name.as_ref().filter(|v| *v != id).cloned(),
             description: tilejson.description.clone(),
             attribution: tilejson.attribution.clone(),
+            extent: tilejson
+                .other
+                .get("extent")
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|v| u32::try_from(v).ok()),
         }
     }
 }
 
+/// A tile source that serves a fixed set of tiles from memory.
+/// Useful for testing the HTTP server without any databases or files, e.g. in the integration tests of the downstream crates.
+#[derive(Debug, Clone)]
+pub struct MemorySource {
+    id: String,
+    tilejson: TileJSON,
+    tile_info: TileInfo,
+    tiles: HashMap<TileCoord, TileData>,
+    alt_tiles: HashMap<(Format, TileCoord), TileData>,
+    errors: HashMap<TileCoord, String>,
+}
+
+impl MemorySource {
+    #[must_use]
+    pub fn new(id: impl Into<String>, tilejson: TileJSON, tile_info: TileInfo) -> Self {
+        Self {
+            id: id.into(),
+            tilejson,
+            tile_info,
+            tiles: HashMap::new(),
+            alt_tiles: HashMap::new(),
+            errors: HashMap::new(),
+        }
+    }
+
+    /// Add a tile to the source. Tiles that were not added are returned as empty.
+    #[must_use]
+    pub fn with_tile(mut self, xyz: TileCoord, data: TileData) -> Self {
+        self.tiles.insert(xyz, data);
+        self
+    }
+
+    /// Add a tile in one of the [`Source::get_alt_formats`] formats, which makes the source support that format
+    #[must_use]
+    pub fn with_alt_tile(mut self, format: Format, xyz: TileCoord, data: TileData) -> Self {
+        self.alt_tiles.insert((format, xyz), data);
+        self
+    }
+
+    /// Make getting the tile fail with the given message, e.g. to test a source that is down
+    #[must_use]
+    pub fn with_error(mut self, xyz: TileCoord, message: impl Into<String>) -> Self {
+        self.errors.insert(xyz, message.into());
+        self
+    }
+}
+
+#[async_trait]
+impl Source for MemorySource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        if let Some(message) = self.errors.get(&xyz) {
+            return Err(MartinError::IoError(std::io::Error::other(message.clone())));
+        }
+        Ok(self.tiles.get(&xyz).cloned().unwrap_or_default())
+    }
+
+    fn get_alt_formats(&self) -> Vec<Format> {
+        self.alt_tiles
+            .keys()
+            .map(|(format, _)| *format)
+            .unique()
+            .collect()
+    }
+
+    async fn get_alt_tile(
+        &self,
+        xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+        format: Format,
+    ) -> MartinResult<TileData> {
+        if !self.get_alt_formats().contains(&format) {
+            return Err(MartinError::UnsupportedTileFormat(self.id.clone(), format));
+        }
+        Ok(self
+            .alt_tiles
+            .get(&(format, xyz))
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
 impl Clone for Box<dyn Source> {
     fn clone(&self) -> Self {
         self.clone_source()
@@ -153,10 +351,15 @@ pub struct CatalogSourceEntry {
     pub name: Option<String>,
     pub description: Option<String>,
     pub attribution: Option<String>,
+    /// Tile extent in tile coordinate space, if the source reports it in its `TileJSON`
+    pub extent: Option<u32>,
 }
 
 #[cfg(test)]
 mod tests {
+    use martin_tile_utils::{Encoding, Format};
+    use tilejson::tilejson;
+
     use super::*;
 
     #[test]
@@ -165,6 +368,41 @@ mod tests {
         assert_eq!(format!("{xyz}"), "1,2,3");
         assert_eq!(format!("{xyz:#}"), "1/2/3");
     }
+
+    #[test]
+    fn catalog_names() {
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let named = |name: Option<&str>| {
+            let mut tj = tilejson! { tiles: vec![] };
+            tj.name = name.map(ToString::to_string);
+            tj
+        };
+        let sources = TileSources::new(vec![vec![
+            Box::new(MemorySource::new("same", named(Some("same")), info)),
+            Box::new(MemorySource::new("other", named(Some("Other")), info)),
+            Box::new(MemorySource::new("none", named(None), info)),
+        ]]);
+        let names = |mode| {
+            sources
+                .get_catalog_with(mode)
+                .into_iter()
+                .map(|(id, entry)| (id, entry.name))
+                .collect::<Vec<_>>()
+        };
+        let expected = |same: Option<&str>, none: Option<&str>| {
+            vec![
+                ("none".to_string(), none.map(ToString::to_string)),
+                ("other".to_string(), Some("Other".to_string())),
+                ("same".to_string(), same.map(ToString::to_string)),
+            ]
+        };
+        assert_eq!(names(CatalogName::Drop), expected(None, None));
+        assert_eq!(names(CatalogName::Keep), expected(Some("same"), None));
+        assert_eq!(
+            names(CatalogName::Always),
+            expected(Some("same"), Some("none"))
+        );
+    }
 }
 
 #[derive(Debug, Clone)]
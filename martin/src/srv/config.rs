@@ -1,9 +1,19 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 
 use crate::args::PreferredEncoding;
+use crate::srv::{CpuBudgetConfig, RateLimitConfig};
+use crate::{OptBoolObj, OptOneMany};
 
+pub const HEALTH_PATH_DEFAULT: &str = "/health";
 pub const KEEP_ALIVE_DEFAULT: u64 = 75;
 pub const LISTEN_ADDRESSES_DEFAULT: &str = "0.0.0.0:3000";
+pub const MAX_SOURCES_PER_REQUEST_DEFAULT: usize = 10;
+pub const MAX_PAYLOAD_SIZE_DEFAULT: usize = 16 * 1024;
+pub const SHUTDOWN_TIMEOUT_DEFAULT: u64 = 0;
+pub const SERVER_HEADER_DEFAULT: &str = concat!("martin/", env!("CARGO_PKG_VERSION"));
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
@@ -13,6 +23,131 @@ pub struct SrvConfig {
     pub base_path: Option<String>,
     pub worker_processes: Option<usize>,
     pub preferred_encoding: Option<PreferredEncoding>,
+    /// Value of the `Server` response header. Set to `false` to omit the header,
+    /// or to a string to override the default `martin/<version>` value.
+    #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
+    pub server_header: OptBoolObj<String>,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Add a `Server-Timing` header to the tile responses with the duration of each processing phase
+    pub server_timing: Option<bool>,
+    /// Add an `X-Martin-Encoding` header to the tile responses with their encoding,
+    /// and whether the tile was sent as stored, re-compressed, or compressed by Martin
+    pub encoding_header: Option<bool>,
+    /// Add a `Link` header to the tile responses with the adjacent tiles at the same zoom, for the clients to prefetch
+    pub prefetch_links: Option<bool>,
+    /// Serve the tiles in their stored encoding, decoding them only if the client does not support it
+    pub disable_compression: Option<bool>,
+    /// What to do if the client accepts none of the supported encodings, not even `identity`
+    pub encoding_fallback: Option<EncodingFallback>,
+    /// Serve the composite tiles even if some of their sources fail, treating the failed ones as empty
+    pub best_effort: Option<bool>,
+    /// Evict the cached tiles that cannot be decompressed, so that the next request fetches them again
+    pub evict_invalid_tiles: Option<bool>,
+    /// Time budget of a tile request in milliseconds, passed on to Postgres as the `statement_timeout`
+    pub request_timeout_ms: Option<u64>,
+    /// Milliseconds to remember the empty tiles without asking the sources again, if the main cache is disabled
+    pub empty_tile_ttl_ms: Option<u64>,
+    /// Maximum number of sources that can be combined in a single tile or `TileJSON` request
+    pub max_sources_per_request: Option<usize>,
+    /// How the catalog reports the name of a tile source when it is the same as the source ID
+    pub catalog_name: Option<CatalogName>,
+    /// Response of the `/` route, instead of the default message
+    pub index: Option<IndexConfig>,
+    /// IDs of the PNG and JPEG sources to transcode to WebP for the clients that accept it
+    pub transcode_webp: Option<Vec<String>>,
+    /// Extra headers of the tile responses by the source ID, e.g. the license of its data
+    pub response_headers: Option<BTreeMap<String, BTreeMap<String, String>>>,
+    /// Number of seconds the in-flight requests are given to finish when the server is shutting down
+    pub shutdown_timeout: Option<u64>,
+    /// Enable the `POST /shutdown` route, authorized with the `Authorization: Bearer <token>` header
+    pub shutdown_token: Option<String>,
+    /// One or more paths of the health check route instead of the default `/health`, e.g. `/healthz`
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub health_path: OptOneMany<String>,
+    /// Cache the tiles separately for each value of this request header or URL query parameter, e.g. a tenant ID
+    pub cache_partition: Option<CachePartition>,
+    /// Split the CPU between the tile compression and the glyph rendering by their weights
+    pub cpu_budget: Option<CpuBudgetConfig>,
+    /// Maximum size of a request body in bytes, larger requests get `413 Payload Too Large`
+    pub max_payload_size: Option<usize>,
+}
+
+/// Response to the clients that accept none of the supported encodings, e.g. `Accept-Encoding: zstd, identity;q=0`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodingFallback {
+    /// Respond with `406 Not Acceptable`
+    #[default]
+    NotAcceptable,
+    /// Send the uncompressed data anyway
+    Identity,
+}
+
+/// Name of a tile source in the catalog, if its `TileJSON` name is the same as the source ID
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatalogName {
+    /// Omit the name, because it adds nothing to the ID
+    #[default]
+    Drop,
+    /// Keep the name as it is in the `TileJSON`
+    Keep,
+    /// Always include a name, using the source ID if the `TileJSON` has no name
+    Always,
+}
+
+/// Part of the request that selects the cache partition of the tiles
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePartition {
+    /// Name of the request header
+    Header(String),
+    /// Name of the URL query parameter
+    QueryParam(String),
+}
+
+/// Response of the `/` route. Only one of the values may be set.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct IndexConfig {
+    /// Plain text to return
+    pub text: Option<String>,
+    /// Path to an HTML file to return
+    pub file: Option<PathBuf>,
+    /// URL to redirect to
+    pub redirect: Option<String>,
+}
+
+impl SrvConfig {
+    /// Get the value of the `Server` header, or `None` if it should not be sent
+    #[must_use]
+    pub fn get_server_header(&self) -> Option<&str> {
+        match &self.server_header {
+            OptBoolObj::NoValue | OptBoolObj::Bool(true) => Some(SERVER_HEADER_DEFAULT),
+            OptBoolObj::Bool(false) => None,
+            OptBoolObj::Object(v) => Some(v.as_str()),
+        }
+    }
+
+    /// Get the paths of the health check route
+    #[must_use]
+    pub fn get_health_paths(&self) -> Vec<&str> {
+        if self.health_path.is_empty() {
+            vec![HEALTH_PATH_DEFAULT]
+        } else {
+            self.health_path.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Get the source IDs that would conflict with the health check paths
+    #[must_use]
+    pub fn get_health_ids(&self) -> Vec<&str> {
+        self.get_health_paths()
+            .into_iter()
+            .filter_map(|path| path.strip_prefix('/'))
+            .filter(|id| !id.is_empty() && !id.contains('/'))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -35,8 +170,7 @@ mod tests {
                 keep_alive: Some(75),
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
-                preferred_encoding: None,
-                base_path: None,
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -52,7 +186,7 @@ mod tests {
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
                 preferred_encoding: Some(PreferredEncoding::Brotli),
-                base_path: None
+                ..Default::default()
             }
         );
         assert_eq!(
@@ -68,8 +202,37 @@ mod tests {
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
                 preferred_encoding: Some(PreferredEncoding::Brotli),
-                base_path: None,
+                ..Default::default()
             }
         );
     }
+
+    #[test]
+    fn parse_server_header() {
+        let cfg = serde_yaml::from_str::<SrvConfig>("keep_alive: 75").unwrap();
+        assert_eq!(cfg.get_server_header(), Some(SERVER_HEADER_DEFAULT));
+
+        let cfg = serde_yaml::from_str::<SrvConfig>("server_header: false").unwrap();
+        assert_eq!(cfg.server_header, OptBoolObj::Bool(false));
+        assert_eq!(cfg.get_server_header(), None);
+
+        let cfg = serde_yaml::from_str::<SrvConfig>("server_header: tiles").unwrap();
+        assert_eq!(cfg.server_header, OptBoolObj::Object("tiles".to_string()));
+        assert_eq!(cfg.get_server_header(), Some("tiles"));
+    }
+
+    #[test]
+    fn parse_health_path() {
+        let cfg = serde_yaml::from_str::<SrvConfig>("keep_alive: 75").unwrap();
+        assert_eq!(cfg.get_health_paths(), vec!["/health"]);
+        assert_eq!(cfg.get_health_ids(), vec!["health"]);
+
+        let cfg = serde_yaml::from_str::<SrvConfig>("health_path: /healthz").unwrap();
+        assert_eq!(cfg.get_health_paths(), vec!["/healthz"]);
+
+        let cfg =
+            serde_yaml::from_str::<SrvConfig>("health_path: [/health, /probes/live]").unwrap();
+        assert_eq!(cfg.get_health_paths(), vec!["/health", "/probes/live"]);
+        assert_eq!(cfg.get_health_ids(), vec!["health"]);
+    }
 }
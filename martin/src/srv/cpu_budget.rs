@@ -0,0 +1,169 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+/// Share the CPU between the tile compression and the glyph rendering, so that a spike of one
+/// does not starve the other. The permits are split between the two workloads by their weights.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CpuBudgetConfig {
+    /// Number of CPU-heavy jobs that may run at the same time. Defaults to the number of CPUs.
+    pub permits: Option<usize>,
+    /// Relative share of the permits for compressing and transcoding the tiles [default: 1]
+    pub tile_weight: Option<u32>,
+    /// Relative share of the permits for rendering the glyphs [default: 1]
+    pub glyph_weight: Option<u32>,
+}
+
+impl CpuBudgetConfig {
+    /// Number of permits of the tile and the glyph workloads
+    fn permits(&self) -> (usize, usize) {
+        split_permits(
+            self.permits.unwrap_or_else(num_cpus::get),
+            self.tile_weight.unwrap_or(1),
+            self.glyph_weight.unwrap_or(1),
+        )
+    }
+
+    /// Number of glyph ranges that may be rendered at the same time
+    #[must_use]
+    pub fn glyph_permits(&self) -> usize {
+        self.permits().1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Workload {
+    /// Compressing and transcoding the tiles
+    Tiles,
+    /// Rendering the glyph ranges
+    Glyphs,
+}
+
+#[derive(Clone, Debug)]
+pub struct CpuBudget {
+    tiles: Arc<Semaphore>,
+    glyphs: Arc<Semaphore>,
+}
+
+impl CpuBudget {
+    #[must_use]
+    pub fn new(config: &CpuBudgetConfig) -> Self {
+        let (tiles, glyphs) = config.permits();
+        Self {
+            tiles: Arc::new(Semaphore::new(tiles)),
+            glyphs: Arc::new(Semaphore::new(glyphs)),
+        }
+    }
+
+    /// Run the job once a permit of its workload is available
+    pub async fn run<F: Future>(&self, workload: Workload, job: F) -> F::Output {
+        let semaphore = match workload {
+            Workload::Tiles => &self.tiles,
+            Workload::Glyphs => &self.glyphs,
+        };
+        // The semaphores are never closed
+        let _permit = semaphore.acquire().await.ok();
+        job.await
+    }
+}
+
+/// Run the job within the budget of its workload, or right away if no budget is configured
+pub async fn run_in_budget<F: Future>(
+    budget: Option<&CpuBudget>,
+    workload: Workload,
+    job: F,
+) -> F::Output {
+    match budget {
+        Some(budget) => budget.run(workload, job).await,
+        None => job.await,
+    }
+}
+
+/// Split the permits proportionally to the weights, giving each workload at least one permit
+fn split_permits(permits: usize, tile_weight: u32, glyph_weight: u32) -> (usize, usize) {
+    let total = u64::from(tile_weight) + u64::from(glyph_weight);
+    let tiles = match (permits as u64 * u64::from(tile_weight) + total / 2).checked_div(total) {
+        Some(share) => usize::try_from(share).unwrap_or(permits),
+        None => permits / 2,
+    };
+    let tiles = tiles.clamp(1, permits.saturating_sub(1).max(1));
+    (tiles, permits.saturating_sub(tiles).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[test]
+    fn test_split_permits() {
+        assert_eq!(split_permits(4, 3, 1), (3, 1));
+        assert_eq!(split_permits(8, 1, 1), (4, 4));
+        assert_eq!(split_permits(10, 1, 4), (2, 8));
+        assert_eq!(split_permits(4, 1, 0), (3, 1));
+        assert_eq!(split_permits(4, 0, 1), (1, 3));
+        assert_eq!(split_permits(4, 0, 0), (2, 2));
+        assert_eq!(split_permits(1, 1, 1), (1, 1));
+        assert_eq!(split_permits(0, 1, 1), (1, 1));
+    }
+
+    #[test]
+    fn test_glyph_permits() {
+        let config = CpuBudgetConfig {
+            permits: Some(10),
+            tile_weight: Some(1),
+            glyph_weight: Some(4),
+        };
+        assert_eq!(config.glyph_permits(), 8);
+    }
+
+    #[actix_rt::test]
+    async fn test_weighted_progress() {
+        let budget = CpuBudget::new(&CpuBudgetConfig {
+            permits: Some(4),
+            tile_weight: Some(3),
+            glyph_weight: Some(1),
+        });
+        let running = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let peak = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let completed = Mutex::new(Vec::new());
+
+        // Saturate both workloads with the jobs of the same duration
+        let jobs = (0..32).map(|i| {
+            let workload = if i % 2 == 0 {
+                Workload::Tiles
+            } else {
+                Workload::Glyphs
+            };
+            let (running, peak, completed) = (&running, &peak, &completed);
+            budget.run(workload, async move {
+                let idx = workload as usize;
+                let now = running[idx].fetch_add(1, Ordering::SeqCst) + 1;
+                peak[idx].fetch_max(now, Ordering::SeqCst);
+                actix_rt::time::sleep(Duration::from_millis(10)).await;
+                running[idx].fetch_sub(1, Ordering::SeqCst);
+                completed.lock().unwrap().push(workload);
+            })
+        });
+        join_all(jobs).await;
+
+        assert_eq!(peak[Workload::Tiles as usize].load(Ordering::SeqCst), 3);
+        assert_eq!(peak[Workload::Glyphs as usize].load(Ordering::SeqCst), 1);
+
+        // Both workloads progress at the same time, three tiles for each glyph range
+        let completed = completed.into_inner().unwrap();
+        let first_round = &completed[..4];
+        let count = |w| first_round.iter().filter(|v| **v == w).count();
+        assert_eq!(count(Workload::Tiles), 3);
+        assert_eq!(count(Workload::Glyphs), 1);
+        assert_eq!(completed.len(), 32);
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use moka::future::Cache;
+
+use crate::TileCoord;
+
+/// Maximum number of empty tile coordinates remembered at the same time
+const EMPTY_TILES_CAPACITY: u64 = 100_000;
+
+/// Remembers the tile coordinates that were recently empty for a short time, so that the
+/// repeated requests for them do not reach the sources. Only used if the main cache is disabled.
+#[derive(Clone, Debug)]
+pub struct EmptyTiles(Cache<EmptyTileKey, ()>);
+
+/// Everything that may change the content of a tile besides the sources' data
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EmptyTileKey {
+    pub source_ids: String,
+    pub xyz: TileCoord,
+    pub query: String,
+    pub partition: Option<String>,
+}
+
+impl EmptyTiles {
+    #[must_use]
+    pub fn new(ttl: Duration) -> Self {
+        Self(
+            Cache::builder()
+                .name("empty_tiles")
+                .max_capacity(EMPTY_TILES_CAPACITY)
+                .time_to_live(ttl)
+                .build(),
+        )
+    }
+
+    #[must_use]
+    pub fn contains(&self, key: &EmptyTileKey) -> bool {
+        self.0.contains_key(key)
+    }
+
+    pub async fn insert(&self, key: EmptyTileKey) {
+        self.0.insert(key, ()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(z: u8) -> EmptyTileKey {
+        EmptyTileKey {
+            source_ids: "src".to_string(),
+            xyz: TileCoord { z, x: 0, y: 0 },
+            query: String::new(),
+            partition: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_empty_tiles_expire() {
+        let empty = EmptyTiles::new(Duration::from_millis(50));
+        empty.insert(key(0)).await;
+        assert!(empty.contains(&key(0)));
+        assert!(!empty.contains(&key(1)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!empty.contains(&key(0)));
+    }
+}
@@ -1,12 +1,22 @@
 use std::string::ToString;
 
 use actix_web::error::{ErrorBadRequest, ErrorNotFound};
-use actix_web::web::{Data, Path};
-use actix_web::{middleware, route, HttpResponse, Result as ActixResult};
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::rt::task::spawn_blocking;
+use actix_web::web::{Data, Path, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use log::trace;
+use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::Deserialize;
 
-use crate::fonts::{FontError, FontSources};
+use crate::fonts::{CatalogFontEntry, FontCatalog, FontError, FontSources};
+use crate::srv::cpu_budget::{run_in_budget, Workload};
 use crate::srv::server::map_internal_error;
+use crate::srv::tiles::{encode, encoded_response, negotiate_encoding, to_encoding};
+use crate::srv::{CpuBudget, SrvConfig};
+use crate::utils::cache::get_or_insert_cached_value;
+use crate::utils::{CacheKey, CacheValue, OptMainCache};
+use crate::Tile;
 
 #[derive(Deserialize, Debug)]
 struct FontRequest {
@@ -15,19 +25,111 @@ struct FontRequest {
     end: u32,
 }
 
-#[route(
-    "/font/{fontstack}/{start}-{end}",
-    method = "GET",
-    wrap = "middleware::Compress::default()"
-)]
+/// Optional filters of the font catalog. All given filters must match, and the matching is case-insensitive.
+#[derive(Deserialize, Debug, Default)]
+struct FontCatalogQuery {
+    /// Font family, e.g. `Overpass Mono`
+    family: Option<String>,
+    /// Font style, e.g. `Bold`
+    style: Option<String>,
+    /// Substring of the font name
+    q: Option<String>,
+}
+
+impl FontCatalogQuery {
+    fn matches(&self, name: &str, entry: &CatalogFontEntry) -> bool {
+        let eq = |filter: &Option<String>, value: Option<&str>| {
+            filter
+                .as_ref()
+                .map_or(true, |f| value.is_some_and(|v| v.eq_ignore_ascii_case(f)))
+        };
+        eq(&self.family, Some(&entry.family))
+            && eq(&self.style, entry.style.as_deref())
+            && self
+                .q
+                .as_ref()
+                .map_or(true, |q| name.to_lowercase().contains(&q.to_lowercase()))
+    }
+
+    /// Keep only the matching fonts, sorted by their names
+    fn filter(&self, catalog: FontCatalog) -> FontCatalog {
+        catalog
+            .into_iter()
+            .filter(|(name, entry)| self.matches(name, entry))
+            .collect()
+    }
+}
+
+/// Return the catalog of the fonts, optionally filtered with the `family`, `style`, and `q` query parameters
+#[route("/font", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_font_catalog(
+    query: Query<FontCatalogQuery>,
+    fonts: Data<FontSources>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(query.filter(fonts.get_catalog()))
+}
+
+/// Return just the sorted list of the font ids, without the detailed catalog entries
+#[route("/font/ids", method = "GET", method = "HEAD")]
 #[allow(clippy::unused_async)]
-async fn get_font(path: Path<FontRequest>, fonts: Data<FontSources>) -> ActixResult<HttpResponse> {
-    let data = fonts
-        .get_font_range(&path.fontstack, path.start, path.end)
-        .map_err(map_font_error)?;
-    Ok(HttpResponse::Ok()
-        .content_type("application/x-protobuf")
-        .body(data))
+async fn get_font_ids(fonts: Data<FontSources>) -> HttpResponse {
+    HttpResponse::Ok().json(fonts.get_catalog().into_keys().collect::<Vec<_>>())
+}
+
+/// Return the sorted contiguous spans of the codepoints available in a font, e.g. `[[32,126],[160,383]]`,
+/// so that the clients can skip the empty glyph ranges
+#[route("/font/{fontstack}/coverage", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_font_coverage(
+    path: Path<String>,
+    fonts: Data<FontSources>,
+) -> ActixResult<HttpResponse> {
+    let spans = fonts.get_coverage(&path).map_err(map_font_error)?;
+    Ok(HttpResponse::Ok().json(spans))
+}
+
+/// Glyphs are compressed the same way as the tiles, unless `disable_compression` is set.
+/// Each encoding of a glyph range is cached separately.
+#[route("/font/{fontstack}/{start}-{end}", method = "GET")]
+async fn get_font(
+    req: HttpRequest,
+    path: Path<FontRequest>,
+    fonts: Data<FontSources>,
+    srv_config: Data<SrvConfig>,
+    cache: Data<OptMainCache>,
+    cpu_budget: Option<Data<CpuBudget>>,
+) -> ActixResult<HttpResponse> {
+    let enc = negotiate_encoding(&req, &srv_config)?;
+    let encoding = enc.and_then(to_encoding).unwrap_or(Encoding::Uncompressed);
+    let cpu_budget = cpu_budget.as_ref().map(Data::get_ref);
+
+    let data = get_or_insert_cached_value!(
+        cache.as_ref().as_ref(),
+        CacheValue::Tile,
+        run_in_budget(cpu_budget, Workload::Glyphs, async {
+            // Rendering blocks until all glyphs are done, so keep it off the server threads
+            let (fonts, fontstack) = (fonts.clone(), path.fontstack.clone());
+            let (start, end) = (path.start, path.end);
+            let data = spawn_blocking(move || fonts.get_font_range(&fontstack, start, end))
+                .await
+                .map_err(map_internal_error)?
+                .map_err(map_font_error)?;
+            // Glyphs are protobuf messages, so they compress the same way as the MVT tiles
+            let glyphs = Tile::new(data, TileInfo::new(Format::Mvt, Encoding::Uncompressed));
+            ActixResult::<_>::Ok(match enc {
+                Some(enc) => encode(glyphs, enc)?.data,
+                None => glyphs.data,
+            })
+        }),
+        CacheKey::Glyphs(path.fontstack.clone(), path.start, path.end, encoding)
+    )?;
+
+    let mut response = encoded_response("application/x-protobuf", encoding);
+    if let Some(cache_control) = fonts.get_cache_control(&path.fontstack) {
+        response.insert_header((CACHE_CONTROL, cache_control.to_string()));
+    }
+    Ok(response.body(data))
 }
 
 pub fn map_font_error(e: FontError) -> actix_web::Error {
@@ -38,7 +140,196 @@ pub fn map_font_error(e: FontError) -> actix_web::Error {
         InvalidFontRangeStartEnd(_, _)
         | InvalidFontRangeStart(_)
         | InvalidFontRangeEnd(_)
-        | InvalidFontRange(_, _) => ErrorBadRequest(e.to_string()),
+        | InvalidFontRange(_, _)
+        | FontRangeTooLarge(..)
+        | FontRangeBeyondUnicode(..) => ErrorBadRequest(e.to_string()),
         _ => map_internal_error(e),
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use pbf_font_tools::protobuf::Message as _;
+    use pbf_font_tools::Glyphs;
+
+    use super::*;
+    use crate::file_config::FontConfigSrc;
+    use crate::fonts::FontSourcesOptions;
+    use crate::utils::{decode_gzip, MainCache};
+    use crate::OptOneMany;
+
+    /// Fonts of a font file or a directory of the test fixtures
+    pub(crate) fn test_fonts(path: &str) -> FontSources {
+        let mut config = OptOneMany::One(FontConfigSrc::Path(PathBuf::from(path)));
+        FontSources::resolve(&mut config, FontSourcesOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_font_catalog_filter() {
+        let entry = |family: &str, style: &str| CatalogFontEntry {
+            family: family.to_string(),
+            style: Some(style.to_string()),
+            ..CatalogFontEntry::default()
+        };
+        let catalog = FontCatalog::from([
+            ("Noto Sans Bold".to_string(), entry("Noto Sans", "Bold")),
+            (
+                "Noto Sans Regular".to_string(),
+                entry("Noto Sans", "Regular"),
+            ),
+            (
+                "Overpass Mono Bold".to_string(),
+                entry("Overpass Mono", "Bold"),
+            ),
+            (
+                "Overpass Mono SemiBold".to_string(),
+                entry("Overpass Mono", "SemiBold"),
+            ),
+        ]);
+        let names = |query: FontCatalogQuery| -> Vec<String> {
+            query.filter(catalog.clone()).into_keys().collect()
+        };
+
+        assert_eq!(names(FontCatalogQuery::default()).len(), 4);
+        let style = Some("Bold".to_string());
+        assert_eq!(
+            names(FontCatalogQuery {
+                style,
+                ..FontCatalogQuery::default()
+            }),
+            vec!["Noto Sans Bold", "Overpass Mono Bold"]
+        );
+        let family = Some("overpass mono".to_string());
+        assert_eq!(
+            names(FontCatalogQuery {
+                family,
+                ..FontCatalogQuery::default()
+            }),
+            vec!["Overpass Mono Bold", "Overpass Mono SemiBold"]
+        );
+        let q = Some("sans reg".to_string());
+        assert_eq!(
+            names(FontCatalogQuery {
+                q,
+                ..FontCatalogQuery::default()
+            }),
+            vec!["Noto Sans Regular"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_font_catalog() {
+        let fonts = test_fonts("../tests/fixtures/fonts");
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(fonts))
+                .service(get_font_catalog),
+        )
+        .await;
+
+        for (query, expected) in [
+            ("", vec!["Overpass Mono Light", "Overpass Mono Regular"]),
+            ("?style=light", vec!["Overpass Mono Light"]),
+            (
+                "?family=Overpass%20Mono&q=regular",
+                vec!["Overpass Mono Regular"],
+            ),
+            ("?style=Bold", vec![]),
+        ] {
+            let req = TestRequest::get()
+                .uri(&format!("/font{query}"))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert!(response.status().is_success());
+            let catalog: FontCatalog = serde_json::from_slice(&read_body(response).await).unwrap();
+            assert_eq!(catalog.into_keys().collect::<Vec<_>>(), expected, "{query}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_font_ids() {
+        let fonts = test_fonts("../tests/fixtures/fonts");
+        let app = init_service(App::new().app_data(Data::new(fonts)).service(get_font_ids)).await;
+
+        let req = TestRequest::get().uri("/font/ids").to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        let ids: Vec<String> = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(ids, vec!["Overpass Mono Light", "Overpass Mono Regular"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_font_coverage() {
+        let fonts = test_fonts("../tests/fixtures/fonts/overpass-mono-regular.ttf");
+        let entry = fonts.get_catalog()["Overpass Mono Regular"].clone();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(fonts))
+                .service(get_font_coverage),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/font/Overpass%20Mono%20Regular/coverage")
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        let spans: Vec<(usize, usize)> =
+            serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(spans.first().unwrap().0, entry.start);
+        assert_eq!(spans.last().unwrap().1, entry.end);
+        let glyphs: usize = spans.iter().map(|(first, last)| last - first + 1).sum();
+        assert_eq!(glyphs, entry.glyphs);
+        // The spans are sorted, and do not touch or overlap
+        assert!(spans.iter().all(|(first, last)| first <= last));
+        assert!(spans.windows(2).all(|v| v[0].1 + 1 < v[1].0));
+
+        let req = TestRequest::get()
+            .uri("/font/Unknown%20Font/coverage")
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_font_encoding() {
+        let fonts = test_fonts("../tests/fixtures/fonts/overpass-mono-regular.ttf");
+        let cache: OptMainCache = Some(MainCache::builder().build());
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(fonts))
+                .app_data(Data::new(SrvConfig::default()))
+                .app_data(Data::new(cache))
+                .service(get_font),
+        )
+        .await;
+
+        // The second gzip request is served from the cache
+        for accept_enc in ["gzip", "gzip", "identity"] {
+            let req = TestRequest::get()
+                .uri("/font/Overpass%20Mono%20Regular/0-255")
+                .insert_header((ACCEPT_ENCODING, accept_enc))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert!(response.status().is_success());
+            let content_enc = response.headers().get(CONTENT_ENCODING).cloned();
+            let body = read_body(response).await;
+            let data = if accept_enc == "gzip" {
+                assert_eq!(content_enc.unwrap(), "gzip");
+                decode_gzip(&body).unwrap()
+            } else {
+                assert!(content_enc.is_none());
+                body.to_vec()
+            };
+            let glyphs = Glyphs::parse_from_bytes(&data).unwrap();
+            assert_eq!(glyphs.stacks[0].name(), "Overpass Mono Regular");
+            assert!(!glyphs.stacks[0].glyphs.is_empty());
+        }
+    }
+}
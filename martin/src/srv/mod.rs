@@ -1,14 +1,36 @@
 mod config;
-pub use config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
+pub use config::{
+    CachePartition, CatalogName, EncodingFallback, IndexConfig, SrvConfig, HEALTH_PATH_DEFAULT,
+    KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT, MAX_PAYLOAD_SIZE_DEFAULT,
+    MAX_SOURCES_PER_REQUEST_DEFAULT, SHUTDOWN_TIMEOUT_DEFAULT,
+};
+
+mod cpu_budget;
+pub use cpu_budget::{CpuBudget, CpuBudgetConfig};
+
+mod empty_tiles;
+pub use empty_tiles::{EmptyTileKey, EmptyTiles};
 
 #[cfg(feature = "fonts")]
 mod fonts;
 
+mod openapi;
+pub use openapi::generate_openapi;
+
+mod rate_limit;
+pub use rate_limit::{RateLimit, RateLimitConfig, RateLimiter};
+
 mod server;
-pub use server::{new_server, router, Catalog, RESERVED_KEYWORDS};
+pub use server::{configure_router, new_server, router, Catalog, ServerStatus, RESERVED_KEYWORDS};
+
+mod shutdown;
+pub use shutdown::{listen_for_shutdown, shutdown_channel, ShutdownSender};
+
+mod style;
+pub use style::generate_style;
 
 mod tiles;
-pub use tiles::{DynTileSource, TileRequest};
+pub use tiles::{check_response_headers, check_webp_sources, DynTileSource, TileRequest};
 
 mod tiles_info;
 pub use tiles_info::{merge_tilejson, SourceIDsRequest};
@@ -0,0 +1,380 @@
+use actix_web::http::header::ContentType;
+use actix_web::web::Data;
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde_json::{json, Map, Value};
+
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::negotiated_response;
+use crate::srv::SrvConfig;
+use crate::Tile;
+
+/// Return an [OpenAPI](https://spec.openapis.org/oas/v3.0.3) document describing the HTTP API of this server.
+/// It is compressed the same way as the catalog.
+#[route("/openapi.json", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_openapi(req: HttpRequest, srv_config: Data<SrvConfig>) -> ActixResult<HttpResponse> {
+    let data = serde_json::to_vec(&generate_openapi(&srv_config)).map_err(map_internal_error)?;
+    let json = Tile::new(data, TileInfo::new(Format::Json, Encoding::Uncompressed));
+    negotiated_response(&req, &srv_config, ContentType::json(), json)
+}
+
+/// A path parameter with a description
+fn path_param(name: &str, schema: &Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": schema,
+        "description": description,
+    })
+}
+
+fn source_ids_param() -> Value {
+    path_param(
+        "source_ids",
+        &json!({ "type": "string" }),
+        "One or more comma-separated source IDs, e.g. `a` or `a,b,c`",
+    )
+}
+
+/// A `GET` operation returning a response with the given content type and schema
+fn get_op(summary: &str, params: &[Value], content_type: &str, schema: &Value) -> Value {
+    json!({
+        "get": {
+            "summary": summary,
+            "parameters": params,
+            "responses": {
+                "200": {
+                    "description": "OK",
+                    "content": { content_type: { "schema": schema } },
+                },
+                "400": { "description": "Invalid request parameters" },
+                "404": { "description": "Not found" },
+            },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+/// Generate the `OpenAPI` document of the routes that are enabled in this build and configuration
+#[must_use]
+#[allow(clippy::too_many_lines)]
+pub fn generate_openapi(srv_config: &SrvConfig) -> Value {
+    let binary = json!({ "type": "string", "format": "binary" });
+    let object = json!({ "type": "object" });
+
+    let mut paths = Map::new();
+    for path in srv_config.get_health_paths() {
+        paths.insert(
+            path.to_string(),
+            get_op(
+                "Server health check",
+                &[],
+                "text/plain",
+                &json!({ "type": "string" }),
+            ),
+        );
+    }
+    paths.insert(
+        "/status".to_string(),
+        get_op(
+            "Usage of the server resources, e.g. the Postgres connection pools",
+            &[],
+            "application/json",
+            &object,
+        ),
+    );
+    paths.insert(
+        "/style.json".to_string(),
+        get_op(
+            "A MapLibre style showing all tile sources",
+            &[],
+            "application/json",
+            &object,
+        ),
+    );
+    if srv_config.shutdown_token.is_some() {
+        paths.insert(
+            "/shutdown".to_string(),
+            json!({
+                "post": {
+                    "summary": "Gracefully shut down the server",
+                    "security": [{ "bearer": [] }],
+                    "responses": {
+                        "202": { "description": "The server is shutting down" },
+                        "401": { "description": "Missing or invalid shutdown token" },
+                    },
+                },
+            }),
+        );
+    }
+    paths.insert(
+        "/catalog".to_string(),
+        get_op(
+            "List of all sources",
+            &[],
+            "application/json",
+            &schema_ref("Catalog"),
+        ),
+    );
+    paths.insert(
+        "/{source_ids}".to_string(),
+        get_op(
+            "TileJSON of a source, or of a composite source",
+            &[
+                source_ids_param(),
+                json!({
+                    "name": "tilejson",
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string", "enum": ["2.2.0", "3.0.0"] },
+                    "description": "TileJSON version of the response",
+                }),
+            ],
+            "application/json",
+            &schema_ref("TileJSON"),
+        ),
+    );
+    paths.insert(
+        "/{source_ids}/{z}/{x}/{y}".to_string(),
+        get_op(
+            "A tile of a source, or of a composite source",
+            &[
+                source_ids_param(),
+                path_param(
+                    "z",
+                    &json!({ "type": "integer", "minimum": 0 }),
+                    "Zoom level",
+                ),
+                path_param(
+                    "x",
+                    &json!({ "type": "integer", "minimum": 0 }),
+                    "Tile column",
+                ),
+                path_param("y", &json!({ "type": "integer", "minimum": 0 }), "Tile row"),
+            ],
+            "application/octet-stream",
+            &binary,
+        ),
+    );
+
+    #[cfg(feature = "sprites")]
+    paths.insert(
+        "/sprite/{source_ids}.png".to_string(),
+        get_op(
+            "Sprite sheet image of one or more sprite sources",
+            &[source_ids_param()],
+            "image/png",
+            &binary,
+        ),
+    );
+
+    #[cfg(feature = "sprites")]
+    paths.insert(
+        "/sprite/{source_ids}.json".to_string(),
+        get_op(
+            "Sprite sheet index of one or more sprite sources",
+            &[source_ids_param()],
+            "application/json",
+            &object,
+        ),
+    );
+
+    #[cfg(feature = "fonts")]
+    paths.insert(
+        "/font".to_string(),
+        get_op(
+            "List of the fonts, optionally filtered",
+            &["family", "style", "q"].map(|name| {
+                json!({
+                    "name": name,
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string" },
+                    "description": if name == "q" {
+                        "Case-insensitive substring of the font name"
+                    } else {
+                        "Case-insensitive exact match"
+                    },
+                })
+            }),
+            "application/json",
+            &object,
+        ),
+    );
+
+    #[cfg(feature = "fonts")]
+    paths.insert(
+        "/font/ids".to_string(),
+        get_op(
+            "Sorted list of the font ids",
+            &[],
+            "application/json",
+            &json!({ "type": "array", "items": { "type": "string" } }),
+        ),
+    );
+
+    #[cfg(feature = "fonts")]
+    paths.insert(
+        "/font/{fontstack}/coverage".to_string(),
+        get_op(
+            "Sorted spans of the codepoints available in a font, as `[first, last]` pairs",
+            &[path_param(
+                "fontstack",
+                &json!({ "type": "string" }),
+                "Font name",
+            )],
+            "application/json",
+            &json!({
+                "type": "array",
+                "items": { "type": "array", "items": { "type": "integer" } },
+            }),
+        ),
+    );
+
+    #[cfg(feature = "fonts")]
+    paths.insert(
+        "/font/{fontstack}/{start}-{end}".to_string(),
+        get_op(
+            "Glyphs of a font, or of a composite font",
+            &[
+                path_param(
+                    "fontstack",
+                    &json!({ "type": "string" }),
+                    "One or more comma-separated font names",
+                ),
+                path_param(
+                    "start",
+                    &json!({ "type": "integer", "minimum": 0 }),
+                    "First codepoint of the range, a multiple of 256",
+                ),
+                path_param(
+                    "end",
+                    &json!({ "type": "integer", "minimum": 255 }),
+                    "Last codepoint of the range, `start + 255`",
+                ),
+            ],
+            "application/x-protobuf",
+            &binary,
+        ),
+    );
+
+    let mut servers = Vec::new();
+    if let Some(base_path) = &srv_config.base_path {
+        servers.push(json!({ "url": base_path }));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Martin",
+            "description": "Blazing fast and lightweight tile server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": servers,
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+            "schemas": {
+                "Catalog": {
+                    "type": "object",
+                    "properties": {
+                        "tiles": {
+                            "type": "object",
+                            "additionalProperties": schema_ref("CatalogSourceEntry"),
+                        },
+                        "sprites": { "type": "object" },
+                        "fonts": { "type": "object" },
+                    },
+                },
+                "CatalogSourceEntry": {
+                    "type": "object",
+                    "required": ["content_type"],
+                    "properties": {
+                        "content_type": { "type": "string" },
+                        "content_encoding": { "type": "string" },
+                        "name": { "type": "string" },
+                        "description": { "type": "string" },
+                        "attribution": { "type": "string" },
+                        "extent": { "type": "integer" },
+                    },
+                },
+                "TileJSON": {
+                    "type": "object",
+                    "description": "See https://github.com/mapbox/tilejson-spec",
+                    "required": ["tilejson", "tiles"],
+                    "properties": {
+                        "tilejson": { "type": "string" },
+                        "tiles": { "type": "array", "items": { "type": "string" } },
+                        "name": { "type": "string" },
+                        "description": { "type": "string" },
+                        "attribution": { "type": "string" },
+                        "minzoom": { "type": "integer" },
+                        "maxzoom": { "type": "integer" },
+                        "bounds": { "type": "array", "items": { "type": "number" } },
+                        "center": { "type": "array", "items": { "type": "number" } },
+                        "vector_layers": { "type": "array", "items": { "type": "object" } },
+                        "extent": { "type": "integer" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    use super::*;
+    use crate::OptOneMany;
+
+    #[actix_rt::test]
+    async fn test_openapi() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(SrvConfig::default()))
+                .service(get_openapi),
+        )
+        .await;
+        let req = TestRequest::get().uri("/openapi.json").to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        let body: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+
+        assert_eq!(body["openapi"], "3.0.3");
+        let tile = &body["paths"]["/{source_ids}/{z}/{x}/{y}"]["get"];
+        let params: Vec<_> = tile["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(params, vec!["source_ids", "z", "x", "y"]);
+        assert!(body["paths"]["/catalog"].is_object());
+        assert!(body["components"]["schemas"]["TileJSON"].is_object());
+        assert!(body["servers"].as_array().unwrap().is_empty());
+        assert!(body["paths"]["/health"].is_object());
+        assert!(body["paths"]["/status"].is_object());
+        assert!(body["paths"]["/style.json"].is_object());
+        assert!(body["paths"]["/shutdown"].is_null());
+
+        let body = generate_openapi(&SrvConfig {
+            base_path: Some("/tiles".to_string()),
+            health_path: OptOneMany::One("/healthz".to_string()),
+            shutdown_token: Some("secret".to_string()),
+            ..SrvConfig::default()
+        });
+        assert_eq!(body["servers"][0]["url"], "/tiles");
+        assert!(body["paths"]["/health"].is_null());
+        assert!(body["paths"]["/healthz"].is_object());
+        assert!(body["paths"]["/shutdown"]["post"].is_object());
+    }
+}
@@ -0,0 +1,319 @@
+use std::future::{ready, Ready};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{Error, HttpResponse};
+use futures::future::LocalBoxFuture;
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+
+use crate::srv::RESERVED_KEYWORDS;
+
+/// Maximum number of clients tracked for each route group. The least recently seen clients
+/// are forgotten first, and start again with a full bucket.
+const MAX_TRACKED_CLIENTS: u64 = 10_000;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Name of the header with the real client IP, set by a trusted reverse proxy, e.g. `X-Forwarded-For`.
+    /// The last address of the header is used, because the earlier ones could be sent by the client itself.
+    /// If not set, the IP address of the connected peer is used.
+    pub ip_header: Option<String>,
+    /// Limit for the tile requests
+    pub tiles: Option<RateLimit>,
+    /// Limit for the font glyph requests
+    pub fonts: Option<RateLimit>,
+    /// Limit for the catalog requests
+    pub catalog: Option<RateLimit>,
+}
+
+/// Allow up to `requests` requests per `seconds` seconds from a single client.
+/// Short bursts of up to `requests` are allowed, after which the requests are spread evenly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub seconds: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum RouteGroup {
+    Tiles,
+    Fonts,
+    Catalog,
+}
+
+impl RouteGroup {
+    fn from_path(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["catalog"] => Some(Self::Catalog),
+            // matches the /font/{fontstack}/{start}-{end} route
+            ["font", _, range]
+                if range.split_once('-').is_some_and(|(start, end)| {
+                    start.parse::<u32>().is_ok() && end.parse::<u32>().is_ok()
+                }) =>
+            {
+                Some(Self::Fonts)
+            }
+            // matches the /{source_ids}/{z}/{x}/{y} route
+            [source_ids, z, x, y]
+                if !RESERVED_KEYWORDS.contains(source_ids)
+                    && z.parse::<u8>().is_ok()
+                    && x.parse::<u32>().is_ok()
+                    && y.parse::<u32>().is_ok() =>
+            {
+                Some(Self::Tiles)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.requests),
+            updated: now,
+        }
+    }
+
+    /// Take a token from the bucket, or return how long to wait until one becomes available
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(limit.requests);
+        let rate = capacity / f64::from(limit.seconds.max(1));
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        } else {
+            Err(Duration::from_secs(u64::from(limit.seconds)))
+        }
+    }
+}
+
+/// The buckets of all clients of a single route group
+#[derive(Clone, Debug)]
+struct GroupLimiter {
+    limit: RateLimit,
+    buckets: Cache<IpAddr, Arc<Mutex<Bucket>>>,
+}
+
+impl GroupLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            // A bucket that was not used for `seconds` is full again, so it can be forgotten
+            buckets: Cache::builder()
+                .max_capacity(MAX_TRACKED_CLIENTS)
+                .time_to_idle(Duration::from_secs(u64::from(limit.seconds.max(1))))
+                .build(),
+        }
+    }
+
+    fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.buckets
+            .get_with(client, || {
+                Arc::new(Mutex::new(Bucket::new(self.limit, now)))
+            })
+            .lock()
+            .unwrap()
+            .take(self.limit, now)
+    }
+}
+
+/// A token-bucket rate limiting middleware. Clones share the same state,
+/// so a single instance should be created for all server workers.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    ip_header: Option<Arc<str>>,
+    tiles: Option<GroupLimiter>,
+    fonts: Option<GroupLimiter>,
+    catalog: Option<GroupLimiter>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            ip_header: config.ip_header.map(Arc::from),
+            tiles: config.tiles.map(GroupLimiter::new),
+            fonts: config.fonts.map(GroupLimiter::new),
+            catalog: config.catalog.map(GroupLimiter::new),
+        }
+    }
+
+    fn get_limiter(&self, group: RouteGroup) -> Option<&GroupLimiter> {
+        match group {
+            RouteGroup::Tiles => self.tiles.as_ref(),
+            RouteGroup::Fonts => self.fonts.as_ref(),
+            RouteGroup::Catalog => self.catalog.as_ref(),
+        }
+    }
+
+    fn get_client_ip(&self, req: &ServiceRequest) -> IpAddr {
+        if let Some(header) = &self.ip_header {
+            // The proxy appends the address it sees, anything before it comes from the client
+            if let Some(ip) = req
+                .headers()
+                .get(header.as_ref())
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+                .and_then(|v| v.trim().parse().ok())
+            {
+                return ip;
+            }
+        }
+        req.peer_addr()
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip())
+    }
+
+    /// Check if the request is allowed, or return how long the client should wait before retrying
+    fn check(&self, req: &ServiceRequest) -> Result<(), Duration> {
+        let Some(limiter) = RouteGroup::from_path(req.path()).and_then(|g| self.get_limiter(g))
+        else {
+            return Ok(());
+        };
+        limiter.check(self.get_client_ip(req))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service,
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: S,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Err(wait) = self.limiter.check(&req) {
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            let response = HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, retry_after.to_string()))
+                .finish()
+                .map_into_right_body();
+            let (req, _) = req.into_parts();
+            return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_rate_limit() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            ip_header: Some("X-Forwarded-For".to_string()),
+            catalog: Some(RateLimit {
+                requests: 2,
+                seconds: 60,
+            }),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/catalog", web::get().to(HttpResponse::Ok))
+                .route("/health", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let get = |path: &str, ip: &str| {
+            TestRequest::get()
+                .uri(path)
+                .insert_header(("X-Forwarded-For", format!("10.0.0.1, {ip}")))
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let response = call_service(&app, get("/catalog", "1.1.1.1")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = call_service(&app, get("/catalog", "1.1.1.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "30");
+
+        // other clients and route groups without limits are not affected
+        let response = call_service(&app, get("/catalog", "2.2.2.2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call_service(&app, get("/health", "1.1.1.1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // only the address added by the proxy counts, the client can't pick another bucket
+        let response = call_service(&app, get("/catalog", "1.1.1.1, 3.3.3.3")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call_service(&app, get("/catalog", "3.3.3.3, 1.1.1.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_route_group() {
+        assert_eq!(RouteGroup::from_path("/catalog"), Some(RouteGroup::Catalog));
+        assert_eq!(
+            RouteGroup::from_path("/font/Arial/0-255"),
+            Some(RouteGroup::Fonts)
+        );
+        assert_eq!(
+            RouteGroup::from_path("/src1,src2/0/0/0"),
+            Some(RouteGroup::Tiles)
+        );
+        assert_eq!(RouteGroup::from_path("/font/Arial/coverage"), None);
+        assert_eq!(RouteGroup::from_path("/sprite/a/b/c"), None);
+        assert_eq!(RouteGroup::from_path("/src/0/0/abc"), None);
+        assert_eq!(RouteGroup::from_path("/src/0/0"), None);
+    }
+}
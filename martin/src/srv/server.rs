@@ -1,36 +1,69 @@
 use std::future::Future;
+use std::io::{self, Write as _};
+use std::ops::Bound::{Excluded, Unbounded};
 use std::pin::Pin;
 use std::string::ToString;
+use std::sync::Arc;
 use std::time::Duration;
 
 use actix_cors::Cors;
 use actix_web::error::ErrorInternalServerError;
-use actix_web::http::header::CACHE_CONTROL;
+use actix_web::http::header::{
+    ContentEncoding, ContentType, ACCESS_CONTROL_ALLOW_METHODS, ALLOW, CACHE_CONTROL, LOCATION,
+    SERVER,
+};
 use actix_web::middleware::TrailingSlash;
-use actix_web::web::Data;
-use actix_web::{middleware, route, web, App, HttpResponse, HttpServer, Responder};
-use futures::TryFutureExt;
+use actix_web::web::{Bytes, Data};
+use actix_web::{
+    guard, middleware, route, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    Result as ActixResult,
+};
+use flate2::write::GzEncoder;
+use futures::{stream, Stream, StreamExt as _, TryFutureExt};
 #[cfg(feature = "lambda")]
 use lambda_web::{is_running_on_lambda, run_actix_on_lambda};
 use log::error;
+use martin_tile_utils::Encoding;
 use serde::{Deserialize, Serialize};
 
 use crate::config::ServerState;
 use crate::source::TileCatalog;
-use crate::srv::config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
-use crate::srv::tiles::get_tile;
+use crate::srv::config::{
+    IndexConfig, SrvConfig, HEALTH_PATH_DEFAULT, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT,
+    MAX_PAYLOAD_SIZE_DEFAULT, SHUTDOWN_TIMEOUT_DEFAULT,
+};
+use crate::srv::shutdown::{listen_for_shutdown, post_shutdown, shutdown_channel};
+use crate::srv::tiles::{encoded_response, get_tile, negotiate_encoding};
 use crate::srv::tiles_info::get_source_info;
-use crate::MartinError::BindingError;
+use crate::srv::{CatalogName, CpuBudget, EmptyTiles, RateLimiter};
+use crate::MartinError::{BindingError, IndexConfigConflict, IndexFileError};
 use crate::MartinResult;
 
 /// List of keywords that cannot be used as source IDs. Some of these are reserved for future use.
 /// Reserved keywords must never end in a "dot number" (e.g. ".1").
 /// This list is documented in the `docs/src/using.md` file, which should be kept in sync.
 pub const RESERVED_KEYWORDS: &[&str] = &[
-    "_", "catalog", "config", "font", "health", "help", "index", "manifest", "metrics", "refresh",
-    "reload", "sprite", "status",
+    "_",
+    "catalog",
+    "config",
+    "font",
+    "health",
+    "help",
+    "index",
+    "manifest",
+    "metrics",
+    "openapi.json",
+    "refresh",
+    "reload",
+    "shutdown",
+    "sprite",
+    "status",
+    "style.json",
 ];
 
+/// Number of the tile sources serialized into each chunk of the streamed catalog
+const CATALOG_CHUNK_SIZE: usize = 100;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Catalog {
     pub tiles: TileCatalog,
@@ -42,14 +75,74 @@ pub struct Catalog {
 
 impl Catalog {
     pub fn new(state: &ServerState) -> MartinResult<Self> {
+        Self::with_names(state, CatalogName::default())
+    }
+
+    /// Create the catalog, reporting the tile source names that are the same as their IDs as configured
+    pub fn with_names(state: &ServerState, names: CatalogName) -> MartinResult<Self> {
         Ok(Self {
-            tiles: state.tiles.get_catalog(),
+            tiles: state.tiles.get_catalog_with(names),
             #[cfg(feature = "sprites")]
             sprites: state.sprites.get_catalog()?,
             #[cfg(feature = "fonts")]
             fonts: state.fonts.get_catalog(),
         })
     }
+
+    /// Serialize the catalog as a stream of JSON chunks, keeping the memory usage bounded regardless
+    /// of the number of the tile sources. The concatenated chunks are the same as [`serde_json::to_vec`].
+    pub fn json_stream(self: Arc<Self>) -> impl Stream<Item = serde_json::Result<Bytes>> {
+        stream::unfold(Some(None), move |pos: Option<Option<String>>| {
+            let catalog = self.clone();
+            async move {
+                let last = pos?;
+                Some(match catalog.json_chunk(last.as_deref()) {
+                    Ok((chunk, next)) => (Ok(Bytes::from(chunk)), next.map(Some)),
+                    Err(e) => (Err(e), None),
+                })
+            }
+        })
+    }
+
+    /// Serialize the tile sources following the `last` one into a chunk of the catalog JSON,
+    /// and return the ID of the last serialized source, or `None` if the catalog is complete.
+    fn json_chunk(&self, last: Option<&str>) -> serde_json::Result<(Vec<u8>, Option<String>)> {
+        let mut chunk = Vec::new();
+        let tiles = if let Some(last) = last {
+            self.tiles.range::<str, _>((Excluded(last), Unbounded))
+        } else {
+            chunk.extend_from_slice(br#"{"tiles":{"#);
+            self.tiles.range::<str, _>(..)
+        };
+
+        let mut next = last;
+        let mut count = 0;
+        for (id, entry) in tiles.take(CATALOG_CHUNK_SIZE) {
+            if next.is_some() {
+                chunk.push(b',');
+            }
+            serde_json::to_writer(&mut chunk, id)?;
+            chunk.push(b':');
+            serde_json::to_writer(&mut chunk, entry)?;
+            next = Some(id.as_str());
+            count += 1;
+        }
+        if count == CATALOG_CHUNK_SIZE {
+            return Ok((chunk, next.map(ToString::to_string)));
+        }
+
+        // Serialize the rest of the catalog, e.g. `{"tiles":{},"fonts":{...}}`, and keep what follows the tiles
+        let rest = serde_json::to_vec(&Self {
+            tiles: TileCatalog::new(),
+            #[cfg(feature = "sprites")]
+            sprites: self.sprites.clone(),
+            #[cfg(feature = "fonts")]
+            fonts: self.fonts.clone(),
+        })?;
+        chunk.push(b'}');
+        chunk.extend_from_slice(&rest[br#"{"tiles":{}"#.len()..]);
+        Ok((chunk, None))
+    }
 }
 
 pub fn map_internal_error<T: std::fmt::Display>(e: T) -> actix_web::Error {
@@ -57,18 +150,74 @@ pub fn map_internal_error<T: std::fmt::Display>(e: T) -> actix_web::Error {
     ErrorInternalServerError(e.to_string())
 }
 
-/// Root path will eventually have a web front. For now, just a stub.
+/// Response of the `/` route, loaded from the [`IndexConfig`] on startup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexPage {
+    Text(String),
+    Html(String),
+    Redirect(String),
+}
+
+impl IndexPage {
+    pub fn new(config: Option<&IndexConfig>) -> MartinResult<Option<Self>> {
+        let Some(config) = config else {
+            return Ok(None);
+        };
+        Ok(match config {
+            IndexConfig {
+                text: Some(v),
+                file: None,
+                redirect: None,
+            } => Some(Self::Text(v.clone())),
+            IndexConfig {
+                text: None,
+                file: Some(v),
+                redirect: None,
+            } => Some(Self::Html(
+                std::fs::read_to_string(v).map_err(|e| IndexFileError(e, v.clone()))?,
+            )),
+            IndexConfig {
+                text: None,
+                file: None,
+                redirect: Some(v),
+            } => Some(Self::Redirect(v.clone())),
+            IndexConfig {
+                text: None,
+                file: None,
+                redirect: None,
+            } => None,
+            _ => Err(IndexConfigConflict)?,
+        })
+    }
+}
+
+/// Root path will eventually have a web front. For now, just a stub unless configured otherwise.
 #[route("/", method = "GET", method = "HEAD")]
 #[allow(clippy::unused_async)]
-async fn get_index() -> &'static str {
+async fn get_index(req: HttpRequest) -> HttpResponse {
     // todo: once this becomes more substantial, add wrap = "middleware::Compress::default()"
-    "Martin server is running. Eventually this will be a nice web front.\n\n\
-    A list of all available sources is at /catalog\n\n\
-    See documentation https://github.com/maplibre/martin"
+    match req.app_data::<Data<IndexPage>>().map(|v| v.as_ref()) {
+        Some(IndexPage::Text(v)) => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(v.clone()),
+        Some(IndexPage::Html(v)) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(v.clone()),
+        Some(IndexPage::Redirect(v)) => HttpResponse::Found()
+            .insert_header((LOCATION, v.as_str()))
+            .finish(),
+        None => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(
+                "Martin server is running. Eventually this will be a nice web front.\n\n\
+                A list of all available sources is at /catalog\n\n\
+                See documentation https://github.com/maplibre/martin",
+            ),
+    }
 }
 
 /// Return 200 OK if healthy. Used for readiness and liveness probes.
-#[route("/health", method = "GET", method = "HEAD")]
+/// Registered at the configurable `health_path` instead of a fixed route.
 #[allow(clippy::unused_async)]
 async fn get_health() -> impl Responder {
     HttpResponse::Ok()
@@ -76,37 +225,209 @@ async fn get_health() -> impl Responder {
         .message_body("OK")
 }
 
-#[route(
-    "/catalog",
-    method = "GET",
-    method = "HEAD",
-    wrap = "middleware::Compress::default()"
-)]
+/// Methods supported by the catalog and `TileJSON` routes
+const READ_ONLY_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Answer the `OPTIONS` requests to the catalog and `TileJSON` routes, e.g. from the browser clients
+/// that send them without the `Origin` header that the CORS middleware handles.
 #[allow(clippy::unused_async)]
-async fn get_catalog(catalog: Data<Catalog>) -> impl Responder {
-    HttpResponse::Ok().json(catalog)
+async fn get_options() -> impl Responder {
+    HttpResponse::NoContent()
+        .insert_header((ALLOW, READ_ONLY_METHODS))
+        .insert_header((ACCESS_CONTROL_ALLOW_METHODS, READ_ONLY_METHODS))
+        .finish()
+}
+
+/// A snapshot of the server resource usage, returned by the `/status` endpoint.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct ServerStatus {
+    #[cfg(feature = "postgres")]
+    pub postgres: Vec<crate::pg::PgPoolStatus>,
+    #[cfg(feature = "fonts")]
+    pub fonts: Option<crate::fonts::FontCacheStatus>,
 }
 
+/// Return the usage of the server resources, e.g. the Postgres connection pools.
+#[route("/status", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_status(
+    #[cfg_attr(
+        not(any(feature = "postgres", feature = "fonts")),
+        allow(unused_variables)
+    )]
+    req: HttpRequest,
+) -> impl Responder {
+    let status = ServerStatus {
+        #[cfg(feature = "postgres")]
+        postgres: req
+            .app_data::<Data<Vec<crate::pg::PgPool>>>()
+            .map(|pools| pools.iter().map(crate::pg::PgPool::get_status).collect())
+            .unwrap_or_default(),
+        #[cfg(feature = "fonts")]
+        fonts: req
+            .app_data::<Data<crate::fonts::FontSources>>()
+            .map(|fonts| fonts.get_cache_status()),
+    };
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(status)
+}
+
+/// Return the list of all sources. Unless `disable_compression` is set, the response is compressed
+/// the same way as the tiles whenever the client accepts gzip or brotli.
+/// The uncompressed response is streamed, so it does not have to be held in memory at once.
+#[route("/catalog", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_catalog(
+    req: HttpRequest,
+    catalog: Data<Catalog>,
+    srv_config: Data<SrvConfig>,
+) -> ActixResult<HttpResponse> {
+    let enc = negotiate_encoding(&req, &srv_config)?;
+    let stream = catalog.into_inner().json_stream();
+    let Some((encoder, encoding)) = enc.and_then(ChunkEncoder::new) else {
+        return Ok(encoded_response(ContentType::json(), Encoding::Uncompressed).streaming(stream));
+    };
+    Ok(encoded_response(ContentType::json(), encoding).streaming(encoder.encode_stream(stream)))
+}
+
+/// Compresses a response body chunk by chunk, as it is being streamed
+enum ChunkEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl ChunkEncoder {
+    /// Create the encoder for the negotiated encoding, or `None` if no compression is needed
+    fn new(enc: ContentEncoding) -> Option<(Self, Encoding)> {
+        match enc {
+            ContentEncoding::Gzip => Some((
+                Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+                Encoding::Gzip,
+            )),
+            ContentEncoding::Brotli => Some((
+                Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                    Vec::new(),
+                    4096,
+                    11,
+                    22,
+                ))),
+                Encoding::Brotli,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Compress the chunk, and return the compressed output produced so far
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+            Self::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+
+    /// Complete the compressed stream, and return the rest of its output
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+
+    /// Compress each chunk of the stream, followed by the end of the compressed data
+    fn encode_stream(
+        self,
+        stream: impl Stream<Item = serde_json::Result<Bytes>> + 'static,
+    ) -> impl Stream<Item = io::Result<Bytes>> {
+        stream::unfold(
+            (Box::pin(stream), Some(self)),
+            |(mut stream, encoder)| async move {
+                let mut encoder = encoder?;
+                Some(match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        let data = encoder.write(&chunk).map(Bytes::from);
+                        (data, (stream, Some(encoder)))
+                    }
+                    Some(Err(e)) => (Err(e.into()), (stream, None)),
+                    None => (encoder.finish().map(Bytes::from), (stream, None)),
+                })
+            },
+        )
+    }
+}
+
+/// Register all routes, with the health check at its default `/health` path
 pub fn router(cfg: &mut web::ServiceConfig) {
-    cfg.service(get_health)
+    configure_router(cfg, &[HEALTH_PATH_DEFAULT]);
+}
+
+/// Register all routes, with the health check at each of the given paths
+pub fn configure_router(cfg: &mut web::ServiceConfig, health_paths: &[&str]) {
+    for path in health_paths {
+        cfg.service(
+            web::resource(*path)
+                .route(web::get().to(get_health))
+                .route(web::head().to(get_health)),
+        );
+    }
+
+    for path in ["/catalog", "/{source_ids}"] {
+        cfg.service(web::resource(path).guard(guard::Options()).to(get_options));
+    }
+
+    cfg.service(get_status)
         .service(get_index)
         .service(get_catalog)
-        .service(get_source_info)
-        .service(get_tile);
+        .service(crate::srv::style::get_style)
+        .service(crate::srv::openapi::get_openapi)
+        .service(post_shutdown);
+
+    // Must be registered before the TileJSON route that would match `/font` too
+    #[cfg(feature = "fonts")]
+    cfg.service(crate::srv::fonts::get_font_catalog)
+        .service(crate::srv::fonts::get_font_ids);
+
+    cfg.service(get_source_info).service(get_tile);
 
     #[cfg(feature = "sprites")]
     cfg.service(crate::srv::sprites::get_sprite_json)
         .service(crate::srv::sprites::get_sprite_png);
 
     #[cfg(feature = "fonts")]
-    cfg.service(crate::srv::fonts::get_font);
+    cfg.service(crate::srv::fonts::get_font_coverage)
+        .service(crate::srv::fonts::get_font);
+}
+
+/// Allow the browsers to get the tiles from any origin
+fn cors_middleware() -> Cors {
+    Cors::default()
+        .allow_any_origin()
+        .allowed_methods(vec!["GET"])
+}
+
+/// Add a `Server` header to all responses, unless it was disabled in the config.
+fn server_header(config: &SrvConfig) -> middleware::DefaultHeaders {
+    let headers = middleware::DefaultHeaders::new();
+    if let Some(value) = config.get_server_header() {
+        headers.add((SERVER, value.to_string()))
+    } else {
+        headers
+    }
 }
 
 type Server = Pin<Box<dyn Future<Output = MartinResult<()>>>>;
 
 /// Create a future for an Actix web server together with the listening address.
 pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server, String)> {
-    let catalog = Catalog::new(&state)?;
+    let catalog = Catalog::with_names(&state, config.catalog_name.unwrap_or_default())?;
+    let index = IndexPage::new(config.index.as_ref())?;
 
     let keep_alive = Duration::from_secs(config.keep_alive.unwrap_or(KEEP_ALIVE_DEFAULT));
     let worker_processes = config.worker_processes.unwrap_or_else(num_cpus::get);
@@ -114,28 +435,80 @@ pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server
         .listen_addresses
         .clone()
         .unwrap_or_else(|| LISTEN_ADDRESSES_DEFAULT.to_string());
+    // Rate limiter state must be shared between all workers
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone().unwrap_or_default());
+    // So is the CPU budget
+    let cpu_budget = config.cpu_budget.as_ref().map(CpuBudget::new);
+    // And the recently empty tiles, which are only needed if the main cache cannot remember them
+    let empty_tiles = config
+        .empty_tile_ttl_ms
+        .filter(|_| state.cache.is_none())
+        .map(|ttl| EmptyTiles::new(Duration::from_millis(ttl)));
+    #[cfg(feature = "postgres")]
+    {
+        crate::pg::schedule_refreshes(&state.pg_refreshes, &state.cache);
+        for pool in &state.pg_pools {
+            pool.maintain_min_idle();
+        }
+    }
+    let shutdown_timeout = config.shutdown_timeout.unwrap_or(SHUTDOWN_TIMEOUT_DEFAULT);
+    let max_payload_size = config.max_payload_size.unwrap_or(MAX_PAYLOAD_SIZE_DEFAULT);
+    let (shutdown_sender, shutdown_receiver) = if config.shutdown_token.is_some() {
+        let (sender, receiver) = shutdown_channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
 
     let factory = move || {
-        let cors_middleware = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET"]);
-
         let app = App::new()
             .app_data(Data::new(state.tiles.clone()))
             .app_data(Data::new(state.cache.clone()));
 
+        #[cfg(feature = "postgres")]
+        let app = app.app_data(Data::new(state.pg_pools.clone()));
+
         #[cfg(feature = "sprites")]
         let app = app.app_data(Data::new(state.sprites.clone()));
 
         #[cfg(feature = "fonts")]
         let app = app.app_data(Data::new(state.fonts.clone()));
 
+        let app = if let Some(index) = &index {
+            app.app_data(Data::new(index.clone()))
+        } else {
+            app
+        };
+
+        let app = if let Some(budget) = &cpu_budget {
+            app.app_data(Data::new(budget.clone()))
+        } else {
+            app
+        };
+
+        let app = if let Some(empty) = &empty_tiles {
+            app.app_data(Data::new(empty.clone()))
+        } else {
+            app
+        };
+
+        let app = if let Some(sender) = &shutdown_sender {
+            app.app_data(Data::new(sender.clone()))
+        } else {
+            app
+        };
+
         app.app_data(Data::new(catalog.clone()))
             .app_data(Data::new(config.clone()))
-            .wrap(cors_middleware)
+            .app_data(web::PayloadConfig::new(max_payload_size))
+            // The last middleware is the outermost one, so the throttled responses still get
+            // the CORS and `Server` headers, and the browsers can read their `Retry-After`
+            .wrap(rate_limiter.clone())
+            .wrap(cors_middleware())
+            .wrap(server_header(&config))
             .wrap(middleware::NormalizePath::new(TrailingSlash::MergeOnly))
             .wrap(middleware::Logger::default())
-            .configure(router)
+            .configure(|cfg| configure_router(cfg, &config.get_health_paths()))
     };
 
     #[cfg(feature = "lambda")]
@@ -148,23 +521,32 @@ pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server
         .bind(listen_addresses.clone())
         .map_err(|e| BindingError(e, listen_addresses.clone()))?
         .keep_alive(keep_alive)
-        .shutdown_timeout(0)
+        .shutdown_timeout(shutdown_timeout)
         .workers(worker_processes)
-        .run()
-        .err_into();
+        .run();
+    if let Some(receiver) = shutdown_receiver {
+        listen_for_shutdown(receiver, server.handle());
+    }
+    let server = server.err_into();
 
     Ok((Box::pin(server), listen_addresses))
 }
 
 #[cfg(test)]
 pub mod tests {
+    use actix_web::http::header::{
+        ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_ENCODING, ORIGIN, RETRY_AFTER, VARY,
+    };
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
     use async_trait::async_trait;
-    use martin_tile_utils::{Encoding, Format, TileInfo};
+    use martin_tile_utils::{Format, TileInfo};
     use tilejson::TileJSON;
 
     use super::*;
     use crate::source::{Source, TileData};
-    use crate::{TileCoord, UrlQuery};
+    use crate::srv::config::SERVER_HEADER_DEFAULT;
+    use crate::srv::{RateLimit, RateLimitConfig};
+    use crate::{OptBoolObj, OptOneMany, TileCoord, TileSources, UrlQuery};
 
     #[derive(Debug, Clone)]
     pub struct TestSource {
@@ -199,4 +581,260 @@ pub mod tests {
             Ok(self.data.clone())
         }
     }
+
+    #[actix_rt::test]
+    async fn test_server_header() {
+        for (header, expected) in [
+            (OptBoolObj::NoValue, Some(SERVER_HEADER_DEFAULT)),
+            (OptBoolObj::Bool(true), Some(SERVER_HEADER_DEFAULT)),
+            (OptBoolObj::Bool(false), None),
+            (OptBoolObj::Object("tiles".to_string()), Some("tiles")),
+        ] {
+            let config = SrvConfig {
+                server_header: header,
+                ..Default::default()
+            };
+            let app = init_service(App::new().wrap(server_header(&config)).configure(router)).await;
+            let req = TestRequest::get().uri("/health").to_request();
+            let response = call_service(&app, req).await;
+            let value = response.headers().get(SERVER).map(|v| v.to_str().unwrap());
+            assert_eq!(value, expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limited_cors() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            ip_header: Some("X-Forwarded-For".to_string()),
+            catalog: Some(RateLimit {
+                requests: 1,
+                seconds: 60,
+            }),
+            ..Default::default()
+        });
+        let config = SrvConfig::default();
+        // Same order as in `new_server`
+        let app = init_service(
+            App::new()
+                .wrap(limiter)
+                .wrap(cors_middleware())
+                .wrap(server_header(&config))
+                .route("/catalog", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for status in [200, 429] {
+            let req = TestRequest::get()
+                .uri("/catalog")
+                .insert_header((ORIGIN, "https://example.com"))
+                .insert_header(("X-Forwarded-For", "1.1.1.1"))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.status(), status);
+            let headers = response.headers();
+            assert!(
+                headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN),
+                "{status}"
+            );
+            assert_eq!(headers.get(SERVER).unwrap(), SERVER_HEADER_DEFAULT);
+            if status == 429 {
+                assert!(headers.contains_key(RETRY_AFTER));
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_health_path() {
+        let config = SrvConfig {
+            health_path: OptOneMany::Many(vec!["/healthz".to_string(), "/health".to_string()]),
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new().configure(|cfg| configure_router(cfg, &config.get_health_paths())),
+        )
+        .await;
+        for path in ["/healthz", "/health"] {
+            let req = TestRequest::get().uri(path).to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(read_body(response).await, "OK");
+        }
+        let req = TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/healthz")
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn test_options() {
+        let app = init_service(App::new().configure(router)).await;
+        for path in ["/catalog", "/some_source"] {
+            let req = TestRequest::default()
+                .method(actix_web::http::Method::OPTIONS)
+                .uri(path)
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.status(), 204, "{path}");
+            let methods = response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap();
+            assert_eq!(methods, READ_ONLY_METHODS);
+            assert_eq!(response.headers().get(ALLOW).unwrap(), READ_ONLY_METHODS);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_index() {
+        let app = init_service(App::new().service(get_index)).await;
+        let req = TestRequest::get().uri("/").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+
+        let config = IndexConfig {
+            redirect: Some("https://example.org/docs".to_string()),
+            ..Default::default()
+        };
+        let index = IndexPage::new(Some(&config)).unwrap().unwrap();
+        let app = init_service(App::new().app_data(Data::new(index)).service(get_index)).await;
+        let req = TestRequest::get().uri("/").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), 302);
+        let location = response.headers().get(LOCATION).unwrap();
+        assert_eq!(location, "https://example.org/docs");
+
+        let config = IndexConfig {
+            text: Some("Welcome".to_string()),
+            redirect: Some("https://example.org/docs".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            IndexPage::new(Some(&config)),
+            Err(IndexConfigConflict)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_catalog_compression() {
+        let catalog = Catalog::default();
+        let expected = serde_json::to_vec(&catalog).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(catalog))
+                .app_data(Data::new(SrvConfig::default()))
+                .service(get_catalog),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/catalog")
+            .insert_header(("Accept-Encoding", "gzip"))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        let body = read_body(response).await;
+        assert_eq!(crate::decode_gzip(&body).unwrap(), expected);
+
+        let req = TestRequest::get().uri("/catalog").to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(read_body(response).await, expected);
+    }
+
+    #[actix_rt::test]
+    async fn test_preferred_encoding_everywhere() {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "test_source",
+            tj: tilejson::tilejson! { tiles: vec![] },
+            data: vec![1_u8, 2, 3],
+        })]]);
+        let srv_config = SrvConfig {
+            preferred_encoding: Some(crate::args::PreferredEncoding::Brotli),
+            ..SrvConfig::default()
+        };
+        let app = App::new()
+            .app_data(Data::new(sources))
+            .app_data(Data::new(Catalog::default()))
+            .app_data(Data::new(srv_config))
+            .app_data(Data::new(crate::NO_MAIN_CACHE));
+        #[cfg(feature = "fonts")]
+        let app = {
+            let fonts = crate::srv::fonts::tests::test_fonts(
+                "../tests/fixtures/fonts/overpass-mono-regular.ttf",
+            );
+            app.app_data(Data::new(fonts))
+        };
+        #[cfg(feature = "sprites")]
+        let app = {
+            let path = std::path::PathBuf::from("../tests/fixtures/sprites/src1");
+            let mut config = crate::file_config::FileConfigEnum::new(vec![path]);
+            let sprites = crate::sprites::SpriteSources::resolve(&mut config).unwrap();
+            app.app_data(Data::new(sprites))
+        };
+        let app = init_service(app.configure(router)).await;
+
+        let mut paths = vec![
+            "/test_source/0/0/0",
+            "/test_source",
+            "/catalog",
+            "/style.json",
+            "/openapi.json",
+        ];
+        if cfg!(feature = "fonts") {
+            paths.push("/font/Overpass%20Mono%20Regular/0-255");
+        }
+        if cfg!(feature = "sprites") {
+            paths.push("/sprite/src1.json");
+        }
+        for path in paths {
+            let req = TestRequest::get()
+                .uri(path)
+                .insert_header(("Accept-Encoding", "gzip, br"))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.status(), 200, "{path}");
+            let enc = response.headers().get(CONTENT_ENCODING);
+            assert_eq!(enc.unwrap(), "br", "{path}");
+            if path != "/test_source/0/0/0" {
+                let mut vary = response.headers().get_all(VARY);
+                assert!(vary.any(|v| v == "Accept-Encoding"), "{path}");
+            }
+            let body = read_body(response).await;
+            assert!(!crate::decode_brotli(&body).unwrap().is_empty(), "{path}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_catalog_stream() {
+        use futures::TryStreamExt as _;
+
+        for count in [0, 1, CATALOG_CHUNK_SIZE, CATALOG_CHUNK_SIZE * 2 + 1] {
+            let catalog = Catalog {
+                tiles: (0..count)
+                    .map(|i| {
+                        let entry = crate::CatalogSourceEntry {
+                            content_type: "application/x-protobuf".to_string(),
+                            description: Some(format!("source \"{i}\"")),
+                            ..Default::default()
+                        };
+                        (format!("src{i}"), entry)
+                    })
+                    .collect(),
+                ..Default::default()
+            };
+            let expected = serde_json::to_vec(&catalog).unwrap();
+            let catalog = Arc::new(catalog);
+            let chunks: Vec<Bytes> = catalog.clone().json_stream().try_collect().await.unwrap();
+            assert_eq!(chunks.len(), count / CATALOG_CHUNK_SIZE + 1);
+            assert_eq!(chunks.concat(), expected, "{count} sources");
+
+            let (encoder, _) = ChunkEncoder::new(ContentEncoding::Gzip).unwrap();
+            let stream = encoder.encode_stream(catalog.json_stream());
+            let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+            let data = crate::decode_gzip(&chunks.concat()).unwrap();
+            assert_eq!(data, expected, "{count} gzipped sources");
+        }
+    }
 }
@@ -0,0 +1,165 @@
+use actix_web::dev::ServerHandle;
+use actix_web::error::ErrorNotFound;
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::web::{Bytes, Data};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt as _;
+use log::info;
+
+use crate::srv::server::map_internal_error;
+use crate::srv::SrvConfig;
+
+/// Passes the requests of the `/shutdown` route to the task that stops the server
+#[derive(Clone, Debug)]
+pub struct ShutdownSender(UnboundedSender<()>);
+
+/// Create a channel for the shutdown requests, see [`listen_for_shutdown`]
+#[must_use]
+pub fn shutdown_channel() -> (ShutdownSender, UnboundedReceiver<()>) {
+    let (sender, receiver) = unbounded();
+    (ShutdownSender(sender), receiver)
+}
+
+/// Gracefully stop the server once a shutdown request is received. New connections are no longer accepted,
+/// and the in-flight requests are given up to `shutdown_timeout` seconds to finish.
+pub fn listen_for_shutdown(mut receiver: UnboundedReceiver<()>, handle: ServerHandle) {
+    actix_rt::spawn(async move {
+        if receiver.next().await.is_some() {
+            handle.stop(true).await;
+        }
+    });
+}
+
+/// Shut down the server. Only available if `shutdown_token` is configured,
+/// and requires the `Authorization: Bearer <shutdown_token>` header.
+/// The body is ignored, but reading it rejects the bodies larger than `max_payload_size`.
+#[route("/shutdown", method = "POST")]
+#[allow(clippy::unused_async)]
+async fn post_shutdown(
+    req: HttpRequest,
+    srv_config: Data<SrvConfig>,
+    _body: Bytes,
+) -> ActixResult<HttpResponse> {
+    let (Some(token), Some(sender)) = (
+        &srv_config.shutdown_token,
+        req.app_data::<Data<ShutdownSender>>(),
+    ) else {
+        return Err(ErrorNotFound("Shutdown is not enabled"));
+    };
+
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| is_same_token(v, token));
+    if !authorized {
+        return Ok(HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .finish());
+    }
+
+    info!("Shutting down the server because of a /shutdown request");
+    sender.0.unbounded_send(()).map_err(map_internal_error)?;
+    Ok(HttpResponse::Accepted().body("Shutting down"))
+}
+
+/// Compare the tokens in constant time, so that the response time does not reveal the matching prefix
+fn is_same_token(value: &str, token: &str) -> bool {
+    value.len() == token.len()
+        && value
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_shutdown() {
+        let srv_config = SrvConfig {
+            shutdown_token: Some("secret".to_string()),
+            ..SrvConfig::default()
+        };
+        let (sender, mut receiver) = shutdown_channel();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(srv_config))
+                .app_data(Data::new(sender))
+                .service(post_shutdown),
+        )
+        .await;
+
+        for auth in [None, Some("Bearer wrong!"), Some("secret")] {
+            let mut req = TestRequest::post().uri("/shutdown");
+            if let Some(auth) = auth {
+                req = req.insert_header((AUTHORIZATION, auth));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), 401);
+            assert!(receiver.try_next().is_err(), "No shutdown expected");
+        }
+
+        let req = TestRequest::post()
+            .uri("/shutdown")
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 202);
+        assert_eq!(receiver.try_next().unwrap(), Some(()));
+    }
+
+    #[actix_rt::test]
+    async fn test_shutdown_payload_limit() {
+        let srv_config = SrvConfig {
+            shutdown_token: Some("secret".to_string()),
+            ..SrvConfig::default()
+        };
+        let (sender, mut receiver) = shutdown_channel();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(srv_config))
+                .app_data(Data::new(sender))
+                .app_data(web::PayloadConfig::new(16))
+                .service(post_shutdown),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/shutdown")
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .set_payload(vec![b'x'; 17])
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 413);
+        assert!(receiver.try_next().is_err(), "No shutdown expected");
+
+        let req = TestRequest::post()
+            .uri("/shutdown")
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .set_payload(vec![b'x'; 16])
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 202);
+    }
+
+    #[actix_rt::test]
+    async fn test_shutdown_disabled() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(SrvConfig::default()))
+                .service(post_shutdown),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/shutdown")
+            .insert_header((AUTHORIZATION, "Bearer "))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 404);
+    }
+}
@@ -3,12 +3,15 @@ use std::string::ToString;
 use actix_web::error::ErrorNotFound;
 use actix_web::http::header::ContentType;
 use actix_web::web::{Data, Path};
-use actix_web::{middleware, route, HttpResponse, Result as ActixResult};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::{Encoding, Format, TileInfo};
 use spreet::Spritesheet;
 
 use crate::sprites::{SpriteError, SpriteSources};
 use crate::srv::server::map_internal_error;
-use crate::srv::SourceIDsRequest;
+use crate::srv::tiles::negotiated_response;
+use crate::srv::{SourceIDsRequest, SrvConfig};
+use crate::Tile;
 
 #[route("/sprite/{source_ids}.png", method = "GET", method = "HEAD")]
 async fn get_sprite_png(
@@ -21,18 +24,18 @@ async fn get_sprite_png(
         .body(sheet.encode_png().map_err(map_internal_error)?))
 }
 
-#[route(
-    "/sprite/{source_ids}.json",
-    method = "GET",
-    method = "HEAD",
-    wrap = "middleware::Compress::default()"
-)]
+/// Return the index of the sprite sheet, compressed the same way as the catalog
+#[route("/sprite/{source_ids}.json", method = "GET", method = "HEAD")]
 async fn get_sprite_json(
+    req: HttpRequest,
     path: Path<SourceIDsRequest>,
     sprites: Data<SpriteSources>,
+    srv_config: Data<SrvConfig>,
 ) -> ActixResult<HttpResponse> {
     let sheet = get_sprite(&path, &sprites).await?;
-    Ok(HttpResponse::Ok().json(sheet.get_index()))
+    let data = serde_json::to_vec(&sheet.get_index()).map_err(map_internal_error)?;
+    let json = Tile::new(data, TileInfo::new(Format::Json, Encoding::Uncompressed));
+    negotiated_response(&req, &srv_config, ContentType::json(), json)
 }
 
 async fn get_sprite(path: &SourceIDsRequest, sprites: &SpriteSources) -> ActixResult<Spritesheet> {
@@ -0,0 +1,163 @@
+use actix_web::http::header::ContentType;
+use actix_web::web::Data;
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde_json::{json, Map, Value};
+
+use crate::source::{Source, TileSources};
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::negotiated_response;
+use crate::srv::SrvConfig;
+use crate::Tile;
+
+/// Colors assigned to the vector layers in turn, so that they can be told apart
+const LAYER_COLORS: &[&str] = &[
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+];
+
+/// Generate a minimal `MapLibre` style that shows all tile sources of this server.
+/// It is compressed the same way as the catalog.
+#[route("/style.json", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_style(
+    req: HttpRequest,
+    sources: Data<TileSources>,
+    srv_config: Data<SrvConfig>,
+) -> ActixResult<HttpResponse> {
+    let info = req.connection_info();
+    let base_path = srv_config.base_path.as_deref().unwrap_or_default();
+    let base_url = format!("{}://{}{base_path}", info.scheme(), info.host());
+    let style = generate_style(&sources, &base_url);
+    let data = serde_json::to_vec(&style).map_err(map_internal_error)?;
+    let json = Tile::new(data, TileInfo::new(Format::Json, Encoding::Uncompressed));
+    negotiated_response(&req, &srv_config, ContentType::json(), json)
+}
+
+#[must_use]
+pub fn generate_style(sources: &TileSources, base_url: &str) -> Value {
+    let mut style_sources = Map::new();
+    let mut layers = Vec::new();
+
+    for src in sources.get_all_sources() {
+        let id = src.get_id();
+        let tj = src.get_tilejson();
+        let mut style_source = match src.get_tile_info().format {
+            Format::Mvt => {
+                add_vector_layers(&mut layers, src);
+                json!({ "type": "vector" })
+            }
+            Format::Png | Format::Jpeg | Format::Webp | Format::Gif => {
+                layers.push(json!({ "id": id, "type": "raster", "source": id }));
+                json!({ "type": "raster", "tileSize": 256 })
+            }
+            // Other formats cannot be shown by MapLibre
+            Format::Json => continue,
+        };
+        style_source["tiles"] = json!([format!("{base_url}/{id}/{{z}}/{{x}}/{{y}}")]);
+        if let Some(v) = tj.minzoom {
+            style_source["minzoom"] = json!(v);
+        }
+        if let Some(v) = tj.maxzoom {
+            style_source["maxzoom"] = json!(v);
+        }
+        if let Some(v) = tj.bounds {
+            style_source["bounds"] = json!([v.left, v.bottom, v.right, v.top]);
+        }
+        if let Some(v) = &tj.attribution {
+            style_source["attribution"] = json!(v);
+        }
+        style_sources.insert(id.to_string(), style_source);
+    }
+
+    json!({
+        "version": 8,
+        "name": "Martin",
+        "sources": style_sources,
+        "layers": layers,
+    })
+}
+
+/// Add a fill, line, and circle layer for each vector layer of the source.
+/// The geometry type of the features is not known in advance, so each layer is filtered by it.
+fn add_vector_layers(layers: &mut Vec<Value>, src: &dyn Source) {
+    let id = src.get_id();
+    let layer_ids = src.get_tilejson().vector_layers.as_ref().map_or_else(
+        || vec![id.to_string()],
+        |v| v.iter().map(|l| l.id.clone()).collect(),
+    );
+
+    for layer_id in layer_ids {
+        let color = LAYER_COLORS[layers.len() / 3 % LAYER_COLORS.len()];
+        layers.push(json!({
+            "id": format!("{id}.{layer_id}.fill"),
+            "type": "fill",
+            "source": id,
+            "source-layer": layer_id,
+            "filter": ["==", ["geometry-type"], "Polygon"],
+            "paint": { "fill-color": color, "fill-opacity": 0.4, "fill-outline-color": color },
+        }));
+        layers.push(json!({
+            "id": format!("{id}.{layer_id}.line"),
+            "type": "line",
+            "source": id,
+            "source-layer": layer_id,
+            "filter": ["==", ["geometry-type"], "LineString"],
+            "paint": { "line-color": color, "line-width": 1.5 },
+        }));
+        layers.push(json!({
+            "id": format!("{id}.{layer_id}.circle"),
+            "type": "circle",
+            "source": id,
+            "source-layer": layer_id,
+            "filter": ["==", ["geometry-type"], "Point"],
+            "paint": { "circle-color": color, "circle-radius": 3 },
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::App;
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+
+    #[actix_rt::test]
+    async fn test_style() {
+        let mut tj = tilejson! { tiles: vec![] };
+        tj.minzoom = Some(2);
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "test_source",
+            tj,
+            data: Vec::new(),
+        })]]);
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(sources))
+                .app_data(Data::new(SrvConfig::default()))
+                .service(get_style),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/style.json")
+            .insert_header(("Host", "example.org:3000"))
+            .to_request();
+        let style: Value = call_and_read_body_json(&app, req).await;
+
+        let source = &style["sources"]["test_source"];
+        assert_eq!(source["type"], "vector");
+        assert_eq!(
+            source["tiles"][0],
+            "http://example.org:3000/test_source/{z}/{x}/{y}"
+        );
+        assert_eq!(source["minzoom"], 2);
+
+        let layers = style["layers"].as_array().unwrap();
+        let types: Vec<_> = layers.iter().map(|l| l["type"].as_str().unwrap()).collect();
+        assert_eq!(types, vec!["fill", "line", "circle"]);
+        assert!(layers.iter().all(|l| l["source-layer"] == "test_source"));
+    }
+}
@@ -1,26 +1,50 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
 use actix_http::header::Quality;
 use actix_http::ContentEncoding;
-use actix_web::error::{ErrorBadRequest, ErrorNotAcceptable, ErrorNotFound};
+use actix_web::error::{
+    ErrorBadGateway, ErrorBadRequest, ErrorNotAcceptable, ErrorNotFound, ErrorServiceUnavailable,
+};
 use actix_web::http::header::{
-    AcceptEncoding, Encoding as HeaderEnc, Preference, CONTENT_ENCODING,
+    Accept, AcceptEncoding, Encoding as HeaderEnc, HeaderName, HeaderValue, Preference,
+    QualityItem, TryIntoHeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, LINK, VARY,
 };
+use actix_web::http::StatusCode;
 use actix_web::web::{Data, Path, Query};
-use actix_web::{route, HttpMessage, HttpRequest, HttpResponse, Result as ActixResult};
-use futures::future::try_join_all;
-use log::trace;
+use actix_web::{
+    route, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Result as ActixResult,
+};
+use futures::future::{join_all, try_join_all};
+use itertools::Itertools as _;
+use log::{debug, trace, warn};
 use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::Deserialize;
 
 use crate::args::PreferredEncoding;
 use crate::source::{Source, TileSources, UrlQuery};
+use crate::srv::cpu_budget::{run_in_budget, Workload};
 use crate::srv::server::map_internal_error;
-use crate::srv::SrvConfig;
+use crate::srv::{
+    CachePartition, CpuBudget, EmptyTileKey, EmptyTiles, EncodingFallback, SrvConfig,
+};
 use crate::utils::cache::get_or_insert_cached_value;
 use crate::utils::{
-    decode_brotli, decode_gzip, encode_brotli, encode_gzip, CacheKey, CacheValue, MainCache,
-    OptMainCache,
+    decode_brotli, decode_gzip, encode_brotli, encode_gzip, encode_webp, with_deadline,
+    with_request_headers, CacheKey, CacheValue, MainCache, OptMainCache, MAX_DEADLINE_MS,
 };
-use crate::{Tile, TileCoord, TileData};
+use crate::{MartinError, MartinResult, Tile, TileCoord, TileData};
+
+static SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+static X_MARTIN_ENCODING: HeaderName = HeaderName::from_static("x-martin-encoding");
+static X_REQUEST_DEADLINE: HeaderName = HeaderName::from_static("x-request-deadline");
+
+/// Query parameter forcing the encoding of the tile, for the clients that cannot set `Accept-Encoding`
+const ENCODING_PARAM: &str = "encoding";
+
+/// Media type of the alternative JSON tiles, which are always `GeoJSON` feature collections
+const GEOJSON_CONTENT_TYPE: &str = "application/geo+json";
 
 static SUPPORTED_ENC: &[HeaderEnc] = &[
     HeaderEnc::gzip(),
@@ -36,6 +60,8 @@ pub struct TileRequest {
     y: u32,
 }
 
+/// HEAD requests produce the same tile as GET, so that all headers match, including the `Content-Length`
+/// that actix computes from the body it omits. This also warms up the cache for the following GET.
 #[route("/{source_ids}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
 async fn get_tile(
     req: HttpRequest,
@@ -43,25 +69,106 @@ async fn get_tile(
     path: Path<TileRequest>,
     sources: Data<TileSources>,
     cache: Data<OptMainCache>,
+    cpu_budget: Option<Data<CpuBudget>>,
+    empty_tiles: Option<Data<EmptyTiles>>,
 ) -> ActixResult<HttpResponse> {
-    let src = DynTileSource::new(
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path.y,
+    };
+    // The `encoding` query parameter overrides the `Accept-Encoding` header, for the clients that cannot set it
+    let accept_enc = match get_encoding_param(&req)? {
+        Some(enc) => Some(AcceptEncoding(vec![QualityItem::max(
+            Preference::Specific(enc),
+        )])),
+        None => req.get_header::<AcceptEncoding>(),
+    };
+    let query = strip_control_params(req.query_string(), &srv_config);
+    let mut src = DynTileSource::new(
         sources.as_ref(),
         &path.source_ids,
-        Some(path.z),
-        req.query_string(),
-        req.get_header::<AcceptEncoding>(),
+        Some(xyz),
+        &query,
+        accept_enc,
         srv_config.preferred_encoding,
         cache.as_ref().as_ref(),
     )?;
+    src.server_timing = srv_config.server_timing.unwrap_or_default();
+    src.disable_compression = srv_config.disable_compression.unwrap_or_default();
+    src.encoding_fallback = srv_config.encoding_fallback.unwrap_or_default();
+    src.best_effort = srv_config.best_effort.unwrap_or_default();
+    src.encoding_header = srv_config.encoding_header.unwrap_or_default();
+    src.prefetch_links = srv_config.prefetch_links.unwrap_or_default();
+    src.evict_invalid_tiles = srv_config.evict_invalid_tiles.unwrap_or_default();
+    src.cpu_budget = cpu_budget.as_ref().map(Data::get_ref);
+    src.response_headers = get_response_headers(&path.source_ids, &srv_config);
+    if let Some(partition) = &srv_config.cache_partition {
+        src.cache_partition = get_cache_partition(&req, partition);
+    }
 
-    src.get_http_response(TileCoord {
-        z: path.z,
-        x: path.x,
-        y: path.y,
-    })
-    .await
+    let transcode_webp = is_webp_source(&path.source_ids, &srv_config);
+    let accept = req.get_header::<Accept>();
+    let alt_formats = src.get_alt_formats();
+    src.select_format(accept.as_ref(), &alt_formats, transcode_webp)?;
+    if transcode_webp && accepts_webp(accept) {
+        src.transcode_webp = true;
+    }
+
+    // Without the main cache, the recently empty tiles are remembered for a short time
+    let empty = empty_tiles.as_deref().map(|empty_tiles| {
+        let key = EmptyTileKey {
+            source_ids: path.source_ids.clone(),
+            xyz,
+            query: query.clone(),
+            partition: src.cache_partition.clone(),
+        };
+        (empty_tiles, key)
+    });
+    let mut response = match &empty {
+        Some((empty_tiles, key)) if empty_tiles.contains(key) => src.get_empty_response(xyz),
+        _ => {
+            // The deadline becomes the `statement_timeout` of the tile query. Each request runs its own query,
+            // and the failed ones are not cached, so a tight client deadline never affects the other requests.
+            // The request headers give the values of the `session_headers` of the Postgres sources.
+            let deadline = get_request_deadline(&req, &srv_config);
+            let fetch = src.get_checked_http_response(xyz);
+            let fetch = with_deadline(deadline, with_request_headers(req.headers().clone(), fetch));
+            let (response, complete) = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), fetch)
+                    .await
+                    .map_err(|_| {
+                        ErrorServiceUnavailable(
+                            "The tile was not ready before the request deadline",
+                        )
+                    })??,
+                None => fetch.await?,
+            };
+            // A tile that is only empty because some sources failed may not be empty next time
+            if let Some((empty_tiles, key)) = &empty {
+                if complete && response.status() == StatusCode::NO_CONTENT {
+                    empty_tiles.insert(key.clone()).await;
+                }
+            }
+            response
+        }
+    };
+    if transcode_webp || !alt_formats.is_empty() {
+        // The response format depends on the client's Accept header
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept"));
+    }
+    if let Some(CachePartition::Header(name)) = &srv_config.cache_partition {
+        if let Ok(value) = HeaderValue::from_str(name) {
+            response.headers_mut().append(VARY, value);
+        }
+    }
+    Ok(response)
 }
 
+// The flags are independent settings of the server, not the states of the source
+#[allow(clippy::struct_excessive_bools)]
 pub struct DynTileSource<'a> {
     pub sources: Vec<&'a dyn Source>,
     pub info: TileInfo,
@@ -70,19 +177,75 @@ pub struct DynTileSource<'a> {
     pub accept_enc: Option<AcceptEncoding>,
     pub preferred_enc: Option<PreferredEncoding>,
     pub cache: Option<&'a MainCache>,
+    /// Add a `Server-Timing` header with the duration of each tile generation phase
+    pub server_timing: bool,
+    /// Add an `X-Martin-Encoding` header with the encoding of the response, and how it was produced
+    pub encoding_header: bool,
+    /// Add a `Link` header with the adjacent tiles for the clients to prefetch
+    pub prefetch_links: bool,
+    /// Never compress the uncompressed tiles, e.g. if a proxy compresses the responses instead
+    pub disable_compression: bool,
+    /// What to do if the client accepts none of the supported encodings
+    pub encoding_fallback: EncodingFallback,
+    /// Treat the sources that fail to produce a tile as empty instead of failing the whole request
+    pub best_effort: bool,
+    /// Evict the cached source tiles that cannot be decompressed, so that the next request fetches them again
+    pub evict_invalid_tiles: bool,
+    /// Transcode the PNG and JPEG tiles to WebP
+    pub transcode_webp: bool,
+    /// Get the tiles in this alternative format of the sources instead of their own one
+    pub alt_format: Option<Format>,
+    /// Cache the tiles separately from the requests of the other partitions, e.g. tenants
+    pub cache_partition: Option<String>,
+    /// Wait for a share of the CPU before (re-)encoding the tiles
+    pub cpu_budget: Option<&'a CpuBudget>,
+    /// Extra headers configured for the sources, added to the response
+    pub response_headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Time spent in each phase of the tile generation
+#[derive(Debug, Default, Clone, Copy)]
+struct Timings {
+    /// Getting the tiles from the sources or the cache
+    db: Duration,
+    /// Combining multiple tiles into one
+    merge: Duration,
+    /// Decoding and encoding the tile to match the client's accepted encodings
+    encode: Duration,
+}
+
+/// A tile with the details of how it was produced
+struct TimedTile {
+    tile: Tile,
+    timings: Timings,
+    /// Encoding of the tile before (re-)compressing it
+    source_enc: Encoding,
+    /// Whether all sources produced their tile, i.e. no failure was hidden by `best_effort`
+    complete: bool,
+}
+
+impl Timings {
+    fn to_header_value(self) -> String {
+        format!(
+            "db;dur={:.3}, merge;dur={:.3}, encode;dur={:.3}",
+            self.db.as_secs_f64() * 1000.0,
+            self.merge.as_secs_f64() * 1000.0,
+            self.encode.as_secs_f64() * 1000.0,
+        )
+    }
 }
 
 impl<'a> DynTileSource<'a> {
     pub fn new(
         sources: &'a TileSources,
         source_ids: &str,
-        zoom: Option<u8>,
+        xyz: Option<TileCoord>,
         query: &'a str,
         accept_enc: Option<AcceptEncoding>,
         preferred_enc: Option<PreferredEncoding>,
         cache: Option<&'a MainCache>,
     ) -> ActixResult<Self> {
-        let (sources, use_url_query, info) = sources.get_sources(source_ids, zoom)?;
+        let (sources, use_url_query, info) = sources.get_sources(source_ids, xyz)?;
 
         if sources.is_empty() {
             return Err(ErrorNotFound("No valid sources found"));
@@ -103,43 +266,201 @@ impl<'a> DynTileSource<'a> {
             accept_enc,
             preferred_enc,
             cache,
+            server_timing: false,
+            encoding_header: false,
+            prefetch_links: false,
+            disable_compression: false,
+            encoding_fallback: EncodingFallback::default(),
+            best_effort: false,
+            evict_invalid_tiles: false,
+            transcode_webp: false,
+            alt_format: None,
+            cache_partition: None,
+            cpu_budget: None,
+            response_headers: Vec::new(),
         })
     }
 
+    /// Alternative formats supported by all the sources
+    #[must_use]
+    pub fn get_alt_formats(&self) -> Vec<Format> {
+        let mut formats = self.sources[0].get_alt_formats();
+        for src in &self.sources[1..] {
+            let src_formats = src.get_alt_formats();
+            formats.retain(|f| src_formats.contains(f));
+        }
+        formats
+    }
+
+    /// Select the tile format with the client's `Accept` header: either the format of the sources,
+    /// or one of their alternative formats. No header or a wildcard selects the format of the sources.
+    pub fn select_format(
+        &mut self,
+        accept: Option<&Accept>,
+        alt_formats: &[Format],
+        transcode_webp: bool,
+    ) -> ActixResult<()> {
+        let Some(accept) = accept.filter(|v| !v.is_empty()) else {
+            return Ok(());
+        };
+        let mut ranked: Vec<_> = accept
+            .iter()
+            .filter(|v| v.quality > Quality::ZERO)
+            .collect();
+        // stable sort keeps the listed order of the items with the same quality
+        ranked.sort_by_key(|v| Reverse(v.quality));
+
+        let format = self.info.format;
+        for item in ranked {
+            let media_type = item.item.essence_str();
+            if is_media_type_of(format, media_type)
+                || matches_wildcard(format, media_type)
+                || (transcode_webp && media_type == "image/webp")
+            {
+                return Ok(());
+            }
+            if let Some(alt) = alt_formats
+                .iter()
+                .find(|f| is_media_type_of(**f, media_type))
+            {
+                self.alt_format = Some(*alt);
+                self.info = TileInfo::new(*alt, Encoding::Uncompressed);
+                return Ok(());
+            }
+        }
+
+        let supported = std::iter::once(format)
+            .chain(alt_formats.iter().copied())
+            .map(|f| f.content_type().to_string())
+            .join(", ");
+        Err(ErrorNotAcceptable(format!(
+            "The tiles are only available as {supported}"
+        )))
+    }
+
     pub async fn get_http_response(&self, xyz: TileCoord) -> ActixResult<HttpResponse> {
-        let tile = self.get_tile_content(xyz).await?;
+        Ok(self.get_checked_http_response(xyz).await?.0)
+    }
+
+    /// Get the response, and whether all sources produced their tile
+    async fn get_checked_http_response(&self, xyz: TileCoord) -> ActixResult<(HttpResponse, bool)> {
+        let TimedTile {
+            tile,
+            timings,
+            source_enc,
+            complete,
+        } = self.get_timed_tile_content(xyz).await?;
+
+        let mut response = if tile.data.is_empty() {
+            HttpResponse::NoContent()
+        } else {
+            HttpResponse::Ok()
+        };
+        self.insert_headers(&mut response, xyz, timings, source_enc, tile.info.encoding);
 
-        Ok(if tile.data.is_empty() {
-            HttpResponse::NoContent().finish()
+        let response = if tile.data.is_empty() {
+            response.finish()
         } else {
-            let mut response = HttpResponse::Ok();
-            response.content_type(tile.info.format.content_type());
+            if self.alt_format == Some(Format::Json) {
+                response.content_type(GEOJSON_CONTENT_TYPE);
+            } else {
+                response.content_type(tile.info.format.content_type());
+            }
             if let Some(val) = tile.info.encoding.content_encoding() {
                 response.insert_header((CONTENT_ENCODING, val));
             }
             response.body(tile.data)
-        })
+        };
+        Ok((response, complete))
+    }
+
+    /// The response to a tile that is known to be empty, with the same headers as if it was produced again
+    #[must_use]
+    pub fn get_empty_response(&self, xyz: TileCoord) -> HttpResponse {
+        let mut response = HttpResponse::NoContent();
+        let encoding = self.info.encoding;
+        self.insert_headers(&mut response, xyz, Timings::default(), encoding, encoding);
+        response.finish()
+    }
+
+    /// Add the headers that do not depend on the tile data
+    fn insert_headers(
+        &self,
+        response: &mut HttpResponseBuilder,
+        xyz: TileCoord,
+        timings: Timings,
+        source_enc: Encoding,
+        encoding: Encoding,
+    ) {
+        if self.server_timing {
+            response.insert_header((SERVER_TIMING.clone(), timings.to_header_value()));
+        }
+        if self.encoding_header {
+            let value = encoding_header_value(source_enc, encoding);
+            response.insert_header((X_MARTIN_ENCODING.clone(), value));
+        }
+        if self.prefetch_links {
+            let links = self.prefetch_links_value(xyz);
+            if !links.is_empty() {
+                response.insert_header((LINK, links));
+            }
+        }
+        for header in &self.response_headers {
+            response.append_header(header.clone());
+        }
     }
 
     pub async fn get_tile_content(&self, xyz: TileCoord) -> ActixResult<Tile> {
-        let mut tiles = try_join_all(self.sources.iter().map(|s| async {
+        Ok(self.get_timed_tile_content(xyz).await?.tile)
+    }
+
+    /// Get the tile of each source, and whether all of them succeeded. With `best_effort`,
+    /// the sources that fail are treated as empty instead of failing the whole request.
+    async fn get_source_tiles(&self, xyz: TileCoord) -> ActixResult<(Vec<TileData>, bool)> {
+        let requests = self.sources.iter().map(|s| async {
+            if let Some(format) = self.alt_format {
+                return get_or_insert_cached_value!(
+                    self.cache,
+                    CacheValue::Tile,
+                    s.get_alt_tile(xyz, self.query_obj.as_ref(), format),
+                    self.tile_cache_key(*s, xyz)
+                );
+            }
             get_or_insert_cached_value!(
                 self.cache,
                 CacheValue::Tile,
                 s.get_tile(xyz, self.query_obj.as_ref()),
-                {
-                    let id = s.get_id().to_string();
-                    if let Some(query_str) = self.query_str {
-                        CacheKey::TileWithQuery(id, xyz, query_str.to_string())
-                    } else {
-                        CacheKey::Tile(id, xyz)
-                    }
-                }
+                self.tile_cache_key(*s, xyz)
             )
-        }))
-        .await
-        .map_err(map_internal_error)?;
+        });
+        let mut complete = true;
+        let tiles = if self.best_effort {
+            join_all(requests)
+                .await
+                .into_iter()
+                .zip(&self.sources)
+                .map(|(tile, s)| {
+                    tile.unwrap_or_else(|e| {
+                        warn!("Treating tile {xyz} of source {} as empty: {e}", s.get_id());
+                        complete = false;
+                        Vec::new()
+                    })
+                })
+                .collect()
+        } else {
+            try_join_all(requests).await.map_err(map_tile_error)?
+        };
+        Ok((tiles, complete))
+    }
+
+    /// Get the tile with the time spent in each phase, and the encoding it had before (re-)compressing it
+    async fn get_timed_tile_content(&self, xyz: TileCoord) -> ActixResult<TimedTile> {
+        let mut timings = Timings::default();
+        let start = Instant::now();
+        let (mut tiles, complete) = self.get_source_tiles(xyz).await?;
+        timings.db = start.elapsed();
 
+        let start = Instant::now();
         let mut layer_count = 0;
         let mut last_non_empty_layer = 0;
         for (idx, tile) in tiles.iter().enumerate() {
@@ -152,7 +473,14 @@ impl<'a> DynTileSource<'a> {
         // Minor optimization to prevent concatenation if there are less than 2 tiles
         let data = match layer_count {
             1 => tiles.swap_remove(last_non_empty_layer),
-            0 => return Ok(Tile::new(Vec::new(), self.info)),
+            0 => {
+                return Ok(TimedTile {
+                    tile: Tile::new(Vec::new(), self.info),
+                    timings,
+                    source_enc: self.info.encoding,
+                    complete,
+                })
+            }
             _ => {
                 // Make sure tiles can be concatenated, or if not, that there is only one non-empty tile for each zoom level
                 // TODO: can zlib, brotli, or zstd be concatenated?
@@ -167,61 +495,118 @@ impl<'a> DynTileSource<'a> {
                         xyz.z
                     )))?;
                 }
-                tiles.concat()
+                if self.info.encoding == Encoding::Gzip {
+                    match join_gzip_tiles(&tiles).map_err(invalid_tile_error) {
+                        Ok(data) => data,
+                        Err(e) => return Err(self.on_invalid_tile(xyz, e).await),
+                    }
+                } else {
+                    tiles.concat()
+                }
             }
         };
 
+        timings.merge = start.elapsed();
+
         // decide if (re-)encoding of the tile data is needed, and recompress if so
-        self.recompress(data)
-    }
-
-    /// Decide which encoding to use for the uncompressed tile data, based on the client's Accept-Encoding header
-    fn decide_encoding(&self, accept_enc: &AcceptEncoding) -> ActixResult<Option<ContentEncoding>> {
-        let mut q_gzip = None;
-        let mut q_brotli = None;
-        for enc in accept_enc.iter() {
-            if let Preference::Specific(HeaderEnc::Known(e)) = enc.item {
-                match e {
-                    ContentEncoding::Gzip => q_gzip = Some(enc.quality),
-                    ContentEncoding::Brotli => q_brotli = Some(enc.quality),
-                    _ => {}
-                }
-            } else if let Preference::Any = enc.item {
-                q_gzip.get_or_insert(enc.quality);
-                q_brotli.get_or_insert(enc.quality);
-            }
-        }
-        Ok(match (q_gzip, q_brotli) {
-            (Some(q_gzip), Some(q_brotli)) if q_gzip == q_brotli => {
-                if q_gzip > Quality::ZERO {
-                    Some(self.get_preferred_enc())
-                } else {
-                    None
-                }
-            }
-            (Some(q_gzip), Some(q_brotli)) if q_brotli > q_gzip => Some(ContentEncoding::Brotli),
-            (Some(_), Some(_)) => Some(ContentEncoding::Gzip),
-            _ => {
-                if let Some(HeaderEnc::Known(enc)) = accept_enc.negotiate(SUPPORTED_ENC.iter()) {
-                    Some(enc)
+        let start = Instant::now();
+        let encode = async {
+            let tile =
+                if self.transcode_webp && matches!(self.info.format, Format::Png | Format::Jpeg) {
+                    self.transcode_to_webp(xyz, data).await?
                 } else {
-                    return Err(ErrorNotAcceptable("No supported encoding found"));
-                }
+                    Tile::new(data, self.info)
+                };
+            let source_enc = tile.info.encoding;
+            MartinResult::Ok((self.recompress(tile)?, source_enc))
+        };
+        let encoded = run_in_budget(self.cpu_budget, Workload::Tiles, encode).await;
+        let (tile, source_enc) = match encoded {
+            Ok(v) => v,
+            Err(e @ MartinError::InvalidTile(_)) => return Err(self.on_invalid_tile(xyz, e).await),
+            Err(MartinError::WebError(e)) => return Err(e),
+            Err(e) => return Err(map_internal_error(e)),
+        };
+        timings.encode = start.elapsed();
+
+        Ok(TimedTile {
+            tile,
+            timings,
+            source_enc,
+            complete,
+        })
+    }
+
+    /// Convert the raster tile to WebP, caching the result separately from the original tiles
+    async fn transcode_to_webp(&self, xyz: TileCoord, data: TileData) -> MartinResult<Tile> {
+        let format = Format::Webp;
+        let data = get_or_insert_cached_value!(
+            self.cache,
+            CacheValue::Tile,
+            async { encode_webp(&data).map_err(MartinError::from) },
+            {
+                let ids = self.sources.iter().map(|s| s.get_id()).join(",");
+                let query_str = self.query_str.map(str::to_string);
+                self.cache_key(CacheKey::TranscodedTile(ids, xyz, query_str, format))
             }
+        )?;
+        Ok(Tile::new(data, TileInfo::new(format, Encoding::Internal)))
+    }
+
+    /// List the adjacent tiles at the same zoom level, wrapping around the antimeridian.
+    /// The links are relative to the tile URL, so that they also work behind a reverse proxy.
+    fn prefetch_links_value(&self, xyz: TileCoord) -> String {
+        let size = 1_u64 << xyz.z.min(32);
+        let (x, y) = (u64::from(xyz.x), u64::from(xyz.y));
+        let query = self.query_str.map_or(String::new(), |q| format!("?{q}"));
+        [
+            ((x + size - 1) % size, Some(y)),
+            ((x + 1) % size, Some(y)),
+            (x, y.checked_sub(1)),
+            (x, Some(y + 1).filter(|v| *v < size)),
+        ]
+        .into_iter()
+        .filter_map(|(x, y)| Some((x, y?)))
+        .filter(|(nx, ny)| (*nx, *ny) != (x, y))
+        .unique()
+        .map(|(x, y)| format!("<../{x}/{y}{query}>; rel=prefetch"))
+        .join(", ")
+    }
+
+    /// Cache key of the tile of a single source, as stored before merging and re-compressing
+    fn tile_cache_key(&self, source: &dyn Source, xyz: TileCoord) -> CacheKey {
+        let id = source.get_id().to_string();
+        let query_str = self.query_str.map(str::to_string);
+        self.cache_key(match (self.alt_format, query_str) {
+            (Some(format), query_str) => CacheKey::AltFormatTile(id, xyz, query_str, format),
+            (None, Some(query_str)) => CacheKey::TileWithQuery(id, xyz, query_str),
+            (None, None) => CacheKey::Tile(id, xyz),
         })
     }
 
-    fn get_preferred_enc(&self) -> ContentEncoding {
-        match self.preferred_enc {
-            None | Some(PreferredEncoding::Gzip) => ContentEncoding::Gzip,
-            Some(PreferredEncoding::Brotli) => ContentEncoding::Brotli,
+    /// Log a stored tile that cannot be decompressed, and evict it from the cache if configured
+    async fn on_invalid_tile(&self, xyz: TileCoord, err: MartinError) -> actix_web::Error {
+        let ids = self.sources.iter().map(|s| s.get_id()).join(",");
+        warn!("Tile {xyz} of source {ids} is invalid: {err}");
+        if let (true, Some(cache)) = (self.evict_invalid_tiles, self.cache) {
+            for source in &self.sources {
+                cache.invalidate(&self.tile_cache_key(*source, xyz)).await;
+            }
         }
+        ErrorBadGateway(err.to_string())
     }
 
-    fn recompress(&self, tile: TileData) -> ActixResult<Tile> {
-        let mut tile = Tile::new(tile, self.info);
+    /// Keep the cached tiles of each partition apart
+    fn cache_key(&self, key: CacheKey) -> CacheKey {
+        match &self.cache_partition {
+            Some(partition) => CacheKey::Partitioned(partition.clone(), Box::new(key)),
+            None => key,
+        }
+    }
+
+    fn recompress(&self, mut tile: Tile) -> MartinResult<Tile> {
         if let Some(accept_enc) = &self.accept_enc {
-            if self.info.encoding.is_encoded() {
+            if tile.info.encoding.is_encoded() {
                 // already compressed, see if we can send it as is, or need to re-compress
                 if !accept_enc.iter().any(|e| {
                     if let Preference::Specific(HeaderEnc::Known(enc)) = e.item {
@@ -235,8 +620,10 @@ impl<'a> DynTileSource<'a> {
                 }
             }
 
-            if tile.info.encoding == Encoding::Uncompressed {
-                if let Some(enc) = self.decide_encoding(accept_enc)? {
+            if tile.info.encoding == Encoding::Uncompressed && !self.disable_compression {
+                if let Some(enc) =
+                    decide_encoding(accept_enc, self.preferred_enc, self.encoding_fallback)?
+                {
                     // (re-)compress the tile into the preferred encoding
                     tile = encode(tile, enc)?;
                 }
@@ -250,7 +637,139 @@ impl<'a> DynTileSource<'a> {
     }
 }
 
-fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
+/// Describe the final encoding of a tile, and whether it was sent as is, re-compressed, or compressed
+fn encoding_header_value(source_enc: Encoding, final_enc: Encoding) -> String {
+    let action = if source_enc == final_enc {
+        "passthrough"
+    } else if source_enc.is_encoded() {
+        "transcoded"
+    } else {
+        "compressed"
+    };
+    let name = final_enc.content_encoding().unwrap_or("identity");
+    format!("{name}; {action}")
+}
+
+/// Decide how to compress a response that is generated uncompressed, e.g. the catalog, a `TileJSON`,
+/// or the glyphs, the same way as the uncompressed tiles. Returns `None` to send it uncompressed.
+pub fn negotiate_encoding(
+    req: &HttpRequest,
+    srv_config: &SrvConfig,
+) -> ActixResult<Option<ContentEncoding>> {
+    if srv_config.disable_compression.unwrap_or_default() {
+        return Ok(None);
+    }
+    let Some(accept_enc) = req.get_header::<AcceptEncoding>() else {
+        return Ok(None);
+    };
+    let fallback = srv_config.encoding_fallback.unwrap_or_default();
+    decide_encoding(&accept_enc, srv_config.preferred_encoding, fallback)
+}
+
+/// Compress the data that is generated uncompressed, e.g. a `TileJSON`, with the negotiated encoding,
+/// and respond with it
+pub fn negotiated_response(
+    req: &HttpRequest,
+    srv_config: &SrvConfig,
+    content_type: impl TryIntoHeaderValue,
+    mut data: Tile,
+) -> ActixResult<HttpResponse> {
+    if let Some(enc) = negotiate_encoding(req, srv_config)? {
+        data = encode(data, enc)?;
+    }
+    Ok(encoded_response(content_type, data.info.encoding).body(data.data))
+}
+
+/// Start the response with the data compressed with the negotiated encoding, which depends on the
+/// client's `Accept-Encoding` header
+pub fn encoded_response(
+    content_type: impl TryIntoHeaderValue,
+    encoding: Encoding,
+) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .insert_header((VARY, "Accept-Encoding"));
+    if let Some(val) = encoding.content_encoding() {
+        response.insert_header((CONTENT_ENCODING, val));
+    }
+    response
+}
+
+/// Decide which encoding to use for the uncompressed data, based on the client's Accept-Encoding header.
+/// Returns `None` to send the data uncompressed, which is only done for the clients that forbid it
+/// with `identity;q=0` if the `fallback` allows it.
+pub fn decide_encoding(
+    accept_enc: &AcceptEncoding,
+    preferred_enc: Option<PreferredEncoding>,
+    fallback: EncodingFallback,
+) -> ActixResult<Option<ContentEncoding>> {
+    let mut q_gzip = None;
+    let mut q_brotli = None;
+    for enc in accept_enc.iter() {
+        if let Preference::Specific(HeaderEnc::Known(e)) = enc.item {
+            match e {
+                ContentEncoding::Gzip => q_gzip = Some(enc.quality),
+                ContentEncoding::Brotli => q_brotli = Some(enc.quality),
+                _ => {}
+            }
+        } else if let Preference::Any = enc.item {
+            q_gzip.get_or_insert(enc.quality);
+            q_brotli.get_or_insert(enc.quality);
+        }
+    }
+    let enc = match (q_gzip, q_brotli) {
+        (Some(q_gzip), Some(q_brotli)) if q_gzip == q_brotli => {
+            if q_gzip > Quality::ZERO {
+                Some(match preferred_enc {
+                    None | Some(PreferredEncoding::Gzip) => ContentEncoding::Gzip,
+                    Some(PreferredEncoding::Brotli) => ContentEncoding::Brotli,
+                })
+            } else {
+                None
+            }
+        }
+        (Some(q_gzip), Some(q_brotli)) if q_brotli > q_gzip => Some(ContentEncoding::Brotli),
+        (Some(_), Some(_)) => Some(ContentEncoding::Gzip),
+        _ => match accept_enc.negotiate(SUPPORTED_ENC.iter()) {
+            Some(HeaderEnc::Known(enc)) if enc != ContentEncoding::Identity => Some(enc),
+            _ => None,
+        },
+    };
+    if enc.is_none() && !accepts_identity(accept_enc) {
+        match fallback {
+            EncodingFallback::NotAcceptable => {
+                return Err(ErrorNotAcceptable(
+                    "The client accepts none of the supported encodings: gzip, br, or identity",
+                ));
+            }
+            EncodingFallback::Identity => {
+                debug!(
+                    "The client accepts none of the supported encodings, sending uncompressed data"
+                );
+            }
+        }
+    }
+    Ok(enc)
+}
+
+/// Check if the client accepts uncompressed data, which it does unless it is excluded explicitly
+/// with `identity;q=0`, or with `*;q=0` without listing `identity`
+fn accepts_identity(accept_enc: &AcceptEncoding) -> bool {
+    let mut any = None;
+    for enc in accept_enc.iter() {
+        match enc.item {
+            Preference::Specific(HeaderEnc::Known(ContentEncoding::Identity)) => {
+                return enc.quality > Quality::ZERO;
+            }
+            Preference::Any => any = Some(enc.quality),
+            Preference::Specific(_) => {}
+        }
+    }
+    any.map_or(true, |q| q > Quality::ZERO)
+}
+
+pub fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
     Ok(match enc {
         ContentEncoding::Brotli => Tile::new(
             encode_brotli(&tile.data)?,
@@ -263,16 +782,16 @@ fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
     })
 }
 
-fn decode(tile: Tile) -> ActixResult<Tile> {
+fn decode(tile: Tile) -> MartinResult<Tile> {
     let info = tile.info;
     Ok(if info.encoding.is_encoded() {
         match info.encoding {
             Encoding::Gzip => Tile::new(
-                decode_gzip(&tile.data)?,
+                decode_gzip(&tile.data).map_err(invalid_tile_error)?,
                 info.encoding(Encoding::Uncompressed),
             ),
             Encoding::Brotli => Tile::new(
-                decode_brotli(&tile.data)?,
+                decode_brotli(&tile.data).map_err(invalid_tile_error)?,
                 info.encoding(Encoding::Uncompressed),
             ),
             _ => Err(ErrorBadRequest(format!(
@@ -284,6 +803,193 @@ fn decode(tile: Tile) -> ActixResult<Tile> {
     })
 }
 
+/// A stored tile that cannot be decompressed, e.g. a gzip stream truncated by a crashed writer,
+/// is an error of the upstream source rather than of Martin
+fn invalid_tile_error<T: std::fmt::Display>(e: T) -> MartinError {
+    MartinError::InvalidTile(e.to_string())
+}
+
+/// Get the cache partition of the request, or `None` if the request has no such header or query parameter
+fn get_cache_partition(req: &HttpRequest, partition: &CachePartition) -> Option<String> {
+    match partition {
+        CachePartition::Header(name) => {
+            let value = req.headers().get(name.as_str())?;
+            value.to_str().ok().map(str::to_string)
+        }
+        CachePartition::QueryParam(name) => Query::<UrlQuery>::from_query(req.query_string())
+            .ok()?
+            .into_inner()
+            .remove(name),
+    }
+}
+
+/// Get the encoding forced with the `encoding` query parameter, e.g. `?encoding=br`
+fn get_encoding_param(req: &HttpRequest) -> ActixResult<Option<HeaderEnc>> {
+    let Some(value) = Query::<UrlQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.into_inner().remove(ENCODING_PARAM))
+    else {
+        return Ok(None);
+    };
+    match value.parse::<HeaderEnc>() {
+        Ok(enc) if SUPPORTED_ENC.contains(&enc) => Ok(Some(enc)),
+        _ => Err(ErrorBadRequest(format!(
+            "Unsupported encoding {value}, expected one of gzip, br, or identity"
+        ))),
+    }
+}
+
+/// Remove the query parameters that control the server rather than the tile, i.e. `encoding`
+/// and the `cache_partition` parameter, so that they neither reach the sources nor split their cache keys
+fn strip_control_params(query: &str, srv_config: &SrvConfig) -> String {
+    let partition = match &srv_config.cache_partition {
+        Some(CachePartition::QueryParam(name)) => Some(name.as_str()),
+        _ => None,
+    };
+    query
+        .split('&')
+        .filter(|pair| {
+            url::form_urlencoded::parse(pair.as_bytes())
+                .next()
+                .is_some_and(|(name, _)| name != ENCODING_PARAM && Some(name.as_ref()) != partition)
+        })
+        .join("&")
+}
+
+/// Get the moment the client stops waiting for the tile, based on the number of milliseconds
+/// in the `X-Request-Deadline` header and the `request_timeout_ms` setting, whichever is smaller.
+/// Both are capped at [`MAX_DEADLINE_MS`], so that a huge client value cannot overflow the deadline.
+fn get_request_deadline(req: &HttpRequest, srv_config: &SrvConfig) -> Option<Instant> {
+    let header = req
+        .headers()
+        .get(&X_REQUEST_DEADLINE)
+        .and_then(|v| v.to_str().ok()?.trim().parse::<u64>().ok());
+    let budget_ms = match (header, srv_config.request_timeout_ms) {
+        (Some(a), Some(b)) => a.min(b),
+        (a, b) => a.or(b)?,
+    };
+    Instant::now().checked_add(Duration::from_millis(budget_ms.min(MAX_DEADLINE_MS)))
+}
+
+/// Check if all requested sources are configured with `transcode_webp`
+fn is_webp_source(source_ids: &str, srv_config: &SrvConfig) -> bool {
+    srv_config
+        .transcode_webp
+        .as_ref()
+        .is_some_and(|ids| source_ids.split(',').all(|id| ids.iter().any(|v| v == id)))
+}
+
+/// Check if the client explicitly accepts WebP images. A wildcard is not enough,
+/// as the older browsers send `*/*` without being able to decode WebP.
+fn accepts_webp(accept: Option<Accept>) -> bool {
+    accept.is_some_and(|accept| {
+        accept
+            .iter()
+            .any(|v| v.quality > Quality::ZERO && v.item.essence_str() == "image/webp")
+    })
+}
+
+/// Check if the media type of the `Accept` header, e.g. `application/geo+json`, is the one of the tile format
+fn is_media_type_of(format: Format, media_type: &str) -> bool {
+    media_type == format.content_type()
+        || match format {
+            Format::Json => media_type == GEOJSON_CONTENT_TYPE,
+            Format::Mvt => media_type == "application/vnd.mapbox-vector-tile",
+            _ => false,
+        }
+}
+
+/// Check if the media type is a wildcard matching the tile format, e.g. `*/*` or `image/*` for PNG tiles
+fn matches_wildcard(format: Format, media_type: &str) -> bool {
+    media_type == "*/*"
+        || media_type
+            .strip_suffix("/*")
+            .is_some_and(|typ| format.content_type().split('/').next() == Some(typ))
+}
+
+/// Make sure all sources configured with `transcode_webp` are PNG or JPEG sources
+pub fn check_webp_sources(sources: &TileSources, source_ids: &[String]) -> MartinResult<()> {
+    for id in source_ids {
+        let Ok(src) = sources.get_source(id) else {
+            warn!("Source {id} is configured to be transcoded to WebP, but it does not exist");
+            continue;
+        };
+        let info = src.get_tile_info();
+        if !matches!(info.format, Format::Png | Format::Jpeg) {
+            return Err(MartinError::WebpTranscodeUnsupported(id.clone(), info));
+        }
+    }
+    Ok(())
+}
+
+/// Headers that Martin sets on the tile responses itself, and that cannot be configured per source
+static MANAGED_HEADERS: &[HeaderName] = &[CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY];
+
+/// Make sure the response headers of the sources are valid, and do not replace the ones Martin manages
+pub fn check_response_headers(
+    sources: &TileSources,
+    headers: &BTreeMap<String, BTreeMap<String, String>>,
+) -> MartinResult<()> {
+    for (id, headers) in headers {
+        if sources.get_source(id).is_err() {
+            warn!("Source {id} is configured with response headers, but it does not exist");
+        }
+        for (name, value) in headers {
+            let is_valid = HeaderName::try_from(name.as_str())
+                .is_ok_and(|name| !MANAGED_HEADERS.contains(&name))
+                && HeaderValue::try_from(value.as_str()).is_ok();
+            if !is_valid {
+                return Err(MartinError::InvalidResponseHeader(id.clone(), name.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the extra headers of all requested sources, skipping the duplicates
+fn get_response_headers(
+    source_ids: &str,
+    srv_config: &SrvConfig,
+) -> Vec<(HeaderName, HeaderValue)> {
+    let Some(headers) = &srv_config.response_headers else {
+        return Vec::new();
+    };
+    source_ids
+        .split(',')
+        .filter_map(|id| headers.get(id))
+        .flatten()
+        .filter_map(|(name, value)| {
+            // The headers are validated when the config is loaded
+            Some((
+                HeaderName::try_from(name.as_str()).ok()?,
+                HeaderValue::try_from(value.as_str()).ok()?,
+            ))
+        })
+        .unique()
+        .collect()
+}
+
+/// Map tile source errors to HTTP errors. Errors caused by a temporary overload are reported
+/// as `503 Service Unavailable` so that the clients may retry later.
+fn map_tile_error(e: MartinError) -> actix_web::Error {
+    #[cfg(feature = "postgres")]
+    if let MartinError::PostgresError(
+        crate::pg::PgError::PoolTimeout(..) | crate::pg::PgError::QueryTimeout(..),
+    ) = e
+    {
+        warn!("{e}");
+        return ErrorServiceUnavailable(e.to_string());
+    }
+    #[cfg(feature = "postgres")]
+    if let MartinError::PostgresError(
+        crate::pg::PgError::InvalidQueryParam(..) | crate::pg::PgError::MissingQueryParam(..),
+    ) = e
+    {
+        return ErrorBadRequest(e.to_string());
+    }
+    map_internal_error(e)
+}
+
 pub fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
     Some(match val {
         ContentEncoding::Identity => Encoding::Uncompressed,
@@ -294,13 +1000,58 @@ pub fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
     })
 }
 
+/// Concatenated gzip streams form a valid multi-member gzip stream, but many decoders stop after the first member.
+/// Instead, decompress each tile, concatenate the MVT layers, and compress the result as a single gzip member.
+fn join_gzip_tiles(tiles: &[TileData]) -> Result<TileData, std::io::Error> {
+    let mut data = Vec::new();
+    for tile in tiles.iter().filter(|t| !t.is_empty()) {
+        data.extend(decode_gzip(tile)?);
+    }
+    encode_gzip(&data)
+}
+
 #[cfg(test)]
 mod tests {
+    use actix_http::Request;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::header::ACCEPT_ENCODING;
+    use actix_web::http::Method;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use async_trait::async_trait;
     use rstest::rstest;
-    use tilejson::tilejson;
+    use tilejson::{tilejson, TileJSON};
 
     use super::*;
+    use crate::source::TileInfoSource;
     use crate::srv::server::tests::TestSource;
+    use crate::test_utils::some;
+    use crate::MemorySource;
+
+    /// The `test_source` that produces the same small MVT tile at every coordinate
+    fn test_sources() -> TileSources {
+        TileSources::new(vec![vec![Box::new(TestSource {
+            id: "test_source",
+            tj: tilejson! { tiles: vec![] },
+            data: vec![1_u8, 2, 3],
+        })]])
+    }
+
+    /// Serve the tiles of the sources like the server does, with the given config and main cache
+    async fn test_app(
+        sources: TileSources,
+        srv_config: SrvConfig,
+        cache: OptMainCache,
+    ) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+        init_service(
+            App::new()
+                .app_data(Data::new(sources))
+                .app_data(Data::new(srv_config))
+                .app_data(Data::new(cache))
+                .service(get_tile),
+        )
+        .await
+    }
 
     #[actix_rt::test]
     async fn test_deleteme() {
@@ -321,11 +1072,7 @@ mod tests {
         #[case] preferred_enc: Option<PreferredEncoding>,
         #[case] expected_enc: Encoding,
     ) {
-        let sources = TileSources::new(vec![vec![Box::new(TestSource {
-            id: "test_source",
-            tj: tilejson! { tiles: vec![] },
-            data: vec![1_u8, 2, 3],
-        })]]);
+        let sources = test_sources();
 
         let accept_enc = Some(AcceptEncoding(
             accept_enc.iter().map(|s| s.parse().unwrap()).collect(),
@@ -347,6 +1094,45 @@ mod tests {
         assert_eq!(tile.info.encoding, expected_enc);
     }
 
+    #[rstest]
+    #[case("gzip;q=0, br;q=0, identity;q=0")]
+    #[case("*;q=0")]
+    #[case("deflate, identity;q=0")]
+    fn test_encoding_fallback(#[case] accept_enc: &str) {
+        let accept_enc =
+            AcceptEncoding(accept_enc.split(", ").map(|s| s.parse().unwrap()).collect());
+        let res = decide_encoding(&accept_enc, None, EncodingFallback::NotAcceptable);
+        let status = res.unwrap_err().as_response_error().status_code();
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        let res = decide_encoding(&accept_enc, None, EncodingFallback::Identity);
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[rstest]
+    #[case("deflate")]
+    #[case("gzip;q=0")]
+    #[case("*;q=0, identity")]
+    fn test_identity_acceptable(#[case] accept_enc: &str) {
+        let accept_enc =
+            AcceptEncoding(accept_enc.split(", ").map(|s| s.parse().unwrap()).collect());
+        let res = decide_encoding(&accept_enc, None, EncodingFallback::NotAcceptable);
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_disable_compression() {
+        let sources = test_sources();
+        let accept_enc = Some(AcceptEncoding(vec!["gzip".parse().unwrap()]));
+        let mut src =
+            DynTileSource::new(&sources, "test_source", None, "", accept_enc, None, None).unwrap();
+        src.disable_compression = true;
+
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let tile = src.get_tile_content(xyz).await.unwrap();
+        assert_eq!(tile.info.encoding, Encoding::Uncompressed);
+        assert_eq!(tile.data, vec![1_u8, 2, 3]);
+    }
+
     #[actix_rt::test]
     async fn test_tile_content() {
         let non_empty_source = TestSource {
@@ -379,4 +1165,683 @@ mod tests {
             assert_eq!(expected, &src.get_tile_content(xyz).await.unwrap().data);
         }
     }
+
+    #[actix_rt::test]
+    async fn test_best_effort() {
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let sources = TileSources::new(vec![vec![
+            Box::new(
+                MemorySource::new("ok", tilejson! { tiles: vec![] }, info)
+                    .with_tile(xyz, vec![1_u8, 2, 3]),
+            ),
+            Box::new(
+                MemorySource::new("failing", tilejson! { tiles: vec![] }, info)
+                    .with_error(xyz, "database is down"),
+            ),
+        ]]);
+
+        let mut src =
+            DynTileSource::new(&sources, "ok,failing", None, "", None, None, None).unwrap();
+        assert!(src.get_tile_content(xyz).await.is_err());
+
+        src.best_effort = true;
+        let tile = src.get_tile_content(xyz).await.unwrap();
+        assert_eq!(tile.data, vec![1_u8, 2, 3]);
+
+        let mut src = DynTileSource::new(&sources, "failing", None, "", None, None, None).unwrap();
+        src.best_effort = true;
+        assert!(src.get_tile_content(xyz).await.unwrap().data.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_accept_format() {
+        // A vector source that can also produce its tiles as GeoJSON
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let geojson = br#"{"type":"FeatureCollection","features":[]}"#.to_vec();
+        let src = MemorySource::new("src", tilejson! { tiles: vec![] }, info)
+            .with_tile(xyz, b"mvt".to_vec())
+            .with_alt_tile(Format::Json, xyz, geojson);
+        let sources = TileSources::new(vec![vec![Box::new(src)]]);
+        let cache: OptMainCache = Some(MainCache::builder().build());
+        let app = test_app(sources, SrvConfig::default(), cache).await;
+
+        for (accept, expected) in [
+            (None, Some(("application/x-protobuf", "mvt"))),
+            (Some("*/*"), Some(("application/x-protobuf", "mvt"))),
+            (
+                Some("application/vnd.mapbox-vector-tile"),
+                Some(("application/x-protobuf", "mvt")),
+            ),
+            (
+                Some("application/geo+json"),
+                Some(("application/geo+json", "FeatureCollection")),
+            ),
+            // The MVT tiles are still served from the cache after the GeoJSON ones
+            (
+                Some("application/geo+json;q=0.5, application/x-protobuf"),
+                Some(("application/x-protobuf", "mvt")),
+            ),
+            (Some("image/png"), None),
+        ] {
+            let mut req = TestRequest::get().uri("/src/0/0/0");
+            if let Some(accept) = accept {
+                req = req.insert_header(("Accept", accept));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            let Some((content_type, body)) = expected else {
+                assert_eq!(resp.status(), 406, "{accept:?}");
+                continue;
+            };
+            assert!(resp.status().is_success(), "{accept:?}");
+            assert_eq!(resp.headers().get(VARY).unwrap(), "Accept");
+            assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), content_type);
+            let resp_body = read_body(resp).await;
+            assert!(String::from_utf8_lossy(&resp_body).contains(body));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_server_timing() {
+        let sources = test_sources();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+
+        let mut src =
+            DynTileSource::new(&sources, "test_source", None, "", None, None, None).unwrap();
+        let response = src.get_http_response(xyz).await.unwrap();
+        assert!(response.headers().get(&SERVER_TIMING).is_none());
+
+        src.server_timing = true;
+        let response = src.get_http_response(xyz).await.unwrap();
+        let header = response.headers().get(&SERVER_TIMING).unwrap();
+        let phases: Vec<_> = header
+            .to_str()
+            .unwrap()
+            .split(", ")
+            .map(|v| {
+                let (name, dur) = v.split_once(";dur=").unwrap();
+                assert!(dur.parse::<f64>().unwrap() >= 0.0);
+                name
+            })
+            .collect();
+        assert_eq!(phases, vec!["db", "merge", "encode"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_encoding_header() {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let info = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let tj = tilejson! { tiles: vec![] };
+        let sources = TileSources::new(vec![vec![Box::new(
+            MemorySource::new("gz", tj, info).with_tile(xyz, encode_gzip(b"layer").unwrap()),
+        )]]);
+
+        let get_header = |accept_enc: &str, enabled: bool| {
+            let accept_enc = Some(AcceptEncoding(vec![accept_enc.parse().unwrap()]));
+            let mut src =
+                DynTileSource::new(&sources, "gz", None, "", accept_enc, None, None).unwrap();
+            src.encoding_header = enabled;
+            async move {
+                let response = src.get_http_response(xyz).await.unwrap();
+                let header = response.headers().get(&X_MARTIN_ENCODING);
+                header.map(|v| v.to_str().unwrap().to_string())
+            }
+        };
+
+        assert_eq!(get_header("br", false).await, None);
+        assert_eq!(get_header("br", true).await.unwrap(), "br; transcoded");
+        assert_eq!(get_header("gzip", true).await.unwrap(), "gzip; passthrough");
+        assert_eq!(
+            get_header("identity", true).await.unwrap(),
+            "identity; transcoded"
+        );
+        assert_eq!(
+            encoding_header_value(Encoding::Uncompressed, Encoding::Gzip),
+            "gzip; compressed"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_max_sources_per_request() {
+        let sources = test_sources();
+        let ids = |count| vec!["test_source"; count].join(",");
+        let max = crate::srv::MAX_SOURCES_PER_REQUEST_DEFAULT;
+        assert!(sources.get_sources(&ids(max), None).is_ok());
+        assert!(sources.get_sources(&ids(max + 1), None).is_err());
+
+        let app = test_app(sources.with_max_sources(2), SrvConfig::default(), None).await;
+
+        let req = TestRequest::get()
+            .uri("/test_source,test_source/0/0/0")
+            .to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+
+        let req = TestRequest::get()
+            .uri("/test_source,test_source,test_source/0/0/0")
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body = read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains("max_sources_per_request"));
+    }
+
+    #[cfg(feature = "postgres")]
+    #[actix_rt::test]
+    async fn test_missing_query_param() {
+        let err = crate::pg::PgError::MissingQueryParam("src".to_string(), "year".to_string());
+        let resp = map_tile_error(MartinError::PostgresError(err)).error_response();
+        assert_eq!(resp.status(), 400);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("requires the query parameter year"));
+    }
+
+    #[actix_rt::test]
+    async fn test_transcode_webp() {
+        let mut png = Vec::new();
+        image::RgbaImage::new(2, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let tj = tilejson! { tiles: vec![] };
+        let png_info = TileInfo::new(Format::Png, Encoding::Internal);
+        let mvt_info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let sources = TileSources::new(vec![vec![
+            Box::new(MemorySource::new("raster", tj.clone(), png_info).with_tile(xyz, png.clone())),
+            Box::new(MemorySource::new("vector", tj, mvt_info)),
+        ]]);
+
+        assert!(check_webp_sources(&sources, &["raster".to_string()]).is_ok());
+        assert!(matches!(
+            check_webp_sources(&sources, &["vector".to_string()]),
+            Err(MartinError::WebpTranscodeUnsupported(..))
+        ));
+
+        let srv_config = SrvConfig {
+            transcode_webp: Some(vec!["raster".to_string()]),
+            ..SrvConfig::default()
+        };
+        let cache: OptMainCache = Some(MainCache::builder().build());
+        let app = test_app(sources, srv_config, cache).await;
+
+        // The second WebP request is served from the cache, which must not affect the legacy clients
+        for (accept, webp) in [
+            ("image/webp,*/*", true),
+            ("image/webp,*/*", true),
+            ("image/png,*/*", false),
+            ("*/*", false),
+        ] {
+            let req = TestRequest::get()
+                .uri("/raster/0/0/0")
+                .insert_header(("Accept", accept))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert!(resp.status().is_success());
+            assert_eq!(resp.headers().get(VARY).unwrap(), "Accept");
+            let content_type = resp.headers().get("content-type").unwrap().clone();
+            let body = read_body(resp).await;
+            if webp {
+                assert_eq!(content_type, "image/webp");
+                assert_eq!(&body[..4], b"RIFF");
+                assert_eq!(&body[8..12], b"WEBP");
+            } else {
+                assert_eq!(content_type, "image/png");
+                assert_eq!(body, png);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_cache_partition() {
+        let sources = test_sources();
+        let srv_config = SrvConfig {
+            cache_partition: Some(CachePartition::Header("X-Tenant".to_string())),
+            ..SrvConfig::default()
+        };
+        let cache = MainCache::builder().build();
+        let app = test_app(sources, srv_config, Some(cache.clone())).await;
+
+        for tenant in ["a", "b", "a"] {
+            let req = TestRequest::get()
+                .uri("/test_source/0/0/0")
+                .insert_header(("X-Tenant", tenant))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert!(resp.status().is_success());
+            assert_eq!(resp.headers().get(VARY).unwrap(), "X-Tenant");
+        }
+        cache.run_pending_tasks().await;
+        assert_eq!(cache.entry_count(), 2);
+        for tenant in ["a", "b"] {
+            let key = CacheKey::Tile("test_source".to_string(), TileCoord { z: 0, x: 0, y: 0 });
+            let key = CacheKey::Partitioned(tenant.to_string(), Box::new(key));
+            assert!(cache.contains_key(&key), "{tenant}");
+        }
+
+        let req = TestRequest::get()
+            .uri("/test_source/0/0/0?tenant=c")
+            .to_http_request();
+        let by_param = CachePartition::QueryParam("tenant".to_string());
+        let by_header = CachePartition::Header("X-Tenant".to_string());
+        assert_eq!(get_cache_partition(&req, &by_param), some("c"));
+        assert_eq!(get_cache_partition(&req, &by_header), None);
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[actix_rt::test]
+    async fn test_truncated_gzip_tile(#[case] evict: bool) {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let mut data = encode_gzip(&[7_u8; 100]).unwrap();
+        data.truncate(data.len() / 2);
+        let info = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let src = MemorySource::new("src", tilejson! { tiles: vec![] }, info).with_tile(xyz, data);
+        let srv_config = SrvConfig {
+            evict_invalid_tiles: Some(evict),
+            ..SrvConfig::default()
+        };
+        let sources = TileSources::new(vec![vec![Box::new(src)]]);
+        let cache = MainCache::builder().build();
+        let app = test_app(sources, srv_config, Some(cache.clone())).await;
+
+        // The client does not accept gzip, so the tile must be decompressed
+        let req = TestRequest::get()
+            .uri("/src/0/0/0")
+            .insert_header((ACCEPT_ENCODING, "identity"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 502);
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("cannot be decompressed"), "{body}");
+
+        cache.run_pending_tasks().await;
+        let key = CacheKey::Tile("src".to_string(), xyz);
+        assert_eq!(cache.contains_key(&key), !evict);
+    }
+
+    #[actix_rt::test]
+    async fn test_prefetch_links() {
+        let sources = test_sources();
+        let srv_config = SrvConfig {
+            prefetch_links: Some(true),
+            ..SrvConfig::default()
+        };
+        let app = test_app(sources, srv_config, None).await;
+
+        for (path, expected) in [
+            (
+                "/test_source/3/4/5",
+                Some("<../3/5>; rel=prefetch, <../5/5>; rel=prefetch, <../4/4>; rel=prefetch, <../4/6>; rel=prefetch"),
+            ),
+            // The x coordinate wraps around the antimeridian, but y stops at the poles
+            (
+                "/test_source/1/0/0",
+                Some("<../1/0>; rel=prefetch, <../0/1>; rel=prefetch"),
+            ),
+            ("/test_source/0/0/0", None),
+        ] {
+            let req = TestRequest::get().uri(path).to_request();
+            let resp = call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{path}");
+            let link = resp.headers().get(LINK).map(|v| v.to_str().unwrap());
+            assert_eq!(link, expected, "{path}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_join_gzip_tiles() {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let tj = tilejson! { tiles: vec![] };
+        let info = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let sources = TileSources::new(vec![vec![
+            Box::new(
+                MemorySource::new("a", tj.clone(), info)
+                    .with_tile(xyz, encode_gzip(b"layer_a").unwrap()),
+            ),
+            Box::new(MemorySource::new("b", tj.clone(), info)),
+            Box::new(
+                MemorySource::new("c", tj, info).with_tile(xyz, encode_gzip(b"layer_c").unwrap()),
+            ),
+        ]]);
+        let accept_enc = Some(AcceptEncoding(vec!["gzip".parse().unwrap()]));
+        let src = DynTileSource::new(&sources, "a,b,c", None, "", accept_enc, None, None).unwrap();
+
+        let tile = src.get_tile_content(xyz).await.unwrap();
+        assert_eq!(tile.info.encoding, Encoding::Gzip);
+        // A decoder that only reads the first gzip member must still see both layers
+        assert_eq!(decode_gzip(&tile.data).unwrap(), b"layer_alayer_c");
+    }
+
+    #[actix_rt::test]
+    async fn test_head_matches_get() {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let data = b"a compressible tile, a compressible tile, a compressible tile".to_vec();
+        let sources = TileSources::new(vec![vec![Box::new(
+            MemorySource::new("src", tilejson! { tiles: vec![] }, info).with_tile(xyz, data),
+        )]]);
+        let app = test_app(sources, SrvConfig::default(), None).await;
+
+        for accept_enc in [None, Some("gzip"), Some("br"), Some("identity")] {
+            let mut headers = Vec::new();
+            for req in [
+                TestRequest::get(),
+                TestRequest::default().method(Method::HEAD),
+            ] {
+                let mut req = req.uri("/src/0/0/0");
+                if let Some(accept_enc) = accept_enc {
+                    req = req.insert_header((ACCEPT_ENCODING, accept_enc));
+                }
+                let resp = call_service(&app, req.to_request()).await;
+                assert!(resp.status().is_success());
+                let content_type = resp.headers().get(CONTENT_TYPE).cloned();
+                let content_encoding = resp.headers().get(CONTENT_ENCODING).cloned();
+                // The server derives Content-Length from the body size, even if the body of HEAD is not sent
+                let len = read_body(resp).await.len();
+                headers.push((content_type, content_encoding, len));
+            }
+            assert_eq!(headers[0], headers[1], "Accept-Encoding: {accept_enc:?}");
+        }
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        let budget = |header: Option<&str>, timeout: Option<u64>| {
+            let mut req = TestRequest::get();
+            if let Some(value) = header {
+                req = req.insert_header((X_REQUEST_DEADLINE.clone(), value));
+            }
+            let srv_config = SrvConfig {
+                request_timeout_ms: timeout,
+                ..SrvConfig::default()
+            };
+            let start = Instant::now();
+            get_request_deadline(&req.to_http_request(), &srv_config)
+                .map(|deadline| deadline.saturating_duration_since(start).as_millis())
+        };
+        assert_eq!(budget(None, None), None);
+        assert_eq!(budget(Some("invalid"), None), None);
+        assert!(budget(Some("50"), None).is_some_and(|v| v <= 50));
+        assert!(budget(None, Some(1000)).is_some_and(|v| v > 50 && v <= 1000));
+        assert!(budget(Some("50"), Some(1000)).is_some_and(|v| v <= 50));
+        assert!(budget(Some("5000"), Some(1000)).is_some_and(|v| v > 50 && v <= 1000));
+
+        // Values that are not a number of milliseconds are ignored
+        assert_eq!(budget(Some("-50"), None), None);
+        assert_eq!(budget(Some("1.5"), None), None);
+        assert!(budget(Some("-50"), Some(1000)).is_some_and(|v| v > 50 && v <= 1000));
+
+        // A huge value must not overflow the deadline, nor exceed the largest `statement_timeout`
+        let max = u128::from(MAX_DEADLINE_MS);
+        assert!(budget(Some("18446744073709551615"), None).is_some_and(|v| v > 1000 && v <= max));
+        assert!(budget(None, Some(u64::MAX)).is_some_and(|v| v > 1000 && v <= max));
+        assert!(budget(Some("18446744073709551615"), Some(1000)).is_some_and(|v| v <= 1000));
+    }
+
+    /// A source that produces the `statement_timeout` a Postgres source would set for its tile query
+    #[derive(Debug, Clone)]
+    struct StatementTimeoutSource(TileJSON);
+
+    #[async_trait]
+    impl Source for StatementTimeoutSource {
+        fn get_id(&self) -> &str {
+            "timeout"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.0
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: TileCoord,
+            _url_query: Option<&UrlQuery>,
+        ) -> MartinResult<TileData> {
+            let timeout = crate::utils::statement_timeout_ms();
+            Ok(timeout
+                .map(|v| v.to_string().into_bytes())
+                .unwrap_or_default())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_request_deadline_statement_timeout() {
+        let sources = TileSources::new(vec![vec![Box::new(StatementTimeoutSource(
+            tilejson! { tiles: vec![] },
+        ))]]);
+        let srv_config = SrvConfig {
+            request_timeout_ms: Some(5000),
+            ..SrvConfig::default()
+        };
+        let app = test_app(sources, srv_config, None).await;
+
+        for (header, min, max) in [
+            (None, 1000, 5000),
+            (Some("50"), 1, 50),
+            (Some("60000"), 1000, 5000),
+        ] {
+            let mut req = TestRequest::get().uri("/timeout/0/0/0");
+            if let Some(value) = header {
+                req = req.insert_header((X_REQUEST_DEADLINE.clone(), value));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            assert!(resp.status().is_success(), "{header:?}");
+            let body = read_body(resp).await;
+            let timeout: u64 = String::from_utf8_lossy(&body).parse().unwrap();
+            assert!(timeout >= min && timeout <= max, "{header:?}: {timeout}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_encoding_param() {
+        let app = test_app(test_sources(), SrvConfig::default(), None).await;
+
+        for accept_enc in [None, Some("gzip"), Some("identity")] {
+            let mut req = TestRequest::get().uri("/test_source/0/0/0?encoding=br");
+            if let Some(accept_enc) = accept_enc {
+                req = req.insert_header((ACCEPT_ENCODING, accept_enc));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            assert!(resp.status().is_success(), "{accept_enc:?}");
+            assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), "br");
+            let body = read_body(resp).await;
+            assert_eq!(decode_brotli(&body).unwrap(), vec![1_u8, 2, 3]);
+        }
+
+        let req = TestRequest::get()
+            .uri("/test_source/0/0/0?encoding=zstd")
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// A source that produces the names of the URL query parameters it receives
+    #[derive(Debug, Clone)]
+    struct QueryParamsSource(TileJSON);
+
+    #[async_trait]
+    impl Source for QueryParamsSource {
+        fn get_id(&self) -> &str {
+            "params"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.0
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        fn support_url_query(&self) -> bool {
+            true
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: TileCoord,
+            url_query: Option<&UrlQuery>,
+        ) -> MartinResult<TileData> {
+            let names = url_query.map(|q| q.keys().sorted().join(","));
+            Ok(names.unwrap_or_default().into_bytes())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_control_params() {
+        let sources = TileSources::new(vec![vec![Box::new(QueryParamsSource(
+            tilejson! { tiles: vec![] },
+        ))]]);
+        let srv_config = SrvConfig {
+            cache_partition: Some(CachePartition::QueryParam("tenant".to_string())),
+            ..SrvConfig::default()
+        };
+        let cache = MainCache::builder().build();
+        let app = test_app(sources, srv_config, Some(cache.clone())).await;
+
+        for (uri, tenant) in [
+            ("/params/0/0/0?year=2024&encoding=identity&tenant=a", "a"),
+            ("/params/0/0/0?tenant=b&year=2024", "b"),
+        ] {
+            let req = TestRequest::get().uri(uri).to_request();
+            let resp = call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{uri}");
+            assert_eq!(read_body(resp).await, "year", "{uri}");
+
+            cache.run_pending_tasks().await;
+            let xyz = TileCoord { z: 0, x: 0, y: 0 };
+            let key = CacheKey::TileWithQuery("params".to_string(), xyz, "year=2024".to_string());
+            let key = CacheKey::Partitioned(tenant.to_string(), Box::new(key));
+            assert!(cache.contains_key(&key), "{uri}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_empty_tiles() {
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let sources = TileSources::new(vec![vec![
+            Box::new(MemorySource::new(
+                "empty",
+                tilejson! { tiles: vec![] },
+                info,
+            )),
+            Box::new(
+                MemorySource::new("failing", tilejson! { tiles: vec![] }, info)
+                    .with_error(xyz, "database is down"),
+            ),
+        ]]);
+        let srv_config = SrvConfig {
+            server_timing: Some(true),
+            encoding_header: Some(true),
+            best_effort: Some(true),
+            ..SrvConfig::default()
+        };
+        let empty_tiles = Data::new(EmptyTiles::new(Duration::from_secs(60)));
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(sources))
+                .app_data(Data::new(srv_config))
+                .app_data(Data::new(crate::NO_MAIN_CACHE))
+                .app_data(empty_tiles.clone())
+                .service(get_tile),
+        )
+        .await;
+        let key = |source_ids: &str, z, query: &str| EmptyTileKey {
+            source_ids: source_ids.to_string(),
+            xyz: TileCoord { z, x: 0, y: 0 },
+            query: query.to_string(),
+            partition: None,
+        };
+
+        for (uri, cached) in [
+            ("/empty/0/0/0", key("empty", 0, "")),
+            ("/empty/1/0/0", key("empty", 1, "")),
+            ("/empty/0/0/0?a=1", key("empty", 0, "a=1")),
+        ] {
+            assert!(!empty_tiles.contains(&cached), "{uri}");
+            // The second response comes from the empty tiles, but has the same headers as the first one
+            let mut headers = Vec::new();
+            for _ in 0..2 {
+                let req = TestRequest::get().uri(uri).to_request();
+                let resp = call_service(&app, req).await;
+                assert_eq!(resp.status(), StatusCode::NO_CONTENT, "{uri}");
+                assert!(empty_tiles.contains(&cached), "{uri}");
+                let mut names: Vec<_> = resp.headers().keys().map(ToString::to_string).collect();
+                names.sort();
+                headers.push(names);
+            }
+            assert!(headers[0].contains(&SERVER_TIMING.to_string()), "{uri}");
+            assert!(headers[0].contains(&X_MARTIN_ENCODING.to_string()), "{uri}");
+            assert_eq!(headers[0], headers[1], "{uri}");
+        }
+
+        // The tile is only empty because a source failed, so it may not be empty next time
+        let req = TestRequest::get().uri("/empty,failing/0/0/0").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(!empty_tiles.contains(&key("empty,failing", 0, "")));
+    }
+
+    #[actix_rt::test]
+    async fn test_response_headers() {
+        let source = |id: &'static str| -> TileInfoSource {
+            Box::new(TestSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                data: vec![1_u8, 2, 3],
+            })
+        };
+        let sources = TileSources::new(vec![vec![source("licensed"), source("other")]]);
+        let headers = BTreeMap::from([(
+            "licensed".to_string(),
+            BTreeMap::from([("X-Data-License".to_string(), "CC-BY-4.0".to_string())]),
+        )]);
+        check_response_headers(&sources, &headers).unwrap();
+
+        let srv_config = SrvConfig {
+            response_headers: Some(headers),
+            ..SrvConfig::default()
+        };
+        let app = test_app(sources, srv_config, None).await;
+
+        for (ids, expected) in [
+            ("licensed", Some("CC-BY-4.0")),
+            ("other", None),
+            ("other,licensed", Some("CC-BY-4.0")),
+        ] {
+            let req = TestRequest::get()
+                .uri(&format!("/{ids}/0/0/0"))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{ids}");
+            let license = resp.headers().get("x-data-license");
+            assert_eq!(license.and_then(|v| v.to_str().ok()), expected, "{ids}");
+        }
+
+        let sources = TileSources::new(vec![vec![source("licensed")]]);
+        for name in ["Invalid Header", "Content-Type"] {
+            let headers = BTreeMap::from([(
+                "licensed".to_string(),
+                BTreeMap::from([(name.to_string(), "value".to_string())]),
+            )]);
+            assert!(matches!(
+                check_response_headers(&sources, &headers),
+                Err(MartinError::InvalidResponseHeader(id, _)) if id == "licensed"
+            ));
+        }
+    }
 }
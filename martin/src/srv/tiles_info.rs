@@ -1,35 +1,53 @@
 use std::string::ToString;
 
 use actix_web::error::ErrorBadRequest;
+use actix_web::http::header::ContentType;
 use actix_web::http::Uri;
-use actix_web::web::{Data, Path};
-use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use actix_web::web::{Data, Path, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
 use itertools::Itertools as _;
+use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::Deserialize;
 use tilejson::{tilejson, TileJSON};
 
 use crate::source::{Source, TileSources};
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::negotiated_response;
 use crate::srv::SrvConfig;
+use crate::Tile;
+
+/// `TileJSON` spec versions that can be requested with the `tilejson` query parameter
+const TILEJSON_VERSIONS: &[&str] = &["2.2.0", "3.0.0"];
 
 #[derive(Deserialize)]
 pub struct SourceIDsRequest {
     pub source_ids: String,
 }
 
-#[route(
-    "/{source_ids}",
-    method = "GET",
-    method = "HEAD",
-    wrap = "middleware::Compress::default()"
-)]
+#[derive(Deserialize)]
+pub struct TileJsonRequest {
+    pub tilejson: Option<String>,
+}
+
+/// Return the `TileJSON` of the sources, compressed the same way as the tiles unless `disable_compression` is set
+#[route("/{source_ids}", method = "GET", method = "HEAD")]
 #[allow(clippy::unused_async)]
 async fn get_source_info(
     req: HttpRequest,
     path: Path<SourceIDsRequest>,
+    tj_req: Query<TileJsonRequest>,
     sources: Data<TileSources>,
     srv_config: Data<SrvConfig>,
 ) -> ActixResult<HttpResponse> {
     let sources = sources.get_sources(&path.source_ids, None)?.0;
+    if let Some(version) = &tj_req.tilejson {
+        if !TILEJSON_VERSIONS.contains(&version.as_str()) {
+            return Err(ErrorBadRequest(format!(
+                "Unsupported TileJSON version {version}, expected one of: {}",
+                TILEJSON_VERSIONS.join(", ")
+            )));
+        }
+    }
 
     let tiles_path = if let Some(base_path) = &srv_config.base_path {
         format!("{base_path}/{}", path.source_ids)
@@ -41,7 +59,12 @@ async fn get_source_info(
             .map_or_else(|| req.path().to_string(), |v| v.path().to_string())
     };
 
-    let query_string = req.query_string();
+    // The `tilejson` parameter only applies to this request, so do not pass it on to the tiles
+    let query_string = req
+        .query_string()
+        .split('&')
+        .filter(|v| !v.is_empty() && *v != "tilejson" && !v.starts_with("tilejson="))
+        .join("&");
     let path_and_query = if query_string.is_empty() {
         format!("{tiles_path}/{{z}}/{{x}}/{{y}}")
     } else {
@@ -58,7 +81,23 @@ async fn get_source_info(
         .map(|tiles_url| tiles_url.to_string())
         .map_err(|e| ErrorBadRequest(format!("Can't build tiles URL: {e}")))?;
 
-    Ok(HttpResponse::Ok().json(merge_tilejson(&sources, tiles_url)))
+    let mut tj = merge_tilejson(&sources, tiles_url);
+    if let Some(version) = &tj_req.tilejson {
+        set_tilejson_version(&mut tj, version);
+    }
+
+    let data = serde_json::to_vec(&tj).map_err(map_internal_error)?;
+    let json = Tile::new(data, TileInfo::new(Format::Json, Encoding::Uncompressed));
+    negotiated_response(&req, &srv_config, ContentType::json(), json)
+}
+
+/// Set the `TileJSON` spec version, omitting the fields that the requested version does not support
+fn set_tilejson_version(tj: &mut TileJSON, version: &str) {
+    if version.starts_with("2.") {
+        tj.vector_layers = None;
+        tj.fillzoom = None;
+    }
+    tj.tilejson = version.to_string();
 }
 
 #[must_use]
@@ -88,7 +127,11 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
         }
 
         if let Some(v) = &tj.attribution {
-            if !attributions.contains(&v) {
+            // Many sources share the same attribution, possibly with a different case
+            if !attributions
+                .iter()
+                .any(|a: &&String| a.to_lowercase() == v.to_lowercase())
+            {
                 attributions.push(v);
             }
         }
@@ -158,11 +201,58 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
 pub mod tests {
     use std::collections::BTreeMap;
 
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
     use tilejson::{Bounds, VectorLayer};
 
     use super::*;
     use crate::srv::server::tests::TestSource;
 
+    #[actix_rt::test]
+    async fn test_tilejson_version() {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "test_source",
+            tj: tilejson! {
+                tiles: vec![],
+                vector_layers: vec![VectorLayer::new("layer1".to_string(), BTreeMap::new())],
+            },
+            data: Vec::default(),
+        })]]);
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(sources))
+                .app_data(Data::new(SrvConfig::default()))
+                .service(get_source_info),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/test_source").to_request();
+        let tj: TileJSON = call_and_read_body_json(&app, req).await;
+        assert_eq!(tj.tilejson, "3.0.0");
+        assert!(tj.vector_layers.is_some());
+
+        let req = TestRequest::get()
+            .uri("/test_source?tilejson=3.0.0")
+            .to_request();
+        let tj: TileJSON = call_and_read_body_json(&app, req).await;
+        assert_eq!(tj.tilejson, "3.0.0");
+        assert!(tj.vector_layers.is_some());
+        assert!(tj.tiles[0].ends_with("/test_source/{z}/{x}/{y}"));
+
+        let req = TestRequest::get()
+            .uri("/test_source?tilejson=2.2.0")
+            .to_request();
+        let tj: TileJSON = call_and_read_body_json(&app, req).await;
+        assert_eq!(tj.tilejson, "2.2.0");
+        assert!(tj.vector_layers.is_none());
+
+        let req = TestRequest::get()
+            .uri("/test_source?tilejson=1.0.0")
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
     #[test]
     fn test_merge_tilejson() {
         let url = "http://localhost:8888/foo/{z}/{x}/{y}".to_string();
@@ -230,4 +320,32 @@ pub mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_merge_attributions() {
+        let url = "http://localhost:8888/foo/{z}/{x}/{y}".to_string();
+        let source = |id, attribution: &str| TestSource {
+            id,
+            tj: tilejson! {
+                tiles: vec![],
+                attribution: attribution.to_string(),
+            },
+            data: Vec::default(),
+        };
+        let src1 = source("a", "© OpenStreetMap contributors");
+        let src2 = source("b", "© Natural Earth");
+        let src3 = source("c", "© OPENSTREETMAP Contributors");
+
+        let tj = merge_tilejson(&[&src1, &src2, &src3], url.clone());
+        assert_eq!(
+            tj.attribution.as_deref(),
+            Some("© OpenStreetMap contributors\n© Natural Earth")
+        );
+
+        let tj = merge_tilejson(&[&src3, &src2, &src1], url);
+        assert_eq!(
+            tj.attribution.as_deref(),
+            Some("© OPENSTREETMAP Contributors\n© Natural Earth")
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use martin_tile_utils::{Format, TileInfo};
+use serde_json::Value;
+use tilejson::TileJSON;
+
+use crate::file_config::FileError::{InvalidTileJson, IoError};
+use crate::file_config::FileResult;
+use crate::source::{Source, TileData, TileInfoSource, UrlQuery};
+use crate::{MartinResult, TileCoord};
+
+/// A source that publishes a hand-written `TileJSON` file instead of its generated `TileJSON`,
+/// e.g. for custom `vector_layers` or a legend. The `tiles` of the file are replaced
+/// with the URL of the source when the `TileJSON` is served.
+#[derive(Clone, Debug)]
+pub struct TileJsonFileSource {
+    source: TileInfoSource,
+    tilejson: TileJSON,
+}
+
+impl TileJsonFileSource {
+    /// Replace the `TileJSON` of the source with the content of the file,
+    /// failing if the file cannot be read or is not a valid `TileJSON`
+    pub fn wrap(source: TileInfoSource, path: &Path) -> FileResult<TileInfoSource> {
+        let data = std::fs::read(path).map_err(|e| IoError(e, path.to_path_buf()))?;
+        let invalid = |e: serde_json::Error| InvalidTileJson(e.to_string(), path.to_path_buf());
+        let mut value: Value = serde_json::from_slice(&data).map_err(invalid)?;
+        if let Value::Object(obj) = &mut value {
+            // The tiles URL is only known when serving, and the version is set per request
+            obj.entry("tiles").or_insert_with(|| Value::Array(vec![]));
+            obj.entry("tilejson")
+                .or_insert_with(|| Value::String("3.0.0".to_string()));
+        }
+        let tilejson = serde_json::from_value(value).map_err(invalid)?;
+        Ok(Box::new(Self { source, tilejson }))
+    }
+}
+
+#[async_trait]
+impl Source for TileJsonFileSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        self.source.get_tile(xyz, url_query).await
+    }
+
+    fn get_alt_formats(&self) -> Vec<Format> {
+        self.source.get_alt_formats()
+    }
+
+    async fn get_alt_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        format: Format,
+    ) -> MartinResult<TileData> {
+        self.source.get_alt_tile(xyz, url_query, format).await
+    }
+
+    // The zoom levels and bounds of the file only describe the source, the actual tiles still decide
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn is_valid_tile(&self, xyz: TileCoord) -> bool {
+        self.source.is_valid_tile(xyz)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::web::Data;
+    use actix_web::App;
+    use martin_tile_utils::{Encoding, Format};
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::{router, SrvConfig};
+    use crate::{MemorySource, TileSources};
+
+    fn new_source() -> TileInfoSource {
+        let mut tj = tilejson! { tiles: vec![] };
+        tj.maxzoom = Some(6);
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        Box::new(MemorySource::new("cities", tj, info))
+    }
+
+    #[actix_rt::test]
+    async fn test_tilejson_file() {
+        let path = Path::new("../tests/fixtures/tilejson/cities.json");
+        let src = TileJsonFileSource::wrap(new_source(), path).unwrap();
+        assert!(src.is_valid_zoom(6));
+        assert!(!src.is_valid_zoom(7));
+
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(TileSources::new(vec![vec![src]])))
+                .app_data(Data::new(SrvConfig::default()))
+                .configure(router),
+        )
+        .await;
+        let req = TestRequest::get().uri("/cities").to_request();
+        let tj: TileJSON = call_and_read_body_json(&app, req).await;
+        assert_eq!(tj.name.as_deref(), Some("Cities of the world"));
+        assert_eq!(tj.maxzoom, None);
+        assert_eq!(
+            tj.legend.as_deref(),
+            Some("Larger circles show larger cities")
+        );
+        let layers = tj.vector_layers.unwrap();
+        assert_eq!(layers[0].id, "cities");
+        assert_eq!(layers[0].fields["population"], "Number of inhabitants");
+        assert_eq!(tj.tiles.len(), 1);
+        assert!(
+            tj.tiles[0].ends_with("/cities/{z}/{x}/{y}"),
+            "{}",
+            tj.tiles[0]
+        );
+    }
+
+    #[test]
+    fn test_tilejson_file_invalid() {
+        let path = Path::new("../tests/fixtures/tilejson/missing.json");
+        let err = TileJsonFileSource::wrap(new_source(), path).unwrap_err();
+        assert!(matches!(err, IoError(..)), "{err}");
+
+        let path = Path::new("../tests/fixtures/tilejson/invalid.json");
+        let err = TileJsonFileSource::wrap(new_source(), path).unwrap_err();
+        assert!(matches!(err, InvalidTileJson(..)), "{err}");
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::warn;
+use martin_tile_utils::{Format, TileInfo};
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{Source, TileData, TileInfoSource, TileSources, UrlQuery};
+use crate::{MartinResult, TileCoord};
+
+/// Configuration of a custom tile matrix set, and the sources that use it
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TileMatrixSetConfig {
+    /// IDs of the sources that use this tile matrix set
+    pub sources: Vec<String>,
+    /// Size of the tile grid for each zoom level, starting with zoom 0
+    pub tile_matrices: Vec<TileMatrix>,
+}
+
+/// Size of the tile grid at a single zoom level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileMatrix {
+    /// Number of tiles in the horizontal direction
+    pub matrix_width: u32,
+    /// Number of tiles in the vertical direction
+    pub matrix_height: u32,
+}
+
+/// A tile matrix set as defined by the [OGC standard](https://docs.ogc.org/is/17-083r4/17-083r4.html),
+/// reduced to the grid size of each zoom level
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileMatrixSet {
+    pub id: String,
+    pub tile_matrices: Vec<TileMatrix>,
+}
+
+impl TileMatrixSet {
+    #[must_use]
+    pub fn is_valid_tile(&self, xyz: TileCoord) -> bool {
+        self.tile_matrices
+            .get(usize::from(xyz.z))
+            .is_some_and(|m| xyz.x < m.matrix_width && xyz.y < m.matrix_height)
+    }
+}
+
+/// A source that validates the tile coordinates against a custom tile matrix set
+#[derive(Clone, Debug)]
+pub struct TmsSource {
+    source: TileInfoSource,
+    tms: TileMatrixSet,
+    tilejson: TileJSON,
+}
+
+impl TmsSource {
+    #[must_use]
+    pub fn new(source: TileInfoSource, tms: TileMatrixSet) -> Self {
+        let mut tilejson = source.get_tilejson().clone();
+        tilejson
+            .other
+            .insert("tile_matrix_set".to_string(), tms.id.clone().into());
+        Self {
+            source,
+            tms,
+            tilejson,
+        }
+    }
+}
+
+#[async_trait]
+impl Source for TmsSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        self.source.get_tile(xyz, url_query).await
+    }
+
+    fn get_alt_formats(&self) -> Vec<Format> {
+        self.source.get_alt_formats()
+    }
+
+    async fn get_alt_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        format: Format,
+    ) -> MartinResult<TileData> {
+        self.source.get_alt_tile(xyz, url_query, format).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn is_valid_tile(&self, xyz: TileCoord) -> bool {
+        self.is_valid_zoom(xyz.z) && self.tms.is_valid_tile(xyz)
+    }
+}
+
+/// Make the configured sources use their custom tile matrix sets
+pub fn apply_tile_matrix_sets(
+    sources: &mut TileSources,
+    configs: &BTreeMap<String, TileMatrixSetConfig>,
+) {
+    for (id, cfg) in configs {
+        let tms = TileMatrixSet {
+            id: id.clone(),
+            tile_matrices: cfg.tile_matrices.clone(),
+        };
+        for source_id in &cfg.sources {
+            let tms = tms.clone();
+            if !sources.wrap_source(source_id, |src| Box::new(TmsSource::new(src, tms))) {
+                warn!("Tile matrix set {id} refers to a source {source_id} that does not exist");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use martin_tile_utils::{Encoding, Format};
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::MemorySource;
+
+    #[test]
+    fn test_tile_matrix_set() {
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let mut sources = TileSources::new(vec![vec![
+            Box::new(MemorySource::new("tms", tilejson! { tiles: vec![] }, info)),
+            Box::new(MemorySource::new("web", tilejson! { tiles: vec![] }, info)),
+        ]]);
+        let configs = BTreeMap::from([(
+            "irregular".to_string(),
+            TileMatrixSetConfig {
+                sources: vec!["tms".to_string()],
+                tile_matrices: vec![
+                    TileMatrix {
+                        matrix_width: 3,
+                        matrix_height: 2,
+                    },
+                    TileMatrix {
+                        matrix_width: 5,
+                        matrix_height: 3,
+                    },
+                ],
+            },
+        )]);
+        apply_tile_matrix_sets(&mut sources, &configs);
+
+        let tms = sources.get_source("tms").unwrap();
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        assert!(tms.is_valid_tile(xyz(0, 2, 1)));
+        assert!(!tms.is_valid_tile(xyz(0, 3, 0)));
+        assert!(!tms.is_valid_tile(xyz(0, 0, 2)));
+        assert!(tms.is_valid_tile(xyz(1, 4, 2)));
+        assert!(!tms.is_valid_tile(xyz(1, 4, 3)));
+        assert!(!tms.is_valid_tile(xyz(2, 0, 0)));
+        assert_eq!(tms.get_tilejson().other["tile_matrix_set"], "irregular");
+
+        // Other sources still use the Web Mercator grid
+        let web = sources.get_source("web").unwrap();
+        assert!(web.is_valid_tile(xyz(1, 1, 1)));
+        assert!(!web.is_valid_tile(xyz(1, 2, 0)));
+        assert!(web.is_valid_tile(xyz(2, 0, 0)));
+        assert!(!web.get_tilejson().other.contains_key("tile_matrix_set"));
+
+        // Sources are skipped if the tile is outside their grid
+        let (found, ..) = sources.get_sources("tms,web", Some(xyz(1, 3, 2))).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get_id(), "tms");
+    }
+}
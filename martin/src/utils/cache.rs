@@ -1,3 +1,4 @@
+use martin_tile_utils::{Encoding, Format};
 use moka::future::Cache;
 
 use crate::{TileCoord, TileData};
@@ -14,6 +15,14 @@ pub enum CacheKey {
     Tile(String, TileCoord),
     /// (`source_id`, `xyz`, `url_query`)
     TileWithQuery(String, TileCoord, String),
+    /// (`source_ids`, `xyz`, `url_query`, `format`) of a tile transcoded to another image format
+    TranscodedTile(String, TileCoord, Option<String>, Format),
+    /// (`source_id`, `xyz`, `url_query`, `format`) of a tile in one of the alternative formats of the source
+    AltFormatTile(String, TileCoord, Option<String>, Format),
+    /// (`fontstack`, `start`, `end`, `encoding`) of a glyph range
+    Glyphs(String, u32, u32, Encoding),
+    /// (`partition`, `key`) of a tile that is only shared by the requests of the same partition, e.g. a tenant
+    Partitioned(String, Box<CacheKey>),
 }
 
 #[derive(Debug, Clone)]
@@ -23,6 +32,30 @@ pub enum CacheValue {
     PmtDirectory(pmtiles::Directory),
 }
 
+/// Evict all tiles of a source from the cache, e.g. after its data has changed.
+/// The cache must be built with `support_invalidation_closures()`.
+#[cfg(feature = "postgres")]
+pub fn invalidate_source(cache: &MainCache, source_id: &str) {
+    let id = source_id.to_string();
+    let res = cache.invalidate_entries_if(move |key, _| is_source_key(key, &id));
+    if let Err(e) = res {
+        log::warn!("Unable to evict source {source_id} from the cache: {e}");
+    }
+}
+
+/// Check if the cache key belongs to a tile of the given source
+#[cfg(feature = "postgres")]
+fn is_source_key(key: &CacheKey, id: &str) -> bool {
+    match key {
+        CacheKey::Tile(v, _) | CacheKey::TileWithQuery(v, ..) | CacheKey::AltFormatTile(v, ..) => {
+            v == id
+        }
+        CacheKey::TranscodedTile(v, ..) => v.split(',').any(|v| v == id),
+        CacheKey::Partitioned(_, key) => is_source_key(key, id),
+        CacheKey::PmtDirectory(..) | CacheKey::Glyphs(..) => false,
+    }
+}
+
 macro_rules! trace_cache {
     ($typ: literal, $cache: expr, $key: expr) => {
         trace!(
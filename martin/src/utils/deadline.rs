@@ -0,0 +1,37 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Largest `statement_timeout` that Postgres accepts, in milliseconds (`i32::MAX`),
+/// which is also the longest time a request may wait for its tiles
+pub const MAX_DEADLINE_MS: u64 = 2_147_483_647;
+
+tokio::task_local! {
+    /// The moment the client of the current request stops waiting for the response
+    static REQUEST_DEADLINE: Instant;
+}
+
+/// Run the future with the deadline of the request, so that the sources can give up on it in time
+pub async fn with_deadline<F: Future>(deadline: Option<Instant>, fut: F) -> F::Output {
+    match deadline {
+        Some(deadline) => REQUEST_DEADLINE.scope(deadline, fut).await,
+        None => fut.await,
+    }
+}
+
+/// Time left until the deadline of the current request, or `None` if the request has no deadline
+#[must_use]
+pub fn remaining_time() -> Option<Duration> {
+    REQUEST_DEADLINE
+        .try_with(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
+
+/// Remaining time budget of the current request in milliseconds, so that Postgres aborts the query
+/// when the client gives up. Zero would disable the timeout, so an expired deadline becomes 1ms.
+#[must_use]
+pub fn statement_timeout_ms() -> Option<u64> {
+    remaining_time().map(|v| {
+        let ms = u64::try_from(v.as_millis()).unwrap_or(u64::MAX);
+        ms.clamp(1, MAX_DEADLINE_MS)
+    })
+}
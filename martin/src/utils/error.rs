@@ -37,6 +37,15 @@ pub enum MartinError {
     #[error("Base path must be a valid URL path, and must begin with a '/' symbol, but is '{0}'")]
     BasePathError(String),
 
+    #[error("Health check path must begin with a '/' symbol, but is '{0}'")]
+    HealthPathError(String),
+
+    #[error("Server header must be a valid HTTP header value, but is {0:?}")]
+    ServerHeaderError(String),
+
+    #[error("Source {0} sets a session setting from the request header {1}, so its tiles must be cached separately for each value of the header with `cache_partition: {{header: {1}}}`")]
+    UnpartitionedSessionHeader(String, String),
+
     #[error("Unable to load config file {}: {0}", .1.display())]
     ConfigLoadError(io::Error, PathBuf),
 
@@ -46,6 +55,30 @@ pub enum MartinError {
     #[error("Unable to write config file {}: {0}", .1.display())]
     ConfigWriteError(io::Error, PathBuf),
 
+    #[error("Only one of the text, file, or redirect values can be set for the index page")]
+    IndexConfigConflict,
+
+    #[error("Unable to read index page file {}: {0}", .1.display())]
+    IndexFileError(io::Error, PathBuf),
+
+    #[error("Source {0} cannot be transcoded to WebP because its tiles are {1}, but only PNG and JPEG tiles are supported")]
+    WebpTranscodeUnsupported(String, martin_tile_utils::TileInfo),
+
+    #[error("Response header {1} of source {0} is invalid or cannot be overridden")]
+    InvalidResponseHeader(String, String),
+
+    #[error("Unable to transcode the tile to WebP: {0}")]
+    WebpTranscodeError(#[from] image::ImageError),
+
+    #[error("The stored tile cannot be decompressed: {0}")]
+    InvalidTile(String),
+
+    #[error("Unable to overzoom tile {1} of source {0}: {2}")]
+    OverzoomError(String, crate::TileCoord, #[source] image::ImageError),
+
+    #[error("Source {0} cannot produce {1} tiles")]
+    UnsupportedTileFormat(String, martin_tile_utils::Format),
+
     #[error("No tile sources found. Set sources by giving a database connection string on command line, env variable, or a config file.")]
     NoSources,
 
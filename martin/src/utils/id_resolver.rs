@@ -10,15 +10,15 @@ pub struct IdResolver {
     /// name -> unique name
     names: Arc<Mutex<HashMap<String, String>>>,
     /// reserved names
-    reserved: HashSet<&'static str>,
+    reserved: HashSet<String>,
 }
 
 impl IdResolver {
     #[must_use]
-    pub fn new(reserved_keywords: &[&'static str]) -> Self {
+    pub fn new(reserved_keywords: &[&str]) -> Self {
         Self {
             names: Arc::new(Mutex::new(HashMap::new())),
-            reserved: reserved_keywords.iter().copied().collect(),
+            reserved: reserved_keywords.iter().map(ToString::to_string).collect(),
         }
     }
 
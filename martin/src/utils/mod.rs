@@ -1,9 +1,14 @@
 pub(crate) mod cache;
+#[cfg(feature = "postgres")]
+pub use cache::invalidate_source;
 pub use cache::{CacheKey, CacheValue, MainCache, OptMainCache, NO_MAIN_CACHE};
 
 mod cfg_containers;
 pub use cfg_containers::{OptBoolObj, OptOneMany};
 
+mod deadline;
+pub use deadline::{statement_timeout_ms, with_deadline, MAX_DEADLINE_MS};
+
 mod error;
 pub use error::*;
 
@@ -13,6 +18,9 @@ pub use id_resolver::IdResolver;
 mod rectangle;
 pub use rectangle::{append_rect, TileRect};
 
+mod request_headers;
+pub use request_headers::{request_header, with_request_headers};
+
 mod utilities;
 pub use utilities::*;
 
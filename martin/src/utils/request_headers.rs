@@ -0,0 +1,22 @@
+use std::future::Future;
+
+use actix_web::http::header::HeaderMap;
+
+tokio::task_local! {
+    /// Headers of the current request, e.g. with the tenant ID set by a trusted proxy
+    static REQUEST_HEADERS: HeaderMap;
+}
+
+/// Run the future with the headers of the request, so that the sources can use their values
+pub async fn with_request_headers<F: Future>(headers: HeaderMap, fut: F) -> F::Output {
+    REQUEST_HEADERS.scope(headers, fut).await
+}
+
+/// Value of the header of the current request, or `None` if it is missing or is not valid text
+#[must_use]
+pub fn request_header(name: &str) -> Option<String> {
+    REQUEST_HEADERS
+        .try_with(|headers| Some(headers.get(name)?.to_str().ok()?.to_string()))
+        .ok()
+        .flatten()
+}
@@ -1,8 +1,12 @@
-use std::io::{Read as _, Write as _};
+use std::io::{Cursor, Read as _, Write as _};
 
 use actix_web::http::Uri;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageResult};
+use martin_tile_utils::Format;
 
 use crate::MartinError::BasePathError;
 use crate::MartinResult;
@@ -20,6 +24,49 @@ pub fn encode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     encoder.finish()
 }
 
+/// Convert a PNG or JPEG image to a lossless WebP image
+pub fn encode_webp(data: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let img = image::load_from_memory(data)?;
+    let mut result = Vec::new();
+    DynamicImage::ImageRgba8(img.to_rgba8())
+        .write_with_encoder(WebPEncoder::new_lossless(&mut result))?;
+    Ok(result)
+}
+
+/// Cut the part of a raster tile covered by its descendant `dz` zoom levels below, and scale it up to the size
+/// of the original tile. The `x` and `y` are the position of the descendant among the `2^dz` x `2^dz` descendants.
+pub fn overzoom_image(data: &[u8], format: Format, dz: u8, x: u32, y: u32) -> ImageResult<Vec<u8>> {
+    let img = image::load_from_memory(data)?;
+    let (width, height) = (img.width(), img.height());
+    let scale = 1_u64 << dz;
+    let offset = |pos: u32, size: u32| {
+        u32::try_from(u64::from(pos) * u64::from(size) / scale).unwrap_or(size)
+    };
+    let part_size = |size: u32| {
+        u32::try_from(u64::from(size) / scale)
+            .unwrap_or(size)
+            .max(1)
+    };
+    let part = img
+        .crop_imm(
+            offset(x, width),
+            offset(y, height),
+            part_size(width),
+            part_size(height),
+        )
+        .resize_exact(width, height, FilterType::Triangle);
+
+    let mut result = Vec::new();
+    match format {
+        Format::Webp => DynamicImage::ImageRgba8(part.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut result))?,
+        Format::Jpeg => DynamicImage::ImageRgb8(part.to_rgb8())
+            .write_to(&mut Cursor::new(&mut result), ImageFormat::Jpeg)?,
+        _ => part.write_to(&mut Cursor::new(&mut result), ImageFormat::Png)?,
+    }
+    Ok(result)
+}
+
 pub fn decode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     let mut decoder = brotli::Decompressor::new(data, 4096);
     let mut decompressed = Vec::new();
@@ -0,0 +1,36 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::web::Data;
+use actix_web::App;
+use martin::srv::{router, SrvConfig};
+use martin::{MemorySource, TileCoord, TileSources, NO_MAIN_CACHE};
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use tilejson::tilejson;
+
+#[actix_rt::test]
+async fn memory_get_tile() {
+    let source = MemorySource::new(
+        "canned",
+        tilejson! { tiles: vec![] },
+        TileInfo::new(Format::Mvt, Encoding::Uncompressed),
+    )
+    .with_tile(TileCoord { z: 0, x: 0, y: 0 }, vec![1_u8, 2, 3]);
+
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(TileSources::new(vec![vec![Box::new(source)]])))
+            .app_data(Data::new(NO_MAIN_CACHE))
+            .app_data(Data::new(SrvConfig::default()))
+            .configure(router),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/canned/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(read_body(response).await.as_ref(), &[1_u8, 2, 3]);
+
+    let req = TestRequest::get().uri("/canned/1/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
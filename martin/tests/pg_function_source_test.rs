@@ -1,9 +1,13 @@
 #![cfg(feature = "postgres")]
 
+use std::time::{Duration, Instant};
+
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
-use martin::TileCoord;
+use martin::pg::{PgSource, PgSqlInfo};
+use martin::{Source, TileCoord};
+use tilejson::tilejson;
 
 pub mod utils;
 pub use utils::*;
@@ -62,3 +66,126 @@ async fn function_source_schemas() {
       description: a function source with MixedCase name
     "###);
 }
+
+#[actix_rt::test]
+async fn function_source_retry() {
+    let pool = mock_pool(indoc! {"
+        connection_string: $DATABASE_URL
+        pool_size: 2
+    "})
+    .await
+    .unwrap();
+    let make_source = |sql: &str, retries: u32, retry_delay_ms: u64| {
+        let mut info = PgSqlInfo::new(sql.to_string(), false, "retry_test".to_string());
+        info.retries = retries;
+        info.retry_delay_ms = retry_delay_ms;
+        PgSource::new(
+            "retry_test".to_string(),
+            info,
+            tilejson! { tiles: vec![] },
+            pool.clone(),
+        )
+    };
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+    let conn = pool.get().await.unwrap();
+    let reset_calls = "SELECT setval('fail_first_calls_seq', 1, false)";
+
+    // The function fails with a serialization failure (SQLSTATE 40001) on its first two calls
+    let sql = indoc! {"
+        SELECT public.fail_first_calls(2)
+        WHERE $1::integer IS NOT NULL AND $2::bigint IS NOT NULL AND $3::bigint IS NOT NULL
+    "};
+    conn.batch_execute(reset_calls).await.unwrap();
+    let err = make_source(sql, 0, 10)
+        .get_tile(xyz, None)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("simulated serialization failure"),
+        "{err}"
+    );
+
+    conn.batch_execute(reset_calls).await.unwrap();
+    let tile = make_source(sql, 10, 20).get_tile(xyz, None).await.unwrap();
+    assert!(tile.is_empty());
+    let row = conn
+        .query_one("SELECT last_value FROM fail_first_calls_seq", &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 3);
+
+    // A syntax error is not retried
+    let start = Instant::now();
+    let err = make_source("SELEC 1", 10, 1000)
+        .get_tile(xyz, None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("syntax error"), "{err}");
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[actix_rt::test]
+async fn function_source_default_params() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        functions:
+          query_with_default:
+            schema: public
+            function: function_zxy_query_test
+            default_params:
+              token: martin
+    "});
+    let mock = mock_sources(cfg).await;
+    let src = source(&mock, "query_with_default");
+    assert_eq!(
+        src.get_tilejson().other["default_params"],
+        serde_json::json!({"token": "martin"})
+    );
+
+    // The function raises an exception if the token is missing
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+    let tile = src.get_tile(xyz, None).await.unwrap();
+    assert!(!tile.is_empty());
+}
+
+#[actix_rt::test]
+async fn function_source_cancel_on_drop() {
+    let pool = mock_pool("connection_string: $DATABASE_URL").await.unwrap();
+    let sql = indoc! {"
+        SELECT NULL::bytea FROM pg_sleep(30)
+        WHERE $1::integer IS NOT NULL AND $2::bigint IS NOT NULL AND $3::bigint IS NOT NULL
+    "};
+    let src = PgSource::new(
+        "cancel_test".to_string(),
+        PgSqlInfo::new(sql.to_string(), false, "cancel_test".to_string()),
+        tilejson! { tiles: vec![] },
+        pool.clone(),
+    );
+
+    // Simulate a client disconnect by dropping the request future while the query is running
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+    let res = tokio::time::timeout(Duration::from_millis(500), src.get_tile(xyz, None)).await;
+    assert!(res.is_err(), "The query should not have completed");
+
+    // The abandoned query must stop running on the server long before pg_sleep would have finished
+    let conn = pool.get().await.unwrap();
+    let start = Instant::now();
+    loop {
+        let running: i64 = conn
+            .query_one(
+                "SELECT count(*) FROM pg_stat_activity WHERE query LIKE '%pg_sleep(30)%' AND state = 'active' AND pid <> pg_backend_pid()",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        if running == 0 {
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "The query was not cancelled"
+        );
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -29,6 +29,7 @@ macro_rules! create_app {
                 ))
                 .app_data(actix_web::web::Data::new(::martin::NO_MAIN_CACHE))
                 .app_data(actix_web::web::Data::new(state.tiles))
+                .app_data(actix_web::web::Data::new(state.pg_pools))
                 .app_data(actix_web::web::Data::new(SrvConfig::default()))
                 .configure(::martin::srv::router),
         )
@@ -124,12 +125,32 @@ postgres:
       table_source_multiple_geom:
         content_type: application/x-protobuf
         description: public.table_source_multiple_geom.geom1
-      table_source_multiple_geom.1:
+      table_source_multiple_geom.geom2:
         content_type: application/x-protobuf
         description: public.table_source_multiple_geom.geom2
     "###);
 }
 
+#[actix_rt::test]
+async fn pg_get_status() {
+    let app = create_app! { "
+postgres:
+   connection_string: $DATABASE_URL
+"};
+
+    let req = test_get("/status");
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let body = read_body(response).await;
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let pools = body["postgres"].as_array().unwrap();
+    assert_eq!(pools.len(), 1);
+    assert!(pools[0]["id"].is_string());
+    for field in ["max_size", "size", "idle", "active", "waiting"] {
+        assert!(pools[0][field].is_u64(), "{field} is not a number: {body}");
+    }
+}
+
 #[actix_rt::test]
 async fn pg_get_table_source_ok() {
     let app = create_app! { "
@@ -1014,7 +1035,7 @@ tables:
     let mock = mock_sources(cfg.clone()).await;
 
     let src = table(&mock, "no_id");
-    assert_eq!(src.id_column, None);
+    assert_eq!(src.id_column, OptOneMany::NoVals);
     assert!(matches!(&src.properties, Some(v) if v.len() == 1));
     let tj = source(&mock, "no_id").get_tilejson();
     assert_yaml_snapshot!(tj, @r###"
@@ -1,9 +1,16 @@
 #![cfg(feature = "postgres")]
 
+use std::time::{Duration, Instant};
+
 use ctor::ctor;
+use deadpool_postgres::Object;
+use futures::future::try_join_all;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
-use martin::TileCoord;
+use martin::pg::{ClientMinMessages, PgError, PgPool, PgSource, PgSqlInfo};
+use martin::{Source, TileCoord};
+use martin_tile_utils::Format;
+use tilejson::tilejson;
 
 pub mod utils;
 pub use utils::*;
@@ -85,7 +92,7 @@ async fn table_source() {
     table_source_multiple_geom:
       content_type: application/x-protobuf
       description: public.table_source_multiple_geom.geom1
-    table_source_multiple_geom.1:
+    table_source_multiple_geom.geom2:
       content_type: application/x-protobuf
       description: public.table_source_multiple_geom.geom2
     "###);
@@ -170,10 +177,331 @@ async fn tables_multiple_geom_ok() {
     let source = table(&mock, "table_source_multiple_geom");
     assert_eq!(source.geometry_column, "geom1");
 
-    let source = table(&mock, "table_source_multiple_geom.1");
+    let source = table(&mock, "table_source_multiple_geom.geom2");
     assert_eq!(source.geometry_column, "geom2");
 }
 
+#[actix_rt::test]
+async fn tables_default_bounds() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        default_srid: 4326
+        auto_bounds: calc
+        default_bounds: [-10.0, -20.0, 30.0, 40.0]
+    "});
+    let mock = mock_sources(cfg).await;
+
+    // The table is empty, so its bounds cannot be computed
+    let tj = source(&mock, "points_empty").get_tilejson();
+    assert_eq!(tj.bounds, Some([-10.0, -20.0, 30.0, 40.0].into()));
+
+    // The computed bounds are not replaced
+    let tj = source(&mock, "points1").get_tilejson();
+    assert_ne!(tj.bounds, Some([-10.0, -20.0, 30.0, 40.0].into()));
+}
+
+#[actix_rt::test]
+async fn tables_extent() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        auto_publish:
+          tables:
+            extent: 8192
+    "});
+    let mock = mock_sources(cfg).await;
+
+    let src = source(&mock, "table_source");
+    assert_eq!(src.get_tilejson().other["extent"], 8192);
+    assert_eq!(src.get_catalog_entry().extent, Some(8192));
+}
+
+#[actix_rt::test]
+async fn tables_geometry_type_override() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        tables:
+          table_source:
+            schema: public
+            table: table_source
+            srid: 4326
+            geometry_column: geom
+            geometry_type: POINT
+            properties:
+              gid: int4
+          points1:
+            schema: public
+            table: points1
+            srid: 4326
+            geometry_column: geom
+            properties:
+              gid: int4
+    "});
+    let mock = mock_sources(cfg).await;
+
+    // The configured type wins over the generic GEOMETRY type of the column
+    assert_eq!(
+        table(&mock, "table_source").geometry_type.as_deref(),
+        Some("POINT")
+    );
+    let tj = source(&mock, "table_source").get_tilejson();
+    let layer = &tj.vector_layers.as_ref().unwrap()[0];
+    assert_eq!(layer.other["geometry_type"], "POINT");
+
+    // Without an override, the detected type is used
+    assert_eq!(
+        table(&mock, "points1").geometry_type.as_deref(),
+        Some("POINT")
+    );
+}
+
+#[actix_rt::test]
+async fn tables_geojson() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        tables:
+          table_source:
+            schema: public
+            table: table_source
+            srid: 4326
+            geometry_column: geom
+            geojson: true
+            id_column: gid
+          points1:
+            schema: public
+            table: points1
+            srid: 4326
+            geometry_column: geom
+    "});
+    let mock = mock_sources(cfg).await;
+    let src = source(&mock, "table_source");
+    assert_eq!(src.get_alt_formats(), vec![Format::Json]);
+
+    let tile = src
+        .get_alt_tile(TileCoord { z: 0, x: 0, y: 0 }, None, Format::Json)
+        .await
+        .unwrap();
+    let collection: serde_json::Value = serde_json::from_slice(&tile).unwrap();
+    assert_eq!(collection["type"], "FeatureCollection");
+    let features = collection["features"].as_array().unwrap();
+    assert!(!features.is_empty());
+    for feature in features {
+        assert_eq!(feature["type"], "Feature");
+        assert!(
+            feature["geometry"]["coordinates"].is_array()
+                || feature["geometry"]["geometries"].is_array()
+        );
+        assert!(feature["id"].is_i64());
+        assert!(feature["properties"].get("gid").is_none());
+    }
+
+    // A tile without any features is empty, like an empty MVT tile
+    let tile = src
+        .get_alt_tile(TileCoord { z: 10, x: 0, y: 0 }, None, Format::Json)
+        .await
+        .unwrap();
+    assert!(tile.is_empty());
+
+    // GeoJSON is only served if enabled
+    let src = source(&mock, "points1");
+    assert!(src.get_alt_formats().is_empty());
+    assert!(src
+        .get_alt_tile(TileCoord { z: 0, x: 0, y: 0 }, None, Format::Json)
+        .await
+        .is_err());
+}
+
+#[actix_rt::test]
+async fn pool_timeout() {
+    let pool = mock_pool(indoc! {"
+        connection_string: $DATABASE_URL
+        pool_size: 1
+        pool_timeout_ms: 100
+    "})
+    .await
+    .unwrap();
+
+    // Hold the only connection, so the next request has to wait for it
+    let _conn = pool.get().await.unwrap();
+    let start = Instant::now();
+    let err = pool.get().await.unwrap_err();
+    assert!(matches!(err, PgError::PoolTimeout(_, 100)), "{err}");
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[actix_rt::test]
+async fn pool_size_and_min_idle() {
+    let pool = mock_pool(indoc! {"
+        connection_string: $DATABASE_URL
+        pool_size: 3
+        min_idle: 2
+    "})
+    .await
+    .unwrap();
+    let status = pool.get_status();
+    assert_eq!(status.max_size, 3);
+    assert_eq!(status.size, 2);
+    assert_eq!(status.idle, 2);
+
+    // The idle connections that were closed are replaced
+    let conns = try_join_all([pool.get(), pool.get()]).await.unwrap();
+    conns.into_iter().for_each(|c| drop(Object::take(c)));
+    assert_eq!(pool.get_status().size, 0);
+    pool.replenish_idle().await.unwrap();
+    let status = pool.get_status();
+    assert_eq!(status.size, 2);
+    assert_eq!(status.idle, 2);
+
+    let err = mock_pool(indoc! {"
+        connection_string: $DATABASE_URL
+        pool_size: 1
+        min_idle: 2
+    "})
+    .await
+    .unwrap_err();
+    assert!(matches!(err, PgError::InvalidMinIdle(_, 2, 1)), "{err}");
+}
+
+#[actix_rt::test]
+async fn pool_max_lifetime() {
+    async fn backend_pid(pool: &PgPool) -> i32 {
+        let conn = pool.get().await.unwrap();
+        let row = conn.query_one("SELECT pg_backend_pid()", &[]).await;
+        row.unwrap().get(0)
+    }
+
+    let pool = mock_pool(indoc! {"
+        connection_string: $DATABASE_URL
+        pool_size: 1
+        max_lifetime_ms: 1000
+    "})
+    .await
+    .unwrap();
+
+    // A young connection is reused
+    let pid = backend_pid(&pool).await;
+    assert_eq!(backend_pid(&pool).await, pid);
+
+    // An expired connection is replaced with a new one
+    actix_rt::time::sleep(Duration::from_millis(1100)).await;
+    assert_ne!(backend_pid(&pool).await, pid);
+    assert_eq!(pool.get_status().size, 1);
+}
+
+#[actix_rt::test]
+async fn query_timeout() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        query_timeout_ms: 10000
+        tables:
+          tight:
+            schema: public
+            table: points3857
+            srid: 3857
+            geometry_column: geom
+            query_timeout_ms: 50
+          generous:
+            schema: public
+            table: points3857
+            srid: 3857
+            geometry_column: geom
+    "});
+    let mock = mock_sources(cfg).await;
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+
+    // Lock the table with another connection, so that the tile query waits for it
+    let pool = mock_pool("connection_string: $DATABASE_URL").await.unwrap();
+    let locker = pool.get().await.unwrap();
+    locker
+        .batch_execute("BEGIN; LOCK TABLE points3857 IN ACCESS EXCLUSIVE MODE;")
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    let err = source(&mock, "tight")
+        .get_tile(xyz, None)
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("tight") && err.contains("50ms"), "{err}");
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // Release the lock shortly, while the source with the connection timeout is waiting for it
+    actix_rt::spawn(async move {
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        locker.batch_execute("COMMIT").await.unwrap();
+    });
+    let tile = source(&mock, "generous").get_tile(xyz, None).await;
+    assert!(!tile.unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn client_min_messages() {
+    let pool = mock_pool(indoc! {"
+        connection_string: $DATABASE_URL
+        pool_size: 1
+    "})
+    .await
+    .unwrap();
+
+    // A source whose tile is the `client_min_messages` setting in effect while getting it
+    let level_source = |level: Option<ClientMinMessages>| {
+        let mut info = PgSqlInfo::new(
+            indoc! {"
+                SELECT convert_to(current_setting('client_min_messages'), 'UTF8')
+                WHERE $1::integer IS NOT NULL AND $2::bigint IS NOT NULL AND $3::bigint IS NOT NULL
+            "}
+            .to_string(),
+            false,
+            "client_min_messages".to_string(),
+        );
+        info.client_min_messages = level;
+        PgSource::new(
+            "level".to_string(),
+            info,
+            tilejson! { tiles: vec![] },
+            pool.clone(),
+        )
+    };
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+
+    let tile = level_source(Some(ClientMinMessages::Warning))
+        .get_tile(xyz, None)
+        .await
+        .unwrap();
+    assert_eq!(tile, b"warning");
+
+    // The setting does not leak to the other queries that reuse the same connection
+    let tile = level_source(None).get_tile(xyz, None).await.unwrap();
+    assert_eq!(tile, b"notice");
+}
+
+#[actix_rt::test]
+async fn tables_fail_on_missing_index() {
+    let mut cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        fail_on_missing_index: true
+        auto_publish:
+          tables:
+            from_schemas: public
+          functions: false
+    "});
+    let err = cfg.resolve().await.err().unwrap().to_string();
+    assert!(err.contains("public.table_source.geom"), "{err}");
+    assert!(!err.contains("public.points1.geom"), "{err}");
+
+    // Tables with spatial indices are published as usual
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        fail_on_missing_index: true
+        auto_publish:
+          tables:
+            from_schemas: MixedCase
+          functions: false
+    "});
+    let sources = mock_sources(cfg).await.0;
+    assert!(sources.tiles.get_source("MixPoints").is_ok());
+}
+
 #[actix_rt::test]
 async fn table_source_schemas() {
     let cfg = mock_pgcfg(indoc! {"
@@ -20,6 +20,17 @@ pub fn mock_pgcfg(yaml: &str) -> Config {
     ", yaml.replace('\n', "\n  ")})
 }
 
+/// Connection pool of the single Postgres config in the given yaml, without resolving its sources
+#[cfg(feature = "postgres")]
+#[allow(dead_code)]
+pub async fn mock_pool(yaml: &str) -> martin::pg::PgResult<martin::pg::PgPool> {
+    let cfg = mock_pgcfg(yaml);
+    let martin::OptOneMany::One(pg_cfg) = &cfg.postgres else {
+        panic!("Expected a single Postgres config");
+    };
+    martin::pg::PgPool::new(pg_cfg).await
+}
+
 #[allow(dead_code)]
 pub async fn mock_sources(mut config: Config) -> MockSource {
     let res = config.resolve().await;
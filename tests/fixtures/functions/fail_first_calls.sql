@@ -0,0 +1,16 @@
+-- This function is not a tile source, and is only used by the tests of the retries of failed tile queries.
+-- It fails with a serialization failure (SQLSTATE 40001) until it has been called more than `failures` times
+-- since the `fail_first_calls_seq` sequence was reset.
+DROP FUNCTION IF EXISTS public.fail_first_calls;
+DROP SEQUENCE IF EXISTS public.fail_first_calls_seq;
+
+CREATE SEQUENCE public.fail_first_calls_seq;
+
+CREATE OR REPLACE FUNCTION public.fail_first_calls(failures integer) RETURNS bytea AS $$
+BEGIN
+    IF nextval('public.fail_first_calls_seq') <= failures THEN
+        RAISE EXCEPTION 'simulated serialization failure' USING ERRCODE = 'serialization_failure';
+    END IF;
+    RETURN null;
+END
+$$ LANGUAGE plpgsql VOLATILE STRICT;